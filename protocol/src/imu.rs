@@ -0,0 +1,106 @@
+//! IMU state and events
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Orientation estimate
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Orientation {
+    /// Rotation from the sensor frame into the earth frame, as i, j, k and w
+    pub quaternion: [f32; 4],
+
+    /// Rotation about the x axis, in degrees
+    pub roll: f32,
+
+    /// Rotation about the y axis, in degrees
+    pub pitch: f32,
+
+    /// Rotation about the z axis, in degrees counterclockwise from magnetic north
+    pub yaw: f32,
+}
+
+/// Current leveling state
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Level {
+    /// Nose-up pitch relative to the level reference, in degrees
+    pub pitch: f32,
+
+    /// Left-side-up roll relative to the level reference, in degrees
+    pub roll: f32,
+
+    /// Both pitch and roll are within tolerance
+    pub level: bool,
+
+    /// Raise needed at each wheel and jack to level
+    pub corrections: Vec<Correction>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Correction {
+    pub name: String,
+
+    /// Height to raise, in inches
+    pub raise: f32,
+
+    /// Number of leveling blocks, rounded to the nearest whole block
+    pub blocks: u32,
+}
+
+/// Summary of a trip, complete or in progress
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Trip {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+
+    /// Time from start to end, in seconds
+    pub duration: f32,
+
+    /// Largest lateral acceleration, in g
+    pub max_lateral: f32,
+
+    /// Largest vibration over one second, in g
+    pub max_vibration: f32,
+
+    /// Time spent with vibration above the rough road threshold, in seconds
+    pub rough_seconds: f32,
+}
+
+/// Summary of an impact
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Impact {
+    /// Time of the trigger
+    pub timestamp: DateTime<Utc>,
+
+    /// Largest dynamic acceleration in the recording, in g
+    pub peak: f32,
+
+    /// File name of the recording
+    pub recording: String,
+}
+
+/// Notable occurrence detected by an IMU
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum ImuEvent {
+    /// Acceleration beyond the alarm threshold while armed, in g
+    Motion {
+        timestamp: DateTime<Utc>,
+        magnitude: f32,
+    },
+
+    /// Change in attitude beyond the alarm threshold while armed, in degrees
+    Tilt {
+        timestamp: DateTime<Utc>,
+        degrees: f32,
+    },
+
+    /// Driving began
+    TripStart { timestamp: DateTime<Utc> },
+
+    /// Driving ended, with a summary of the trip
+    TripEnd { trip: Trip },
+
+    /// Shock above the impact threshold, once its recording is complete
+    Impact { impact: Impact },
+}
@@ -0,0 +1,27 @@
+//! Messages shared by habctl and the habux client
+//!
+//! The websocket carries bincode-encoded `socket::Data` from habctl and
+//! `socket::Request` from the client.  Both ends build against this crate, so
+//! they agree on every message, and it is `no_std` so that it also builds for
+//! the client's wasm target.
+//!
+//! Bincode is not self-describing, so a change to any message breaks older
+//! peers.  Each end opens with a `Hello` carrying `VERSION`, which is always
+//! the first variant so that it decodes in any version, and closes the socket
+//! if the other end is incompatible.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod imu;
+pub mod socket;
+pub mod telemetry;
+
+/// Version of the protocol, incremented on every incompatible change
+pub const VERSION: u32 = 4;
+
+/// Whether a peer speaking `version` can be understood
+pub fn compatible(version: u32) -> bool {
+    version == VERSION
+}
@@ -0,0 +1,153 @@
+//! Websocket messages
+//!
+//! habctl opens with a bare `Data::Hello`, and the client answers with a
+//! `Request::Hello`, naming the session to resume if it is reconnecting.
+//! habctl then sends `Data::Session`, and every later message is `Sequenced`,
+//! numbered from 1 within the session.  A new session starts with `Signals`
+//! and `Snapshot`; a resumed one continues after the last message the client
+//! received, including any sent while it was disconnected.
+//!
+//! Requests other than `Hello` carry an ID chosen by the client, which is
+//! returned with the acknowledgement, reply or error that answers them.
+//! Command arguments and results are JSON text, as bincode can't carry values
+//! of no fixed type.
+
+use crate::imu::{ImuEvent, Level, Orientation};
+use crate::telemetry::{Descriptor, Update};
+use alloc::string::String;
+use alloc::vec::Vec;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Message from habctl to the client
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Data {
+    /// First message on connecting, with the protocol version of habctl
+    Hello {
+        version: u32,
+    },
+
+    Empty,
+    SystemTime(DateTime<Utc>),
+    Orientation(String, Orientation),
+    Level(String, Level),
+    ImuEvent(String, ImuEvent),
+
+    /// Descriptions of every signal, on connecting
+    Signals(Vec<Descriptor>),
+
+    /// Latest value of every signal, replacing any previously received
+    Snapshot(Vec<Update>),
+
+    /// Latest values of the signals that changed since the last updates
+    Updates(Vec<Update>),
+
+    /// A subscription request succeeded
+    Ack {
+        id: u32,
+    },
+
+    /// A command succeeded, with its result as JSON
+    Reply {
+        id: u32,
+        result: String,
+    },
+
+    /// A request failed
+    Error {
+        id: u32,
+        error: String,
+    },
+
+    /// Answer to the client's `Hello`, with whether its session was resumed
+    ///
+    /// A session that can't be resumed, because it expired or because
+    /// messages since the client's sequence were discarded, is replaced by a
+    /// new one.
+    Session {
+        id: u64,
+        resumed: bool,
+    },
+}
+
+/// Message from habctl after `Data::Session`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Sequenced {
+    pub sequence: u64,
+    pub data: Data,
+}
+
+/// Message from the client to habctl
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    /// First message on connecting, with the protocol version of the client
+    Hello {
+        version: u32,
+        resume: Option<Resume>,
+    },
+
+    /// Receive updates of the signals of a device, or of one of its signals,
+    /// at most once per `interval` milliseconds
+    ///
+    /// Until its first subscription, a client receives updates of every
+    /// signal at the server's update interval.  Subscribing again with the
+    /// same ID replaces the subscription.
+    Subscribe {
+        id: u32,
+        device: String,
+        signal: Option<String>,
+        interval: u32,
+    },
+
+    Unsubscribe {
+        id: u32,
+    },
+
+    /// Perform a command named in the device's metadata, with JSON arguments
+    Command {
+        id: u32,
+        device: String,
+        command: String,
+        arguments: String,
+    },
+}
+
+/// Session to resume, and the sequence of the last message received in it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Resume {
+    pub session: u64,
+    pub sequence: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Data, Request, Resume};
+    use crate::VERSION;
+
+    #[test]
+    fn hello_is_stable() {
+        // the variant index then the version, so any version can read it
+        let mut hello = [0u8; 8];
+        hello[4..].copy_from_slice(&VERSION.to_le_bytes());
+
+        let data = bincode::serialize(&Data::Hello { version: VERSION }).unwrap();
+        assert_eq!(&hello[..], &data[..]);
+
+        let resume = Some(Resume {
+            session: 1,
+            sequence: 2,
+        });
+        let request = bincode::serialize(&Request::Hello {
+            version: VERSION,
+            resume,
+        })
+        .unwrap();
+        assert_eq!(&hello[..], &request[..8]);
+
+        // versions that only read the version ignore the rest
+        match bincode::deserialize(&request).unwrap() {
+            Data::Hello { version } => assert!(crate::compatible(version)),
+            data => panic!("decoded {:?}", data),
+        }
+    }
+}
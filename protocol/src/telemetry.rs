@@ -0,0 +1,184 @@
+//! Telemetry signals
+//!
+//! Each value published by a device is a signal, a scalar value under a topic
+//! of the device and signal name, and is described by its unit and valid range.
+
+pub mod unit;
+
+use alloc::string::String;
+use chrono::{DateTime, Utc};
+use core::fmt;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use unit::Unit;
+
+/// Name of a signal of a device
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Topic {
+    pub device: String,
+    pub signal: String,
+}
+
+impl Topic {
+    pub fn new(device: &str, signal: &str) -> Self {
+        Self {
+            device: device.into(),
+            signal: signal.into(),
+        }
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.device, self.signal)
+    }
+}
+
+/// Value of a signal
+///
+/// A bare value in JSON, but tagged with its variant in binary formats, which
+/// can't tell the variants apart from the value alone.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Number(f32),
+    Text(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Value")]
+enum Tagged {
+    Bool(bool),
+    Number(f32),
+    Text(String),
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "Value", untagged)]
+enum Untagged {
+    Bool(bool),
+    Number(f32),
+    Text(String),
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            Untagged::serialize(self, serializer)
+        } else {
+            Tagged::serialize(self, serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            Untagged::deserialize(deserializer)
+        } else {
+            Tagged::deserialize(deserializer)
+        }
+    }
+}
+
+impl Value {
+    pub fn as_number(&self) -> Option<f32> {
+        match self {
+            Value::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Bool(value)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(value: f32) -> Self {
+        Value::Number(value)
+    }
+}
+
+impl From<u16> for Value {
+    fn from(value: u16) -> Self {
+        Value::Number(value.into())
+    }
+}
+
+impl From<u32> for Value {
+    fn from(value: u32) -> Self {
+        Value::Number(value as f32)
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+/// Published value of a topic
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Update {
+    pub topic: Topic,
+    pub timestamp: DateTime<Utc>,
+    pub value: Value,
+
+    /// Older than the stale age when read back from the bus
+    #[serde(default)]
+    pub stale: bool,
+}
+
+/// Description of a signal of a device
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Descriptor {
+    /// Topic as `device/signal`
+    pub id: String,
+
+    pub device: String,
+    pub signal: String,
+
+    pub unit: Unit,
+
+    /// Factor from the raw value reported by the device to the unit
+    pub scale: f32,
+
+    /// Range of valid values, in the unit
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+
+    pub description: String,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Topic, Update, Value};
+    use alloc::string::ToString;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn value_encoding() {
+        let update = Update {
+            topic: Topic::new("big", "state"),
+            timestamp: Utc.ymd(2021, 6, 1).and_hms(12, 0, 0),
+            value: Value::Text("Bulk".to_string()),
+            stale: false,
+        };
+
+        // binary formats need the variant to decode the value
+        let encoded = bincode::serialize(&update).unwrap();
+        let decoded: Update = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(update.value, decoded.value);
+        assert_eq!(update.topic, decoded.topic);
+
+        let encoded = bincode::serialize(&Value::Number(13.2)).unwrap();
+        assert_eq!(Value::Number(13.2), bincode::deserialize(&encoded).unwrap());
+
+        // but in JSON the value is bare
+        let json = serde_json::to_string(&Value::Number(13.2)).unwrap();
+        assert_eq!("13.2", json);
+        assert_eq!(Value::Bool(true), serde_json::from_str("true").unwrap());
+    }
+}
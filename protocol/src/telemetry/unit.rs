@@ -0,0 +1,118 @@
+//! Units of signals, and quantities typed by their unit
+//!
+//! Frames hold quantities rather than bare numbers wherever a value has a
+//! unit, so that a value can't be stored in the wrong unit without a type
+//! error.  Quantities serialize as their bare number, so frames keep their
+//! shape on the wire, and the unit is described by the signal registry.
+
+use super::Value;
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+/// Unit of a signal, serialized as its symbol
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Unit {
+    /// Unitless, a count or a state
+    #[serde(rename = "")]
+    None,
+
+    #[serde(rename = "V")]
+    Volt,
+
+    #[serde(rename = "A")]
+    Ampere,
+
+    #[serde(rename = "W")]
+    Watt,
+
+    #[serde(rename = "Wh")]
+    WattHour,
+
+    #[serde(rename = "°C")]
+    Celsius,
+
+    #[serde(rename = "°")]
+    Degree,
+
+    #[serde(rename = "°/s")]
+    DegreePerSecond,
+
+    /// Acceleration in standard gravities
+    #[serde(rename = "g")]
+    Gravity,
+
+    #[serde(rename = "µT")]
+    Microtesla,
+}
+
+impl Unit {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Unit::None => "",
+            Unit::Volt => "V",
+            Unit::Ampere => "A",
+            Unit::Watt => "W",
+            Unit::WattHour => "Wh",
+            Unit::Celsius => "°C",
+            Unit::Degree => "°",
+            Unit::DegreePerSecond => "°/s",
+            Unit::Gravity => "g",
+            Unit::Microtesla => "µT",
+        }
+    }
+}
+
+macro_rules! quantity {
+    ($(#[$doc:meta])* $name:ident, $unit:ident) => {
+        $(#[$doc])*
+        #[derive(Copy, Clone, Debug, Default, PartialEq, PartialOrd, Serialize, Deserialize)]
+        #[serde(transparent)]
+        pub struct $name(pub f32);
+
+        impl $name {
+            pub const UNIT: Unit = Unit::$unit;
+        }
+
+        impl From<$name> for Value {
+            fn from(quantity: $name) -> Self {
+                Value::Number(quantity.0)
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "{} {}", self.0, Self::UNIT.symbol())
+            }
+        }
+    };
+}
+
+quantity!(
+    /// Electric potential, in volts
+    Volts,
+    Volt
+);
+
+quantity!(
+    /// Electric current, in amperes
+    Amperes,
+    Ampere
+);
+
+quantity!(
+    /// Power, in watts
+    Watts,
+    Watt
+);
+
+quantity!(
+    /// Energy, in watt hours
+    WattHours,
+    WattHour
+);
+
+quantity!(
+    /// Temperature, in degrees Celsius
+    Celsius,
+    Celsius
+);
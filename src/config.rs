@@ -6,12 +6,27 @@ use serde::Deserialize;
 
 static INSTANCE: OnceCell<Config> = OnceCell::new();
 
+/// Default directory for persistent state
+const DEFAULT_STATE_PATH: &str = "/var/lib/habctl";
+
 /// Global configuration
 #[derive(Deserialize, Debug)]
 pub struct Config {
     #[serde(skip, default)]
     pub build: Build,
+
+    /// Directory for persistent state such as calibrations (default /var/lib/habctl)
+    #[serde(default = "default_state_path")]
+    pub state_path: String,
+
     pub hardware: crate::hardware::config::Hardware,
+
+    #[serde(default)]
+    pub history: crate::history::config::History,
+
+    #[serde(default)]
+    pub telemetry: crate::telemetry::config::Telemetry,
+
     pub web: crate::web::config::Web,
 }
 
@@ -32,6 +47,34 @@ impl Config {
     pub fn get() -> &'static Config {
         INSTANCE.get().expect("config not loaded")
     }
+
+    /// Loads a config for tests, unless one is already loaded, with loopback
+    /// devices and state in a temporary directory
+    #[cfg(test)]
+    pub fn load_test() {
+        let state_path = std::env::temp_dir().join(format!("habctl-test-{}", std::process::id()));
+        let config = format!(
+            r#"
+            state_path = "{}"
+
+            [web]
+            static_path = "static"
+            listen_addr = "127.0.0.1:8081"
+            update_interval = 100
+
+            [hardware.mppt.big]
+
+            [hardware.imu.hab]
+            "#,
+            state_path.display()
+        );
+
+        let _ = INSTANCE.set(toml::from_str(&config).unwrap());
+    }
+}
+
+fn default_state_path() -> String {
+    DEFAULT_STATE_PATH.into()
 }
 
 /// Configuration captured at build time
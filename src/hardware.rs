@@ -2,78 +2,139 @@
 
 pub mod config;
 pub mod device;
+pub mod gpio;
+pub mod i2c;
 pub mod imu;
+pub mod solar;
+pub mod timestamp;
 pub mod victron;
 
-use anyhow::Result;
-use device::Device;
+use crate::telemetry::{self, Topic};
+use anyhow::{Error, Result};
+use chrono::Utc;
+use device::{Device, Registry};
 use futures::future::try_join_all;
 use imu::Icm20948;
-use serde::Serialize;
-use std::future::Future;
-use std::pin::Pin;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use solar::Aiming;
+use std::collections::BTreeMap;
 use std::sync::Arc;
-use std::time::SystemTime;
 use victron::ve_direct::VeDirectMppt;
 
-#[derive(Serialize)]
 pub struct Hardware {
-    imu: Vec<Arc<Icm20948>>,
-    mppt: Vec<Arc<VeDirectMppt>>,
+    /// Devices ordered by kind, then name
+    devices: Vec<Arc<dyn Device>>,
+
+    solar: Option<config::Solar>,
 }
 
 impl Hardware {
-    pub async fn run(&self) -> Result<Vec<Vec<()>>> {
-        let mut imu_runners = Vec::new();
-        for i in 0..self.imu.len() {
-            imu_runners.push(self.imu[i].run())
+    /// Build the configured devices
+    pub fn new() -> Result<Self> {
+        let config = &crate::config::Config::get().hardware;
+        let registry = registry();
+
+        let mut devices = Vec::new();
+        for (kind, configs) in &config.devices {
+            for (name, config) in configs {
+                devices.push(registry.build(kind, name, config.clone())?);
+            }
         }
 
-        let mut mppt_runners = Vec::new();
-        for i in 0..self.mppt.len() {
-            mppt_runners.push(self.mppt[i].run())
+        let mut hardware = Self {
+            devices: Vec::new(),
+            solar: config.solar.clone(),
+        };
+
+        for device in devices {
+            let name = device.metadata().name;
+            if hardware.device(&name).is_some() {
+                return Err(Error::msg(format!("more than one device named {}", name)));
+            }
+
+            hardware.devices.push(device);
         }
 
-        try_join_all(vec![
-            Box::pin(try_join_all(imu_runners)) as Pin<Box<dyn Future<Output = Result<Vec<()>>>>>,
-            Box::pin(try_join_all(mppt_runners)),
-        ])
-        .await
+        Ok(hardware)
+    }
+
+    pub fn devices(&self) -> &[Arc<dyn Device>] {
+        &self.devices
+    }
+
+    pub fn device(&self, name: &str) -> Option<&Arc<dyn Device>> {
+        self.devices.iter().find(|d| d.metadata().name == name)
+    }
+
+    /// Devices of one kind
+    pub fn all<D: Device>(&self) -> impl Iterator<Item = &D> {
+        self.devices
+            .iter()
+            .filter_map(|d| d.as_any().downcast_ref())
     }
-}
 
-impl Default for Hardware {
-    fn default() -> Self {
-        let config = crate::Config::get();
-
-        let hardware = Self {
-            imu: config
-                .hardware
-                .imu
-                .iter()
-                .map(|(name, config)| match &config.port {
-                    Some(port) => Icm20948::device(name, &port),
-                    None => Icm20948::loopback(name),
-                })
-                .collect(),
-            mppt: config
-                .hardware
-                .mppt
-                .iter()
-                .map(|(name, config)| match &config.port {
-                    Some(port) => VeDirectMppt::device(name, &port),
-                    None => VeDirectMppt::loopback(name),
-                })
-                .collect(),
+    /// Device of one kind by name
+    pub fn find<D: Device>(&self, name: &str) -> Option<&D> {
+        self.device(name)?.as_any().downcast_ref()
+    }
+
+    /// Recommendation for aiming the solar panels, from the current heading and panel power
+    pub fn aiming(&self) -> Result<Aiming> {
+        let config = self
+            .solar
+            .as_ref()
+            .ok_or_else(|| Error::msg("solar panel aiming is not configured"))?;
+
+        let heading = self
+            .find::<Icm20948>(&config.imu)
+            .ok_or_else(|| Error::msg(format!("no IMU named {}", config.imu)))?
+            .heading()
+            .ok_or_else(|| Error::msg(format!("no heading from {} yet", config.imu)))?;
+
+        let bus = telemetry::bus();
+        let power: Vec<f32> = self
+            .devices
+            .iter()
+            .filter_map(|device| bus.latest(&Topic::new(&device.metadata().name, "panel_power")))
+            .filter_map(|update| update.value.as_number())
+            .collect();
+        let panel_power = if power.is_empty() {
+            None
+        } else {
+            Some(power.iter().sum())
         };
 
-        hardware
+        Ok(Aiming::new(config, Utc::now(), &heading, panel_power))
+    }
+
+    pub async fn run(&self) -> Result<Vec<()>> {
+        try_join_all(self.devices.iter().map(|device| device.run())).await
+    }
+}
+
+/// Snapshots of each device, grouped by kind
+impl Serialize for Hardware {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut kinds = BTreeMap::<String, Vec<_>>::new();
+        for device in &self.devices {
+            kinds
+                .entry(device.metadata().kind)
+                .or_default()
+                .push(device.snapshot());
+        }
+
+        let mut map = serializer.serialize_map(Some(kinds.len()))?;
+        for (kind, snapshots) in &kinds {
+            map.serialize_entry(kind, snapshots)?;
+        }
+        map.end()
     }
 }
 
-pub fn timestamp() -> f32 {
-    SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap()
-        .as_secs_f32()
+/// Kinds of device that can be configured
+fn registry() -> Registry {
+    let mut registry = Registry::default();
+    registry.register::<Icm20948>();
+    registry.register::<VeDirectMppt>();
+    registry
 }
@@ -1,10 +1,32 @@
 use serde::Deserialize;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 #[derive(Deserialize, Debug)]
 pub struct Hardware {
-    pub imu: HashMap<String, Imu>,
-    pub mppt: HashMap<String, Mppt>,
+    /// Solar panel aiming, if the site and panels are described
+    pub solar: Option<Solar>,
+
+    /// Device configurations by kind, such as `imu`, then by name
+    #[serde(flatten)]
+    pub devices: BTreeMap<String, BTreeMap<String, toml::Value>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Solar {
+    /// Name of the IMU measuring the vehicle attitude
+    pub imu: String,
+
+    /// Site latitude, in degrees north
+    pub latitude: f32,
+
+    /// Site longitude, in degrees east
+    pub longitude: f32,
+
+    /// Tilt of the panels from the roof, in degrees (default 0)
+    pub tilt: Option<f32>,
+
+    /// Direction the panels tilt towards, in degrees clockwise from vehicle forward (default 0)
+    pub azimuth: Option<f32>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -13,8 +35,228 @@ pub struct Mppt {
     pub loopback: Option<bool>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct Imu {
     pub port: Option<String>,
     pub loopback: Option<bool>,
+
+    /// FIFO output data rate in Hz (4.4 to 1125, default 200)
+    pub sample_rate: Option<f32>,
+
+    /// Accelerometer full scale in g (2, 4, 8 or 16, default 2)
+    pub accelerometer_range: Option<u8>,
+
+    /// Interval between published telemetry frames, in milliseconds (default 1000)
+    pub frame_interval: Option<u64>,
+
+    /// Rotation of the IMU relative to the vehicle (x forward, y left, z up)
+    pub mounting: Option<Mounting>,
+
+    /// GPIO line wired to the INT pin, polled at the frame interval if absent
+    pub interrupt: Option<Interrupt>,
+
+    /// Orientation filter
+    #[serde(default)]
+    pub ahrs: Ahrs,
+
+    /// Compass heading
+    #[serde(default)]
+    pub compass: Compass,
+
+    /// Leveling assistant, if the IMU is mounted in the camper
+    pub leveling: Option<Leveling>,
+
+    /// Calibration routines
+    #[serde(default)]
+    pub calibration: Calibration,
+
+    /// Motion and tamper alarm thresholds
+    #[serde(default)]
+    pub alarm: Alarm,
+
+    /// Driving detection thresholds
+    #[serde(default)]
+    pub trip: Trip,
+
+    /// Impact detection and recording
+    #[serde(default)]
+    pub impact: Impact,
+
+    /// Vibration spectrum analysis
+    #[serde(default)]
+    pub vibration: Vibration,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Impact {
+    /// Dynamic acceleration that triggers a recording, in g, which must be
+    /// within the accelerometer range (default 1.5)
+    pub threshold: Option<f32>,
+
+    /// Time recorded before the trigger, in seconds (default 2)
+    pub pre_trigger: Option<f32>,
+
+    /// Time recorded after the trigger, in seconds (default 5)
+    pub post_trigger: Option<f32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Vibration {
+    /// Samples per transform, a power of two setting the frequency resolution
+    /// (default 512)
+    pub fft_size: Option<usize>,
+
+    /// Frequency bands summarized each minute (default body 1-5Hz, road
+    /// 10-30Hz and structure 30-90Hz)
+    pub bands: Option<Vec<Band>>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Band {
+    pub name: String,
+
+    /// Lower edge, in Hz
+    pub low: f32,
+
+    /// Upper edge, in Hz, which must be below half the sample rate
+    pub high: f32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Interrupt {
+    /// GPIO character device, such as /dev/gpiochip0
+    pub chip: String,
+
+    /// Line offset on the chip
+    pub line: u32,
+
+    /// Condition that raises the interrupt (default data_ready)
+    #[serde(default)]
+    pub source: InterruptSource,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InterruptSource {
+    /// Every new sample
+    #[default]
+    DataReady,
+
+    /// FIFO filled to its watermark
+    Watermark,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Alarm {
+    /// Deviation of any sample from the armed attitude, in g (default 0.05)
+    pub acceleration: Option<f32>,
+
+    /// Change in attitude, in degrees (default 1.0)
+    pub tilt: Option<f32>,
+
+    /// Minimum time between motion events, in seconds (default 10)
+    pub holdoff: Option<i64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Calibration {
+    /// Time to hold still for gyrometer and accelerometer captures, in seconds (default 5)
+    pub duration: Option<f32>,
+
+    /// Time to rotate through every orientation for magnetometer calibration,
+    /// in seconds (default 60)
+    pub sweep_duration: Option<f32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Ahrs {
+    /// Filter algorithm (default madgwick)
+    #[serde(default)]
+    pub filter: AhrsFilter,
+
+    /// Madgwick gain (default 0.1)
+    pub beta: Option<f32>,
+
+    /// Mahony proportional gain (default 1.0)
+    pub kp: Option<f32>,
+
+    /// Mahony integral gain (default 0.0)
+    pub ki: Option<f32>,
+
+    /// Fuse the magnetometer for heading (default true)
+    pub magnetometer: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AhrsFilter {
+    #[default]
+    Madgwick,
+    Mahony,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Compass {
+    /// Magnetic declination, in degrees east of true north (default 0)
+    pub declination: Option<f32>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Leveling {
+    /// Distance between front and rear axles, in inches
+    pub wheelbase: f32,
+
+    /// Distance between left and right wheels, in inches
+    pub track: f32,
+
+    /// Pitch and roll within which the camper is level, in degrees (default 0.5)
+    pub tolerance: Option<f32>,
+
+    /// Height of a leveling block, in inches (default 1.0)
+    pub block_height: Option<f32>,
+
+    /// Jacks or other supports in addition to the wheels
+    #[serde(default)]
+    pub jacks: Vec<Jack>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default)]
+pub struct Mounting {
+    /// Degrees about the vehicle x axis
+    pub roll: f32,
+
+    /// Degrees about the vehicle y axis
+    pub pitch: f32,
+
+    /// Degrees about the vehicle z axis
+    pub yaw: f32,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Jack {
+    pub name: String,
+
+    /// Inches forward of the center of the wheels
+    pub x: f32,
+
+    /// Inches left of the center of the wheels
+    pub y: f32,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Trip {
+    /// Vibration above which the vehicle is driving, in g (default 0.02)
+    pub driving: Option<f32>,
+
+    /// Vibration above which the road is rough, in g (default 0.15)
+    pub rough: Option<f32>,
+
+    /// Ground speed above which the vehicle is driving, in m/s (default 2.0)
+    pub speed: Option<f32>,
+
+    /// Time driving before a trip starts, in seconds (default 30)
+    pub start_delay: Option<i64>,
+
+    /// Time parked before a trip ends, in seconds (default 180)
+    pub stop_delay: Option<i64>,
 }
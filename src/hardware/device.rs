@@ -1,6 +1,397 @@
+//! Devices and the registry of device kinds
+//!
+//! Each kind of device is registered under the name of its table in the
+//! hardware configuration, so `[hardware.imu.hab]` builds an IMU named `hab`.
+//! Once built, devices are run and inspected through the object-safe `Device`
+//! trait, with typed access to a particular kind by downcasting.
+
+use anyhow::{Error, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Identity of a device
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Metadata {
+    pub name: String,
+
+    /// Registered kind, which is also its configuration table
+    pub kind: String,
+
+    /// Device path, absent in loopback mode
+    pub port: Option<String>,
+
+    /// Commands accepted by `Device::command`
+    pub commands: Vec<String>,
+}
+
+/// Whether a device is working
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// Not yet producing frames
+    #[default]
+    Starting,
+
+    /// Producing frames without errors
+    Connected,
+
+    /// Producing frames, but with recent errors or late
+    Degraded,
+
+    /// Lost the device, and retrying if the device supports it
+    Disconnected,
+
+    /// Simulated or idle in loopback mode
+    Loopback,
+}
+
+/// Health of a device
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Health {
+    pub state: State,
+
+    /// Time of the latest frame
+    pub last_frame: Option<DateTime<Utc>>,
+
+    /// Recent frames per second
+    pub frame_rate: f32,
+
+    /// Errors since the device started
+    pub errors: u32,
+
+    pub last_error: Option<String>,
+}
+
+/// Tracks the health of a device from its frames and errors
+#[derive(Default)]
+pub struct Monitor {
+    loopback: bool,
+    disconnected: bool,
+
+    /// Times of the frames within the rate window
+    frames: VecDeque<DateTime<Utc>>,
+    last_frame: Option<DateTime<Utc>>,
+
+    errors: u32,
+    last_error: Option<(DateTime<Utc>, String)>,
+}
+
+impl Monitor {
+    pub fn new(loopback: bool) -> Self {
+        Self {
+            loopback,
+            ..Self::default()
+        }
+    }
+
+    /// A frame was received, which also means the device is connected
+    pub fn frame(&mut self, timestamp: DateTime<Utc>) {
+        self.disconnected = false;
+        self.last_frame = Some(timestamp);
+
+        self.frames.push_back(timestamp);
+        while matches!(self.frames.front(), Some(t) if timestamp - *t > rate_window()) {
+            self.frames.pop_front();
+        }
+    }
+
+    /// An error that the device recovers from by itself
+    pub fn error(&mut self, timestamp: DateTime<Utc>, error: &dyn fmt::Display) {
+        self.errors += 1;
+        self.last_error = Some((timestamp, error.to_string()));
+    }
+
+    /// The device was lost
+    pub fn disconnected(&mut self, timestamp: DateTime<Utc>, error: &dyn fmt::Display) {
+        self.error(timestamp, error);
+        self.disconnected = true;
+        self.frames.clear();
+    }
+
+    /// Health at `now`, late if no frame has been received within `stale_after`
+    pub fn health(&self, now: DateTime<Utc>, stale_after: Duration) -> Health {
+        let late = matches!(self.last_frame, Some(t) if now - t > stale_after);
+        let erring = matches!(&self.last_error, Some((t, _)) if now - *t < recent_error());
+
+        let state = if self.disconnected {
+            State::Disconnected
+        } else if self.loopback {
+            State::Loopback
+        } else if self.last_frame.is_none() {
+            State::Starting
+        } else if late || erring {
+            State::Degraded
+        } else {
+            State::Connected
+        };
+
+        // frames in the window, or none if the device has gone quiet
+        let frame_rate = match (self.frames.front(), self.frames.back()) {
+            (Some(first), Some(last)) if !late && last > first => {
+                (self.frames.len() - 1) as f32 / (*last - *first).num_milliseconds() as f32 * 1000.0
+            }
+            _ => 0.0,
+        };
+
+        Health {
+            state,
+            last_frame: self.last_frame,
+            frame_rate,
+            errors: self.errors,
+            last_error: self.last_error.as_ref().map(|(_, e)| e.clone()),
+        }
+    }
+}
+
+/// Window over which the frame rate is measured
+fn rate_window() -> Duration {
+    Duration::seconds(10)
+}
+
+/// Time after an error during which the device is degraded
+fn recent_error() -> Duration {
+    Duration::seconds(60)
+}
+
+/// A device, driven by its own task
+pub trait Device: Send + Sync + 'static {
+    fn metadata(&self) -> Metadata;
+
+    fn health(&self) -> Health;
+
+    /// Current state and telemetry
+    fn snapshot(&self) -> &dyn erased_serde::Serialize;
+
+    /// Perform a command named in the metadata, returning its result
+    fn command(&self, command: &str, _arguments: Value) -> Result<Value> {
+        Err(Error::msg(format!(
+            "{} does not support {}",
+            self.metadata().name,
+            command
+        )))
+    }
+
+    /// Operate the device until it fails permanently
+    fn run(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+
+    /// The concrete device, for typed access
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// Kind of device that can be built from its configuration
+pub trait Driver: Device + Sized {
+    /// Name of the kind and its configuration table
+    const KIND: &'static str;
+
+    type Config: DeserializeOwned;
+
+    /// Device path, or none for loopback mode
+    fn port(config: &Self::Config) -> Option<&str>;
+
+    fn device(name: &str, path: &str, config: &Self::Config) -> Arc<Self>;
+    fn loopback(name: &str, config: &Self::Config) -> Arc<Self>;
+}
+
+type Constructor = Box<dyn Fn(&str, toml::Value) -> Result<Arc<dyn Device>> + Send + Sync>;
+
+/// Device kinds by name
+#[derive(Default)]
+pub struct Registry {
+    kinds: HashMap<&'static str, Constructor>,
+}
+
+impl Registry {
+    pub fn register<D: Driver>(&mut self) {
+        self.kinds.insert(
+            D::KIND,
+            Box::new(|name, config| {
+                let config: D::Config = config.try_into()?;
+
+                Ok(match D::port(&config) {
+                    Some(port) => D::device(name, port, &config) as Arc<dyn Device>,
+                    None => D::loopback(name, &config),
+                })
+            }),
+        );
+    }
+
+    /// Build a device of a registered kind from its configuration
+    pub fn build(&self, kind: &str, name: &str, config: toml::Value) -> Result<Arc<dyn Device>> {
+        let constructor = self
+            .kinds
+            .get(kind)
+            .ok_or_else(|| Error::msg(format!("unknown device kind {}", kind)))?;
+
+        constructor(name, config).map_err(|e| Error::msg(format!("{} {}: {}", kind, name, e)))
+    }
+}
+
+/// Serialize the latest value of a watch, for a device's latest frame
+pub fn serialize_latest<T: Serialize, S: Serializer>(
+    latest: &watch::Sender<T>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    latest.borrow().serialize(serializer)
+}
+
+/// Deserialize command arguments, treating none as an empty object
+pub fn arguments<T: DeserializeOwned>(arguments: Value) -> Result<T> {
+    let arguments = match arguments {
+        Value::Null => Value::Object(Default::default()),
+        arguments => arguments,
+    };
+
+    Ok(serde_json::from_value(arguments)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Device, Driver, Health, Metadata, Monitor, Registry, State};
+    use anyhow::{Error, Result};
+    use chrono::{Duration, TimeZone, Utc};
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+    use std::any::Any;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::Arc;
+
+    struct Thermometer {
+        name: String,
+        port: Option<String>,
+        offset: f32,
+    }
+
+    #[derive(Deserialize)]
+    struct Config {
+        port: Option<String>,
+        offset: f32,
+    }
+
+    impl Device for Thermometer {
+        fn metadata(&self) -> Metadata {
+            Metadata {
+                name: self.name.clone(),
+                kind: Self::KIND.to_owned(),
+                port: self.port.clone(),
+                commands: Vec::new(),
+            }
+        }
+
+        fn health(&self) -> Health {
+            Monitor::new(self.port.is_none()).health(Utc::now(), Duration::seconds(1))
+        }
+
+        fn snapshot(&self) -> &dyn erased_serde::Serialize {
+            &self.offset
+        }
+
+        fn run(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    impl Driver for Thermometer {
+        const KIND: &'static str = "thermometer";
+        type Config = Config;
+
+        fn port(config: &Config) -> Option<&str> {
+            config.port.as_deref()
+        }
+
+        fn device(name: &str, path: &str, config: &Config) -> Arc<Self> {
+            Arc::new(Self {
+                name: name.to_owned(),
+                port: Some(path.to_owned()),
+                offset: config.offset,
+            })
+        }
+
+        fn loopback(name: &str, config: &Config) -> Arc<Self> {
+            Arc::new(Self {
+                name: name.to_owned(),
+                port: None,
+                offset: config.offset,
+            })
+        }
+    }
+
+    #[test]
+    fn build() {
+        let mut registry = Registry::default();
+        registry.register::<Thermometer>();
+
+        let config = toml::from_str("port = \"/dev/ttyUSB0\"\noffset = 1.5").unwrap();
+        let device = registry.build("thermometer", "cab", config).unwrap();
+
+        assert_eq!("cab", device.metadata().name);
+        assert_eq!(Some("/dev/ttyUSB0".to_owned()), device.metadata().port);
+        assert_eq!(json!(1.5), serde_json::to_value(device.snapshot()).unwrap());
+        assert!(device.command("reset", Value::Null).is_err());
+
+        let thermometer = device.as_any().downcast_ref::<Thermometer>().unwrap();
+        assert_eq!(1.5, thermometer.offset);
+
+        // unknown kinds and bad configuration are errors
+        let config = toml::from_str("offset = \"warm\"").unwrap();
+        assert!(registry.build("thermometer", "cab", config).is_err());
+        assert!(registry
+            .build("barometer", "cab", toml::Value::Integer(0))
+            .is_err());
+    }
+
+    #[test]
+    fn health() {
+        let start = Utc.ymd(2021, 6, 1).and_hms(12, 0, 0);
+        let stale_after = Duration::seconds(5);
+        let at = |seconds| start + Duration::seconds(seconds);
+
+        let mut monitor = Monitor::new(false);
+        assert_eq!(State::Starting, monitor.health(start, stale_after).state);
+
+        // a frame a second
+        for i in 0..20 {
+            monitor.frame(at(i));
+        }
+        let health = monitor.health(at(19), stale_after);
+        assert_eq!(State::Connected, health.state);
+        assert_eq!(1.0, health.frame_rate);
+
+        // a checksum error degrades it for a while
+        monitor.error(at(20), &"bad checksum");
+        monitor.frame(at(20));
+        assert_eq!(State::Degraded, monitor.health(at(20), stale_after).state);
+
+        // frames stop arriving
+        let health = monitor.health(at(30), stale_after);
+        assert_eq!(State::Degraded, health.state);
+        assert_eq!(0.0, health.frame_rate);
+
+        // the port goes away until frames resume
+        monitor.disconnected(at(31), &Error::msg("no such device"));
+        let health = monitor.health(at(31), stale_after);
+        assert_eq!(State::Disconnected, health.state);
+        assert_eq!(2, health.errors);
+        assert_eq!(Some("no such device".to_owned()), health.last_error);
+
+        monitor.frame(at(40));
+        assert_eq!(State::Degraded, monitor.health(at(40), stale_after).state);
 
-pub trait Device {
-    fn device(name: &str, path: &str) -> Arc<Self>;
-    fn loopback(name: &str) -> Arc<Self>;
+        // recovered once the error is no longer recent
+        monitor.frame(at(95));
+        assert_eq!(State::Connected, monitor.health(at(95), stale_after).state);
+    }
 }
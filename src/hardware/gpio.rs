@@ -0,0 +1,113 @@
+//! GPIO lines used as interrupts
+//!
+//! Lines are requested through the Linux GPIO character device API and
+//! waited on asynchronously.  A simulated line stands in for loopback and
+//! tests, raised by whatever simulates the device driving it.
+
+use anyhow::{Error, Result};
+use futures::StreamExt;
+use gpio_cdev::{AsyncLineEventHandle, Chip, EventRequestFlags, LineRequestFlags};
+use std::future::Future;
+use std::pin::Pin;
+use tokio::sync::mpsc;
+
+/// Line driven by a device to request attention
+pub trait Interrupt: Send {
+    /// Wait for the line to be raised
+    fn wait(&mut self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>>;
+}
+
+/// Rising edges of a line on a GPIO chip
+pub struct GpioLine {
+    events: AsyncLineEventHandle,
+}
+
+impl GpioLine {
+    pub fn open(chip: &str, line: u32, consumer: &str) -> Result<Self> {
+        let events = Chip::new(chip)?.get_line(line)?.events(
+            LineRequestFlags::INPUT,
+            EventRequestFlags::RISING_EDGE,
+            consumer,
+        )?;
+
+        Ok(Self {
+            events: AsyncLineEventHandle::new(events)?,
+        })
+    }
+}
+
+impl Interrupt for GpioLine {
+    fn wait(&mut self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            match self.events.next().await {
+                Some(event) => event.map(|_| ()).map_err(Error::from),
+                None => Err(Error::msg("GPIO line closed")),
+            }
+        })
+    }
+}
+
+/// Line raised in software
+///
+/// Edges raised while nobody is waiting are coalesced into one, as with a
+/// latched interrupt.
+pub struct SimulatedLine {
+    edges: mpsc::Receiver<()>,
+}
+
+/// Raises a simulated line
+#[derive(Clone)]
+pub struct Trigger {
+    edges: mpsc::Sender<()>,
+}
+
+impl SimulatedLine {
+    pub fn new() -> (Self, Trigger) {
+        let (sender, edges) = mpsc::channel(1);
+        (Self { edges }, Trigger { edges: sender })
+    }
+}
+
+impl Interrupt for SimulatedLine {
+    fn wait(&mut self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(async move {
+            self.edges
+                .recv()
+                .await
+                .ok_or_else(|| Error::msg("simulated line has no trigger"))
+        })
+    }
+}
+
+impl Trigger {
+    pub fn raise(&self) {
+        // a full channel already has an edge pending
+        let _ = self.edges.try_send(());
+    }
+
+    /// Whether the line has been dropped
+    pub fn is_closed(&self) -> bool {
+        self.edges.is_closed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Interrupt, SimulatedLine};
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn coalesces_edges() {
+        let (mut line, trigger) = SimulatedLine::new();
+        let quiet = Duration::from_millis(10);
+
+        trigger.raise();
+        trigger.raise();
+        assert!(timeout(quiet, line.wait()).await.unwrap().is_ok());
+        assert!(timeout(quiet, line.wait()).await.is_err());
+
+        drop(trigger);
+        assert!(timeout(quiet, line.wait()).await.unwrap().is_err());
+    }
+}
@@ -0,0 +1,110 @@
+//! I2C buses
+//!
+//! Drivers are written against the embedded-hal `I2c` trait, so the same
+//! driver runs on a Linux I2C adapter or on a simulated bus of register maps.
+
+pub mod simulated;
+
+use embedded_hal::i2c::{ErrorKind, ErrorType, I2c, Operation};
+use i2c_linux::{Message, ReadFlags, WriteFlags};
+use std::fmt;
+use std::fs::File;
+use std::io;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Failure reported by the adapter
+    Io(io::Error),
+
+    /// The device returned fewer bytes than requested
+    ShortRead { expected: usize, actual: usize },
+
+    /// Bus condition raised by a simulated device
+    Bus(ErrorKind),
+}
+
+impl embedded_hal::i2c::Error for Error {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Bus(kind) => *kind,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::ShortRead { expected, actual } => {
+                write!(f, "short read of {} bytes, expected {}", actual, expected)
+            }
+            Error::Bus(kind) => write!(f, "{}", kind),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// I2C adapter exposed by Linux as `/dev/i2c-*`
+pub struct LinuxI2c {
+    i2c: i2c_linux::I2c<File>,
+}
+
+impl LinuxI2c {
+    pub fn open(path: &str) -> Result<Self, Error> {
+        Ok(Self {
+            i2c: i2c_linux::I2c::from_path(path)?,
+        })
+    }
+}
+
+impl ErrorType for LinuxI2c {
+    type Error = Error;
+}
+
+impl I2c for LinuxI2c {
+    /// Perform the operations as one transfer, separated by repeated starts
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let mut messages: Vec<Message> = operations
+            .iter_mut()
+            .map(|operation| match operation {
+                Operation::Read(data) => Message::Read {
+                    address: address as u16,
+                    data,
+                    flags: ReadFlags::default(),
+                },
+                Operation::Write(data) => Message::Write {
+                    address: address as u16,
+                    data,
+                    flags: WriteFlags::default(),
+                },
+            })
+            .collect();
+
+        let expected: Vec<usize> = messages.iter().map(|message| message.len()).collect();
+        self.i2c.i2c_transfer(&mut messages)?;
+
+        // reads are truncated to the length actually received
+        for (message, expected) in messages.iter().zip(expected) {
+            if message.len() != expected {
+                return Err(Error::ShortRead {
+                    expected,
+                    actual: message.len(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+}
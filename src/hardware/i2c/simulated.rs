@@ -0,0 +1,246 @@
+//! In-memory I2C bus of scriptable register-mapped devices
+//!
+//! Each device is a `RegisterMap`: a register pointer set by the first byte
+//! written, auto-incremented by each byte transferred, and optionally banked
+//! by a bank select register.  Hooks on individual registers let a model
+//! react to writes and refresh values before they are read, and faults can
+//! be queued to exercise error handling.
+
+use super::Error;
+use embedded_hal::i2c::{ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Register as (bank, address)
+pub type Register = (u8, u8);
+
+type ReadHook = Box<dyn FnMut(&mut RegisterMap) + Send>;
+type WriteHook = Box<dyn FnMut(&mut RegisterMap, u8) + Send>;
+
+/// Simulated bus, whose clones share the same devices
+///
+/// A test or simulation keeps a clone to script and inspect devices while a
+/// driver owns the bus.
+#[derive(Clone, Default)]
+pub struct SimulatedBus {
+    devices: Arc<Mutex<HashMap<u8, RegisterMap>>>,
+}
+
+impl SimulatedBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attach a device at `address`, replacing any already there
+    pub fn attach(&self, address: u8, device: RegisterMap) {
+        self.devices.lock().unwrap().insert(address, device);
+    }
+
+    /// Run `f` on the device at `address`, if one is attached
+    pub fn device<T>(&self, address: u8, f: impl FnOnce(&mut RegisterMap) -> T) -> Option<T> {
+        self.devices.lock().unwrap().get_mut(&address).map(f)
+    }
+}
+
+impl ErrorType for SimulatedBus {
+    type Error = Error;
+}
+
+impl I2c for SimulatedBus {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        match self.devices.lock().unwrap().get_mut(&address) {
+            Some(device) => device.transaction(operations),
+            None => Err(Error::Bus(ErrorKind::NoAcknowledge(
+                NoAcknowledgeSource::Address,
+            ))),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RegisterMap {
+    registers: HashMap<Register, u8>,
+
+    /// Bank select register address and the shift of the bank within it
+    bank_select: Option<(u8, u8)>,
+    bank: u8,
+    pointer: u8,
+
+    /// Registers read as a queue, without advancing the pointer
+    streams: HashMap<Register, VecDeque<u8>>,
+
+    read_hooks: Vec<(Register, ReadHook)>,
+    write_hooks: Vec<(Register, WriteHook)>,
+
+    /// Failures for the next transactions, which then have no effect
+    faults: VecDeque<ErrorKind>,
+
+    writes: Vec<(Register, u8)>,
+}
+
+impl RegisterMap {
+    /// Device whose bank is selected by writing `select`, with the bank number
+    /// shifted left by `shift`
+    pub fn banked(select: u8, shift: u8) -> Self {
+        Self {
+            bank_select: Some((select, shift)),
+            ..Self::default()
+        }
+    }
+
+    /// Currently selected bank
+    #[cfg(test)]
+    pub fn bank(&self) -> u8 {
+        self.bank
+    }
+
+    pub fn get(&self, register: Register) -> u8 {
+        self.registers.get(&register).copied().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, register: Register, value: u8) {
+        self.registers.insert(register, value);
+    }
+
+    /// Set consecutive registers starting at `register`
+    pub fn set_block(&mut self, (bank, address): Register, values: &[u8]) {
+        for (i, value) in values.iter().enumerate() {
+            self.set((bank, address.wrapping_add(i as u8)), *value);
+        }
+    }
+
+    /// Queue bytes to be read from a stream register such as a FIFO
+    pub fn push(&mut self, register: Register, data: &[u8]) {
+        self.streams
+            .entry(register)
+            .or_default()
+            .extend(data.iter().copied());
+    }
+
+    /// Number of bytes queued in a stream register
+    pub fn queued(&self, register: Register) -> usize {
+        self.streams.get(&register).map_or(0, |stream| stream.len())
+    }
+
+    /// Discard bytes queued in a stream register
+    pub fn clear(&mut self, register: Register) {
+        if let Some(stream) = self.streams.get_mut(&register) {
+            stream.clear();
+        }
+    }
+
+    /// Clear all registers and streams and return to bank 0, as on power up
+    pub fn reset(&mut self) {
+        self.registers.clear();
+        self.streams.values_mut().for_each(VecDeque::clear);
+        self.bank = 0;
+    }
+
+    /// Call `hook` before `register` is read by the bus
+    pub fn on_read(
+        &mut self,
+        register: Register,
+        hook: impl FnMut(&mut RegisterMap) + Send + 'static,
+    ) {
+        self.read_hooks.push((register, Box::new(hook)));
+    }
+
+    /// Call `hook` with the value after `register` is written by the bus
+    pub fn on_write(
+        &mut self,
+        register: Register,
+        hook: impl FnMut(&mut RegisterMap, u8) + Send + 'static,
+    ) {
+        self.write_hooks.push((register, Box::new(hook)));
+    }
+
+    /// Fail the next transaction with `kind`
+    #[cfg(test)]
+    pub fn fail(&mut self, kind: ErrorKind) {
+        self.faults.push_back(kind);
+    }
+
+    /// Every value written by the bus, in order
+    #[cfg(test)]
+    pub fn writes(&self) -> &[(Register, u8)] {
+        &self.writes
+    }
+
+    fn transaction(&mut self, operations: &mut [Operation<'_>]) -> Result<(), Error> {
+        if let Some(kind) = self.faults.pop_front() {
+            return Err(Error::Bus(kind));
+        }
+
+        for operation in operations {
+            match operation {
+                Operation::Write(data) => {
+                    if let Some((pointer, values)) = data.split_first() {
+                        self.pointer = *pointer;
+
+                        for value in values {
+                            self.write(*value);
+                            self.pointer = self.pointer.wrapping_add(1);
+                        }
+                    }
+                }
+                Operation::Read(data) => {
+                    for value in data.iter_mut() {
+                        *value = self.read();
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn register(&self) -> Register {
+        match self.bank_select {
+            // bank select is reachable from every bank
+            Some((select, _)) if select == self.pointer => (0, select),
+            _ => (self.bank, self.pointer),
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        let register = self.register();
+
+        let mut hooks = std::mem::take(&mut self.read_hooks);
+        for (_, hook) in hooks.iter_mut().filter(|(r, _)| *r == register) {
+            hook(self);
+        }
+        hooks.append(&mut self.read_hooks);
+        self.read_hooks = hooks;
+
+        match self.streams.get_mut(&register) {
+            Some(stream) => stream.pop_front().unwrap_or(0),
+            None => {
+                self.pointer = self.pointer.wrapping_add(1);
+                self.get(register)
+            }
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        let register = self.register();
+        self.writes.push((register, value));
+        self.set(register, value);
+
+        if let Some((select, shift)) = self.bank_select {
+            if register == (0, select) {
+                self.bank = value >> shift;
+            }
+        }
+
+        let mut hooks = std::mem::take(&mut self.write_hooks);
+        for (_, hook) in hooks.iter_mut().filter(|(r, _)| *r == register) {
+            hook(self, value);
+        }
+        hooks.append(&mut self.write_hooks);
+        self.write_hooks = hooks;
+    }
+}
@@ -1,135 +1,886 @@
-use crate::hardware::device::Device;
-use anyhow::Result;
+pub mod ahrs;
+pub mod alarm;
+pub mod calibration;
+pub mod compass;
+pub mod icm20948;
+pub mod impact;
+pub mod leveling;
+pub mod simulator;
+pub mod trip;
+pub mod vibration;
+
+use crate::hardware::config;
+use crate::hardware::device::{self, Device, Driver, Health, Metadata, Monitor};
+use crate::hardware::gpio::{GpioLine, Interrupt};
+use crate::hardware::i2c::LinuxI2c;
+use crate::hardware::timestamp::Timestamp;
+use crate::telemetry::unit::{Celsius, Unit};
+use crate::telemetry::{self, Signals, Spec, Value as Signal};
+use ahrs::{Ahrs, Orientation};
+use alarm::{Alarm, Armed};
+use anyhow::{Error, Result};
+use calibration::{Calibrator, Routine, Status};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use compass::{Compass, Heading};
+use embedded_hal::i2c::I2c;
+use icm20948::{Drain, Registers, ACCEL_SENSITIVITY, FIFO_SIZE, GYRO_SENSITIVITY, PACKET_SIZE};
+use impact::ImpactRecorder;
+use leveling::{Level, Leveling, Reference};
 use nalgebra as na;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::any::Any;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
+use tokio::sync::{broadcast, watch};
 use tokio::task;
-use tokio::time::{sleep, Duration};
+use tokio::time::{self, sleep, Duration, Instant};
+use trip::{TripDetector, Trips};
+use vibration::VibrationAnalyzer;
+
+pub use habctl_protocol::imu::ImuEvent;
+
+/// Default FIFO output data rate, in Hz
+const DEFAULT_SAMPLE_RATE: f32 = 200.0;
+
+/// Default accelerometer full scale, in g
+const DEFAULT_ACCELEROMETER_RANGE: u8 = 2;
+
+/// Default interval between published frames, in milliseconds
+const DEFAULT_FRAME_INTERVAL: u64 = 1000;
+
+/// Number of raw samples buffered for slow subscribers
+const SAMPLE_CHANNEL_CAPACITY: usize = 4096;
+
+/// Number of events buffered for slow subscribers
+const EVENT_CHANNEL_CAPACITY: usize = 64;
+
+/// Number of recent events kept for the API
+const RECENT_EVENTS: usize = 100;
+
+/// Commands accepted by `Device::command`
+const COMMANDS: &[&str] = &[
+    "set_level_reference",
+    "clear_level_reference",
+    "start_calibration",
+    "cancel_calibration",
+    "set_alarm",
+];
+
+/// Arguments of the `start_calibration` command
+#[derive(Deserialize)]
+struct StartCalibration {
+    routine: Routine,
+}
 
 #[derive(Serialize)]
 pub struct Icm20948 {
     loopback: bool,
     name: String,
     port: String,
-    pub telemetry: Mutex<ImuFrame>,
+
+    /// Configured output data rate, in Hz
+    sample_rate: f32,
+
+    /// Accelerometer full scale, in g
+    accelerometer_range: u8,
+
+    /// Interval between published telemetry frames
+    frame_interval: Duration,
+
+    /// Number of times the FIFO has overflowed and been reset
+    fifo_overflows: AtomicU32,
+
+    #[serde(skip)]
+    monitor: Mutex<Monitor>,
+
+    #[serde(skip)]
+    interrupt: Option<config::Interrupt>,
+
+    #[serde(skip)]
+    ahrs: config::Ahrs,
+
+    #[serde(skip)]
+    compass: Compass,
+
+    #[serde(skip)]
+    leveling: Option<Mutex<Leveling>>,
+
+    #[serde(skip)]
+    calibrator: Mutex<Calibrator>,
+
+    #[serde(skip)]
+    alarm: Mutex<Alarm>,
+
+    #[serde(skip)]
+    trips: Mutex<TripDetector>,
+
+    #[serde(skip)]
+    impacts: Mutex<ImpactRecorder>,
+
+    #[serde(skip)]
+    vibration: Option<Mutex<VibrationAnalyzer>>,
+
+    #[serde(skip)]
+    events: broadcast::Sender<ImuEvent>,
+
+    #[serde(skip)]
+    recent_events: Mutex<VecDeque<ImuEvent>>,
+
+    /// Latest frame, also published to the telemetry bus
+    #[serde(rename = "telemetry", serialize_with = "device::serialize_latest")]
+    frames: watch::Sender<ImuFrame>,
+
+    #[serde(skip)]
+    samples: broadcast::Sender<ImuSample>,
 }
 
 impl Device for Icm20948 {
-    fn device(name: &str, path: &str) -> Arc<Icm20948> {
-        Arc::new(Icm20948 {
-            loopback: false,
-            name: name.to_owned(),
-            port: path.to_owned(),
-            telemetry: Mutex::default(),
-        })
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            name: self.name.clone(),
+            kind: Self::KIND.to_owned(),
+            port: if self.loopback {
+                None
+            } else {
+                Some(self.port.clone())
+            },
+            commands: COMMANDS.iter().map(|c| c.to_string()).collect(),
+        }
     }
 
-    fn loopback(name: &str) -> Arc<Icm20948> {
-        Arc::new(Icm20948 {
-            loopback: true,
-            name: name.to_owned(),
-            port: String::new(),
-            telemetry: Mutex::default(),
-        })
+    fn health(&self) -> Health {
+        let monitor = self.monitor.lock().unwrap();
+        monitor.health(Utc::now(), telemetry::bus().stale_after())
+    }
+
+    fn snapshot(&self) -> &dyn erased_serde::Serialize {
+        self
+    }
+
+    fn command(&self, command: &str, arguments: Value) -> Result<Value> {
+        match command {
+            "set_level_reference" => Ok(json!(self.set_level_reference()?)),
+            "clear_level_reference" => Ok(json!(self.clear_level_reference()?)),
+            "start_calibration" => {
+                let arguments: StartCalibration = device::arguments(arguments)?;
+                Ok(json!(self.start_calibration(arguments.routine)))
+            }
+            "cancel_calibration" => Ok(json!(self.cancel_calibration())),
+            "set_alarm" => {
+                let armed: Armed = device::arguments(arguments)?;
+                Ok(json!(self.set_alarm(armed.armed)?))
+            }
+            _ => Err(Error::msg(format!(
+                "IMU {} does not support {}",
+                self.name, command
+            ))),
+        }
+    }
+
+    fn run(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(Icm20948::run(self))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Driver for Icm20948 {
+    const KIND: &'static str = "imu";
+
+    type Config = config::Imu;
+
+    fn port(config: &config::Imu) -> Option<&str> {
+        config.port.as_deref()
+    }
+
+    fn device(name: &str, path: &str, config: &config::Imu) -> Arc<Icm20948> {
+        Arc::new(Icm20948::new(name, path, false, config))
+    }
+
+    fn loopback(name: &str, config: &config::Imu) -> Arc<Icm20948> {
+        Arc::new(Icm20948::new(name, "", true, config))
     }
 }
 
 impl Icm20948 {
-    pub async fn run(&self) -> Result<()> {
+    fn new(name: &str, path: &str, loopback: bool, config: &config::Imu) -> Icm20948 {
+        let (samples, _) = broadcast::channel(SAMPLE_CHANNEL_CAPACITY);
+        let (events, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+
+        let sample_rate = config.sample_rate.unwrap_or(DEFAULT_SAMPLE_RATE);
+        let mounting = config.mounting.unwrap_or_default();
+        let mounting = na::UnitQuaternion::from_euler_angles(
+            mounting.roll.to_radians(),
+            mounting.pitch.to_radians(),
+            mounting.yaw.to_radians(),
+        );
+
+        Icm20948 {
+            loopback,
+            name: name.to_owned(),
+            port: path.to_owned(),
+            sample_rate,
+            accelerometer_range: config
+                .accelerometer_range
+                .unwrap_or(DEFAULT_ACCELEROMETER_RANGE),
+            frame_interval: Duration::from_millis(
+                config.frame_interval.unwrap_or(DEFAULT_FRAME_INTERVAL),
+            ),
+            fifo_overflows: AtomicU32::new(0),
+            monitor: Mutex::new(Monitor::new(loopback)),
+            interrupt: config.interrupt.clone(),
+            ahrs: config.ahrs.clone(),
+            compass: Compass::new(&config.compass, mounting),
+            leveling: config
+                .leveling
+                .as_ref()
+                .map(|leveling| Mutex::new(Leveling::new(name, leveling, mounting))),
+            calibrator: Mutex::new(Calibrator::new(name, &config.calibration)),
+            alarm: Mutex::new(Alarm::new(name, &config.alarm)),
+            trips: Mutex::new(TripDetector::new(name, &config.trip, mounting)),
+            impacts: Mutex::new(ImpactRecorder::new(name, &config.impact)),
+            vibration: VibrationAnalyzer::new(name, &config.vibration, sample_rate)
+                .map_err(|e| log::error!("IMU {}: vibration: {}", name, e))
+                .ok()
+                .map(Mutex::new),
+            events,
+            recent_events: Mutex::default(),
+            frames: watch::channel(ImuFrame::default()).0,
+            samples,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Latest orientation estimate
+    pub fn orientation(&self) -> Option<Orientation> {
+        self.frames.borrow().orientation
+    }
+
+    /// Latest compass heading
+    pub fn heading(&self) -> Option<Heading> {
+        self.frames.borrow().heading
+    }
+
+    /// Latest leveling state, if leveling is configured
+    pub fn level(&self) -> Option<Level> {
+        self.frames.borrow().level.clone()
+    }
+
+    /// Take the current attitude as level
+    pub fn set_level_reference(&self) -> Result<Reference> {
+        self.leveling()?.lock().unwrap().set_reference()
+    }
+
+    /// Return to the IMU mounting as the level reference
+    pub fn clear_level_reference(&self) -> Result<()> {
+        self.leveling()?.lock().unwrap().clear_reference()
+    }
+
+    /// Calibration and the progress of any routine
+    pub fn calibration(&self) -> Status {
+        self.calibrator.lock().unwrap().status()
+    }
+
+    /// Start a calibration routine, replacing any in progress
+    pub fn start_calibration(&self, routine: Routine) -> Status {
+        let mut calibrator = self.calibrator.lock().unwrap();
+        calibrator.start(routine);
+        calibrator.status()
+    }
+
+    /// Abandon any calibration routine in progress
+    pub fn cancel_calibration(&self) -> Status {
+        let mut calibrator = self.calibrator.lock().unwrap();
+        calibrator.cancel();
+        calibrator.status()
+    }
+
+    /// Whether the motion and tamper alarm is armed
+    pub fn alarm(&self) -> Armed {
+        self.alarm.lock().unwrap().armed()
+    }
+
+    /// Arm or disarm the motion and tamper alarm
+    pub fn set_alarm(&self, armed: bool) -> Result<Armed> {
+        self.alarm.lock().unwrap().set_armed(armed)
+    }
+
+    /// Trip history and the trip in progress
+    pub fn trips(&self) -> Trips {
+        self.trips.lock().unwrap().trips()
+    }
+
+    /// Band powers of recent minutes, oldest first
+    pub fn vibration(&self) -> Result<vibration::History> {
+        self.vibration
+            .as_ref()
+            .map(|vibration| vibration.lock().unwrap().history())
+            .ok_or_else(|| Error::msg(format!("vibration analysis is disabled for {}", self.name)))
+    }
+
+    /// Names of the saved impact recordings, oldest first
+    pub fn impacts(&self) -> Result<Vec<String>> {
+        impact::recordings(&self.name)
+    }
+
+    /// Samples of a saved impact recording, as CSV
+    pub fn impact(&self, recording: &str) -> Result<String> {
+        impact::recording(&self.name, recording)
+    }
+
+    /// Recent events, oldest first
+    pub fn events(&self) -> Vec<ImuEvent> {
+        self.recent_events.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Subscribe to events as they occur
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ImuEvent> {
+        self.events.subscribe()
+    }
+
+    fn emit(&self, event: ImuEvent) {
+        log::info!("IMU {}: {:?}", self.name, event);
+
+        let mut recent = self.recent_events.lock().unwrap();
+        if recent.len() == RECENT_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+
+        // no receivers is not an error
+        let _ = self.events.send(event);
+    }
+
+    fn leveling(&self) -> Result<&Mutex<Leveling>> {
+        self.leveling
+            .as_ref()
+            .ok_or_else(|| Error::msg(format!("leveling is not configured for {}", self.name)))
+    }
+
+    /// Subscribe to the raw, full-rate sample stream
+    pub fn subscribe(&self) -> broadcast::Receiver<ImuSample> {
+        self.samples.subscribe()
+    }
+
+    async fn run(&self) -> Result<()> {
+        telemetry::bus().describe::<ImuFrame>(&self.name);
+
         if self.loopback {
-            log::debug!("Icm20948 {} is in loopback mode.", self.name);
-            sleep(Duration::from_secs(600)).await;
+            log::debug!("Icm20948 {} is simulated in loopback mode.", self.name);
         } else {
             log::debug!("Icm20948 {} at {}", self.name, self.port);
+        }
 
-            loop {
-                task::block_in_place(|| {
-                    self.read_imu_data();
-                });
+        loop {
+            let result = if self.loopback {
+                let (bus, line) = simulator::bus();
+                let line = self
+                    .interrupt
+                    .as_ref()
+                    .map(|_| Box::new(line) as Box<dyn Interrupt>);
 
-                tokio::time::sleep(Duration::from_secs(1)).await;
+                self.sample(bus, line).await
+            } else {
+                match task::block_in_place(|| self.open()) {
+                    Ok((bus, line)) => self.sample(bus, line).await,
+                    Err(e) => Err(e),
+                }
+            };
+
+            if let Err(e) = result {
+                log::error!("IMU {}: {}", self.name, e);
+                self.monitor.lock().unwrap().disconnected(Utc::now(), &e);
             }
+
+            sleep(Duration::from_secs(1)).await;
         }
+    }
+
+    /// Open the I2C bus and the interrupt line, if configured
+    fn open(&self) -> Result<(LinuxI2c, Option<Box<dyn Interrupt>>)> {
+        let bus = LinuxI2c::open(&self.port)?;
 
-        Ok(())
+        let line = match &self.interrupt {
+            Some(interrupt) => {
+                let line = GpioLine::open(&interrupt.chip, interrupt.line, "habctl")?;
+                Some(Box::new(line) as Box<dyn Interrupt>)
+            }
+            None => None,
+        };
+
+        Ok((bus, line))
     }
 
-    fn read_imu_data(&self) {
-        let mut frame = ImuFrame::default();
+    /// Configure the device and drain its FIFO until an error occurs
+    ///
+    /// The FIFO is drained when `line` is raised, or polled if there is no line.
+    async fn sample<B>(&self, bus: B, mut line: Option<Box<dyn Interrupt>>) -> Result<()>
+    where
+        B: I2c,
+        B::Error: std::error::Error + Send + Sync + 'static,
+    {
+        let mut imu = Registers::new(bus);
+        let rate =
+            task::block_in_place(|| imu.configure(self.sample_rate, self.accelerometer_range))?;
+        let accel_sensitivity = ACCEL_SENSITIVITY * 2.0 / self.accelerometer_range as f32;
+
+        if let Some(vibration) = &self.vibration {
+            vibration.lock().unwrap().set_sample_rate(rate);
+        }
+        let period = ChronoDuration::nanoseconds((1e9 / rate) as i64);
+
+        log::info!("IMU {}: sampling at {:.1} Hz", self.name, rate);
+
+        let magnetometer = match task::block_in_place(|| imu.start_magnetometer()) {
+            Ok(()) => true,
+            Err(e) => {
+                log::warn!("IMU {}: no magnetometer: {}", self.name, e);
+                false
+            }
+        };
+
+        if let Some(interrupt) = &self.interrupt {
+            task::block_in_place(|| imu.enable_interrupt(interrupt.source))?;
+        }
+
+        // wake-on-motion threshold applied to the device
+        let mut wake = None;
 
-        match i2c_linux::I2c::from_path(self.port.to_owned()) {
-            Ok(i2c) => {
-                let mut i2c = i2c;
-                i2c.smbus_set_slave_address(0x69, false)
-                    .expect("set i2c address");
-
-                // power on, best available clock
-                i2c.smbus_write_byte_data(0x06, 0x01).expect("power up");
-
-                let accel_xout_h = i2c.smbus_read_byte_data(0x2d).unwrap() as f32;
-                let accel_xout_l = i2c.smbus_read_byte_data(0x2e).unwrap() as f32;
-                let accel_yout_h = i2c.smbus_read_byte_data(0x2f).unwrap() as f32;
-                let accel_yout_l = i2c.smbus_read_byte_data(0x30).unwrap() as f32;
-                let accel_zout_h = i2c.smbus_read_byte_data(0x31).unwrap() as f32;
-                let accel_zout_l = i2c.smbus_read_byte_data(0x32).unwrap() as f32;
-                let gyro_xout_h = i2c.smbus_read_byte_data(0x33).unwrap() as f32;
-                let gyro_xout_l = i2c.smbus_read_byte_data(0x34).unwrap() as f32;
-                let gyro_yout_h = i2c.smbus_read_byte_data(0x35).unwrap() as f32;
-                let gyro_yout_l = i2c.smbus_read_byte_data(0x36).unwrap() as f32;
-                let gyro_zout_h = i2c.smbus_read_byte_data(0x37).unwrap() as f32;
-                let gyro_zout_l = i2c.smbus_read_byte_data(0x38).unwrap() as f32;
-                let temp_out_h = i2c.smbus_read_byte_data(0x39).unwrap() as f32;
-                let temp_out_l = i2c.smbus_read_byte_data(0x3a).unwrap() as f32;
-
-                fn scale(hi: f32, lo: f32, s: f32) -> f32 {
-                    (hi * 256.0 + lo) * s
+        // without an interrupt, drain when the FIFO is about half full, but at least once per frame
+        let half_full = Duration::from_secs_f32((FIFO_SIZE / 2 / PACKET_SIZE) as f32 / rate);
+        let mut interval = time::interval(half_full.min(self.frame_interval));
+
+        let mut buffer = vec![0u8; FIFO_SIZE];
+        let mut decimator = Decimator::default();
+        let mut ahrs = Ahrs::new(&self.ahrs);
+        let mut frame_start = Instant::now();
+
+        // capture time of the frame, when its first samples were drained
+        let mut captured = None;
+
+        loop {
+            match &mut line {
+                // bounded by the frame interval, so frames are published while the line is quiet
+                Some(line) => {
+                    if let Ok(raised) = time::timeout(self.frame_interval, line.wait()).await {
+                        raised?;
+                    }
+
+                    let threshold = self.alarm.lock().unwrap().wake_threshold();
+                    if threshold != wake {
+                        task::block_in_place(|| imu.wake_on_motion(threshold))?;
+                        wake = threshold;
+                    }
+                }
+                None => {
+                    interval.tick().await;
+                }
+            }
+
+            let drained = task::block_in_place(|| imu.drain(&mut buffer))?;
+            let drain_time = Timestamp::now();
+            let now = drain_time.utc;
+
+            // the magnetometer runs at 100Hz, so its latest value applies to the whole batch
+            let field = if magnetometer {
+                task::block_in_place(|| imu.magnetometer())?
+            } else {
+                None
+            };
+
+            match drained {
+                Drain::Overflow => {
+                    let count = self.fifo_overflows.fetch_add(1, Ordering::Relaxed) + 1;
+                    log::warn!("IMU {}: FIFO overflow ({} total), reset", self.name, count);
+                    self.monitor
+                        .lock()
+                        .unwrap()
+                        .error(Utc::now(), &"FIFO overflow");
+                    continue;
                 }
+                Drain::Packets(len) => {
+                    if len > 0 && captured.is_none() {
+                        captured = Some(drain_time);
+                    }
 
-                const ACCEL_SCALE: f32 = 4.0 / 65535.0;
-                let accel_x = scale(accel_xout_h, accel_xout_l, ACCEL_SCALE);
-                let accel_y = scale(accel_yout_h, accel_yout_l, ACCEL_SCALE);
-                let accel_z = scale(accel_zout_h, accel_zout_l, ACCEL_SCALE);
+                    // the last packet was captured most recently, so work backwards from now
+                    let packets = buffer[..len].chunks_exact(PACKET_SIZE);
+                    let count = packets.len() as i32;
+                    let mut calibrator = self.calibrator.lock().unwrap();
+                    let mut alarm = self.alarm.lock().unwrap();
+                    let mut trips = self.trips.lock().unwrap();
+                    let mut impacts = self.impacts.lock().unwrap();
+                    let mut vibration = self.vibration.as_ref().map(|v| v.lock().unwrap());
+                    let mut trip_ended = false;
+                    let mut recordings = Vec::new();
 
-                frame.accelerometer = Some(na::Vector3::new(accel_x, accel_y, accel_z));
+                    for (i, packet) in packets.enumerate() {
+                        let mut sample = ImuSample::from_packet(
+                            now - period * (count - 1 - i as i32),
+                            packet,
+                            accel_sensitivity,
+                        );
+                        sample.magnetometer = field;
 
-                const GYRO_SCALE: f32 = 250.0 / 65535.0;
-                let gyro_x = scale(gyro_xout_h, gyro_xout_l, GYRO_SCALE);
-                let gyro_y = scale(gyro_yout_h, gyro_yout_l, GYRO_SCALE);
-                let gyro_z = scale(gyro_zout_h, gyro_zout_l, GYRO_SCALE);
+                        calibrator.add(&sample);
+                        calibrator.calibration().apply(&mut sample);
 
-                frame.gyrometer = Some(na::Vector3::new(gyro_x, gyro_y, gyro_z));
+                        ahrs.update(
+                            &sample.gyrometer,
+                            &sample.accelerometer,
+                            sample.magnetometer.as_ref(),
+                            1.0 / rate,
+                        );
+                        decimator.add(&sample);
 
-                const TEMP_SCALE: f32 = 1.0 / 333.87;
-                let temp = scale(temp_out_h, temp_out_l, TEMP_SCALE) + 21.0;
+                        if let Some(event) = alarm.sample(sample.timestamp, &sample.accelerometer) {
+                            self.emit(event);
+                        }
 
-                frame.temperature = Some(temp);
-                frame.timestamp = Some(crate::hardware::timestamp());
+                        // there is no GPS yet, so driving is detected from vibration alone
+                        if let Some(event) = trips.sample(&sample, &ahrs.up(), None) {
+                            if let ImuEvent::TripEnd { .. } = event {
+                                trip_ended = true;
+                            }
 
-                log::info!("{}: {}", self.name, frame);
-                *self.telemetry.lock().unwrap() = frame;
+                            self.emit(event);
+                        }
+
+                        if let Some(vibration) = &mut vibration {
+                            vibration.sample(&sample, &ahrs.up(), trips.driving());
+                        }
+
+                        if let Some(recording) = impacts.sample(&sample, &ahrs.up()) {
+                            recordings.push(recording);
+                        }
+
+                        // no receivers is not an error
+                        let _ = self.samples.send(sample);
+                    }
+
+                    // write after releasing the locks, so that nothing waits on the disk
+                    let calibration = calibrator.unsaved();
+                    let trip_history = trip_ended.then(|| trips.snapshot());
+                    let vibration_history = vibration.as_mut().and_then(|v| v.unsaved());
+                    drop((calibrator, alarm, trips, impacts, vibration));
+
+                    if let Some(calibration) = calibration {
+                        if let Err(e) = task::block_in_place(|| calibration.save()) {
+                            log::error!("IMU {}: calibration: {}", self.name, e);
+                        }
+                    }
+                    if let Some(trip_history) = trip_history {
+                        if let Err(e) = task::block_in_place(|| trip_history.save()) {
+                            log::error!("IMU {}: trips: {}", self.name, e);
+                        }
+                    }
+                    if let Some(vibration_history) = vibration_history {
+                        if let Err(e) = task::block_in_place(|| vibration_history.save()) {
+                            log::error!("IMU {}: vibration: {}", self.name, e);
+                        }
+                    }
+
+                    // announced once saved, so the recording can be fetched
+                    for recording in recordings {
+                        if let Err(e) = task::block_in_place(|| recording.save()) {
+                            log::error!("IMU {}: impact: {}", self.name, e);
+                        }
+
+                        self.emit(ImuEvent::Impact {
+                            impact: recording.impact,
+                        });
+                    }
+                }
             }
-            Err(e) => {
-                log::error!("IMU {}: {}", self.name, e);
+
+            if frame_start.elapsed() >= self.frame_interval {
+                frame_start = Instant::now();
+
+                let mut frame = decimator.take();
+                frame.orientation = Some(ahrs.orientation());
+                frame.driving = Some(self.trips.lock().unwrap().driving());
+
+                if let (Some(a), Some(m)) = (&frame.accelerometer, &frame.magnetometer) {
+                    frame.heading = self.compass.heading(a, m);
+                }
+
+                if let Some(a) = &frame.accelerometer {
+                    if let Some(leveling) = &self.leveling {
+                        frame.level = leveling.lock().unwrap().update(a);
+                    }
+
+                    let event = self.alarm.lock().unwrap().frame(Utc::now(), a);
+                    if let Some(event) = event {
+                        self.emit(event);
+                    }
+                }
+
+                frame.temperature = Some(Celsius(task::block_in_place(|| imu.temperature())?));
+                let timestamp = captured.take().unwrap_or(drain_time);
+                frame.timestamp = Some(timestamp);
+
+                log::info!("{}: {}", self.name, frame);
+                telemetry::bus().publish(&self.name, timestamp.utc, &frame);
+                self.frames.send_replace(frame);
+                self.monitor.lock().unwrap().frame(timestamp.utc);
             }
         }
     }
 }
 
+/// A single full-rate sample from the FIFO
+#[derive(Copy, Clone, Debug, Serialize)]
+pub struct ImuSample {
+    /// Estimated capture time
+    pub timestamp: DateTime<Utc>,
+
+    /// Rotation rate in degrees per second
+    pub gyrometer: na::Vector3<f32>,
+
+    /// Acceleration in g
+    pub accelerometer: na::Vector3<f32>,
+
+    /// Latest magnetic field in microtesla, aligned to the accelerometer axes
+    pub magnetometer: Option<na::Vector3<f32>>,
+}
+
+impl ImuSample {
+    /// Decode a FIFO packet of big-endian accelerometer then gyrometer axes,
+    /// given the accelerometer LSB per g
+    fn from_packet(timestamp: DateTime<Utc>, packet: &[u8], accel_sensitivity: f32) -> Self {
+        let axis = |i: usize| i16::from_be_bytes([packet[2 * i], packet[2 * i + 1]]) as f32;
+
+        Self {
+            timestamp,
+            accelerometer: na::Vector3::new(axis(0), axis(1), axis(2)) / accel_sensitivity,
+            gyrometer: na::Vector3::new(axis(3), axis(4), axis(5)) / GYRO_SENSITIVITY,
+            magnetometer: None,
+        }
+    }
+}
+
+/// Averages samples down to the frame rate
+#[derive(Default)]
+struct Decimator {
+    count: u32,
+    accelerometer: na::Vector3<f32>,
+    gyrometer: na::Vector3<f32>,
+    magnetometer_count: u32,
+    magnetometer: na::Vector3<f32>,
+}
+
+impl Decimator {
+    fn add(&mut self, sample: &ImuSample) {
+        self.count += 1;
+        self.accelerometer += sample.accelerometer;
+        self.gyrometer += sample.gyrometer;
+
+        if let Some(m) = sample.magnetometer {
+            self.magnetometer_count += 1;
+            self.magnetometer += m;
+        }
+    }
+
+    /// Produce a frame of the mean values and restart averaging
+    fn take(&mut self) -> ImuFrame {
+        let mut frame = ImuFrame::default();
+
+        if self.count > 0 {
+            let n = self.count as f32;
+            frame.accelerometer = Some(self.accelerometer / n);
+            frame.gyrometer = Some(self.gyrometer / n);
+        }
+
+        if self.magnetometer_count > 0 {
+            frame.magnetometer = Some(self.magnetometer / self.magnetometer_count as f32);
+        }
+
+        *self = Decimator::default();
+        frame
+    }
+}
+
 #[derive(Default, Clone, Debug, Serialize)]
 pub struct ImuFrame {
-    timestamp: Option<f32>,
+    /// Capture time of the first samples in the frame
+    timestamp: Option<Timestamp>,
 
-    /// Rotation rate in degress per second (max 2000 dps)
+    /// Mean rotation rate in degrees per second (max 250 dps)
     gyrometer: Option<na::Vector3<f32>>,
 
-    /// Accelerameter 3-vector in g (max 2g)
+    /// Mean accelerometer 3-vector in g (max the configured range)
     accelerometer: Option<na::Vector3<f32>>,
 
-    /// Magnetometer 3-vector in Tesla (max 4900 microTesla)
+    /// Mean magnetometer 3-vector in microtesla (max 4900 microtesla)
     magnetometer: Option<na::Vector3<f32>>,
 
-    /// IMU temperature in deg C
-    temperature: Option<f32>,
+    /// Orientation estimate at the end of the frame
+    orientation: Option<Orientation>,
+
+    /// Tilt-compensated compass heading from the mean field and gravity
+    heading: Option<Heading>,
+
+    /// Leveling assistant state
+    level: Option<Level>,
+
+    /// Whether the vehicle is driving, from vibration
+    driving: Option<bool>,
+
+    /// IMU temperature
+    temperature: Option<Celsius>,
+}
+
+impl Signals for ImuFrame {
+    const SPECS: &'static [Spec] = &[
+        Spec::new(
+            "gyrometer.x",
+            Unit::DegreePerSecond,
+            "Mean rotation rate about x",
+        )
+        .range(-250.0, 250.0),
+        Spec::new(
+            "gyrometer.y",
+            Unit::DegreePerSecond,
+            "Mean rotation rate about y",
+        )
+        .range(-250.0, 250.0),
+        Spec::new(
+            "gyrometer.z",
+            Unit::DegreePerSecond,
+            "Mean rotation rate about z",
+        )
+        .range(-250.0, 250.0),
+        Spec::new(
+            "accelerometer.x",
+            Unit::Gravity,
+            "Mean acceleration along x",
+        ),
+        Spec::new(
+            "accelerometer.y",
+            Unit::Gravity,
+            "Mean acceleration along y",
+        ),
+        Spec::new(
+            "accelerometer.z",
+            Unit::Gravity,
+            "Mean acceleration along z",
+        ),
+        Spec::new(
+            "magnetometer.x",
+            Unit::Microtesla,
+            "Mean magnetic field along x",
+        )
+        .range(-4900.0, 4900.0),
+        Spec::new(
+            "magnetometer.y",
+            Unit::Microtesla,
+            "Mean magnetic field along y",
+        )
+        .range(-4900.0, 4900.0),
+        Spec::new(
+            "magnetometer.z",
+            Unit::Microtesla,
+            "Mean magnetic field along z",
+        )
+        .range(-4900.0, 4900.0),
+        Spec::new(
+            "orientation.roll",
+            Unit::Degree,
+            "Rotation about the x axis",
+        )
+        .range(-180.0, 180.0),
+        Spec::new(
+            "orientation.pitch",
+            Unit::Degree,
+            "Rotation about the y axis",
+        )
+        .range(-90.0, 90.0),
+        Spec::new("orientation.yaw", Unit::Degree, "Rotation about the z axis")
+            .range(-180.0, 180.0),
+        Spec::new(
+            "heading.magnetic",
+            Unit::Degree,
+            "Heading from magnetic north",
+        )
+        .range(0.0, 360.0),
+        Spec::new(
+            "heading.true_north",
+            Unit::Degree,
+            "Heading from true north",
+        )
+        .range(0.0, 360.0),
+        Spec::new(
+            "level.pitch",
+            Unit::Degree,
+            "Nose-up pitch from the level reference",
+        ),
+        Spec::new(
+            "level.roll",
+            Unit::Degree,
+            "Left-side-up roll from the level reference",
+        ),
+        Spec::new(
+            "level.level",
+            Unit::None,
+            "Whether pitch and roll are within tolerance",
+        ),
+        Spec::new("driving", Unit::None, "Whether the vehicle is driving"),
+        Spec::new("temperature", Celsius::UNIT, "Die temperature"),
+    ];
+
+    fn signals(&self) -> Vec<(String, Signal)> {
+        let mut signals = Vec::new();
+        let mut add = |name: &str, value: Signal| signals.push((name.to_owned(), value));
+
+        let vectors = [
+            ("gyrometer", &self.gyrometer),
+            ("accelerometer", &self.accelerometer),
+            ("magnetometer", &self.magnetometer),
+        ];
+        for (name, vector) in vectors.iter() {
+            if let Some(v) = vector {
+                add(&format!("{}.x", name), v.x.into());
+                add(&format!("{}.y", name), v.y.into());
+                add(&format!("{}.z", name), v.z.into());
+            }
+        }
+
+        if let Some(orientation) = &self.orientation {
+            add("orientation.roll", orientation.roll.into());
+            add("orientation.pitch", orientation.pitch.into());
+            add("orientation.yaw", orientation.yaw.into());
+        }
+
+        if let Some(heading) = &self.heading {
+            add("heading.magnetic", heading.magnetic.into());
+            add("heading.true_north", heading.true_north.into());
+        }
+
+        if let Some(level) = &self.level {
+            add("level.pitch", level.pitch.into());
+            add("level.roll", level.roll.into());
+            add("level.level", level.level.into());
+        }
+
+        if let Some(driving) = self.driving {
+            add("driving", driving.into());
+        }
+
+        if let Some(temperature) = self.temperature {
+            add("temperature", temperature.into());
+        }
+
+        signals
+    }
 }
 
 impl std::fmt::Display for ImuFrame {
@@ -137,25 +888,25 @@ impl std::fmt::Display for ImuFrame {
         let accel = if let Some(a) = self.accelerometer {
             format!("{:?}", (a.x, a.y, a.z))
         } else {
-            format!("none")
+            "none".to_string()
         };
 
         let gyro = if let Some(g) = self.gyrometer {
             format!("{:?}", (g.x, g.y, g.z))
         } else {
-            format!("none")
+            "none".to_string()
         };
 
         let mag = if let Some(m) = self.magnetometer {
             format!("{:?}", (m.x, m.y, m.z))
         } else {
-            format!("none")
+            "none".to_string()
         };
 
         let temp = if let Some(t) = self.temperature {
-            format!("{}", t)
+            format!("{}", t.0)
         } else {
-            format!("none")
+            "none".to_string()
         };
 
         write!(
@@ -165,3 +916,38 @@ impl std::fmt::Display for ImuFrame {
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Decimator, ImuSample, ACCEL_SENSITIVITY};
+    use chrono::Utc;
+
+    #[test]
+    fn decode_packet() {
+        // +1g on z, full scale negative rotation on x
+        let packet = [
+            0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let sample = ImuSample::from_packet(Utc::now(), &packet, ACCEL_SENSITIVITY);
+
+        assert_eq!(1.0, sample.accelerometer.z);
+        assert!((sample.gyrometer.x + 250.1).abs() < 0.1);
+    }
+
+    #[test]
+    fn decimate() {
+        let mut decimator = Decimator::default();
+        let a = ImuSample::from_packet(
+            Utc::now(),
+            &[0x40, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            ACCEL_SENSITIVITY,
+        );
+        let b = ImuSample::from_packet(Utc::now(), &[0; 12], ACCEL_SENSITIVITY);
+        decimator.add(&a);
+        decimator.add(&b);
+
+        let frame = decimator.take();
+        assert_eq!(Some(0.5), frame.accelerometer.map(|a| a.x));
+        assert!(decimator.take().accelerometer.is_none());
+    }
+}
@@ -0,0 +1,321 @@
+//! Attitude and heading reference system
+//!
+//! Fuses gyrometer, accelerometer and optionally magnetometer samples into an
+//! orientation estimate.  The earth frame is north-west-up and the estimate is
+//! the rotation from the sensor frame into the earth frame.
+
+use crate::hardware::config;
+use nalgebra as na;
+
+pub use habctl_protocol::imu::Orientation;
+
+/// Default Madgwick gradient descent gain
+const DEFAULT_BETA: f32 = 0.1;
+
+/// Default Mahony proportional gain
+const DEFAULT_KP: f32 = 1.0;
+
+/// Default Mahony integral gain
+const DEFAULT_KI: f32 = 0.0;
+
+/// Orientation of a rotation from the sensor frame into the earth frame
+fn orientation(quaternion: na::UnitQuaternion<f32>) -> Orientation {
+    let (roll, pitch, yaw) = quaternion.euler_angles();
+    let q = quaternion.coords;
+
+    Orientation {
+        quaternion: [q.x, q.y, q.z, q.w],
+        roll: roll.to_degrees(),
+        pitch: pitch.to_degrees(),
+        yaw: yaw.to_degrees(),
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Filter {
+    Madgwick { beta: f32 },
+    Mahony { kp: f32, ki: f32 },
+}
+
+pub struct Ahrs {
+    filter: Filter,
+    magnetometer: bool,
+    q: na::Quaternion<f32>,
+
+    /// Mahony integral feedback, in rad/s
+    integral: na::Vector3<f32>,
+
+    initialized: bool,
+}
+
+impl Ahrs {
+    pub fn new(config: &config::Ahrs) -> Self {
+        let filter = match config.filter {
+            config::AhrsFilter::Madgwick => Filter::Madgwick {
+                beta: config.beta.unwrap_or(DEFAULT_BETA),
+            },
+            config::AhrsFilter::Mahony => Filter::Mahony {
+                kp: config.kp.unwrap_or(DEFAULT_KP),
+                ki: config.ki.unwrap_or(DEFAULT_KI),
+            },
+        };
+
+        Self {
+            filter,
+            magnetometer: config.magnetometer.unwrap_or(true),
+            q: na::Quaternion::identity(),
+            integral: na::Vector3::zeros(),
+            initialized: false,
+        }
+    }
+
+    pub fn orientation(&self) -> Orientation {
+        orientation(na::UnitQuaternion::new_normalize(self.q))
+    }
+
+    /// Estimated direction of gravity's reaction, up, in the sensor frame
+    pub fn up(&self) -> na::Vector3<f32> {
+        na::UnitQuaternion::new_normalize(self.q).inverse() * na::Vector3::z()
+    }
+
+    /// Update with a gyrometer sample in degrees/s, accelerometer in g and
+    /// magnetometer in any unit, taken `dt` seconds after the previous sample
+    pub fn update(
+        &mut self,
+        gyrometer: &na::Vector3<f32>,
+        accelerometer: &na::Vector3<f32>,
+        magnetometer: Option<&na::Vector3<f32>>,
+        dt: f32,
+    ) {
+        let magnetometer = magnetometer
+            .filter(|_| self.magnetometer)
+            .and_then(|m| m.try_normalize(0.0));
+
+        let accelerometer = match accelerometer.try_normalize(0.0) {
+            Some(a) => a,
+            None => {
+                // free fall, nothing to correct against
+                self.integrate(&gyrometer.map(f32::to_radians), dt);
+                return;
+            }
+        };
+
+        if !self.initialized {
+            self.initialize(&accelerometer, magnetometer.as_ref());
+            return;
+        }
+
+        let gyrometer = gyrometer.map(f32::to_radians);
+
+        match self.filter {
+            Filter::Madgwick { beta } => {
+                self.madgwick(&gyrometer, &accelerometer, magnetometer.as_ref(), beta, dt)
+            }
+            Filter::Mahony { kp, ki } => self.mahony(
+                &gyrometer,
+                &accelerometer,
+                magnetometer.as_ref(),
+                kp,
+                ki,
+                dt,
+            ),
+        }
+    }
+
+    /// Start from the attitude given by gravity, and heading if available
+    fn initialize(&mut self, a: &na::Vector3<f32>, m: Option<&na::Vector3<f32>>) {
+        let roll = a.y.atan2(a.z);
+        let pitch = (-a.x).atan2((a.y * a.y + a.z * a.z).sqrt());
+
+        let yaw = m.map_or(0.0, |m| {
+            let level = na::UnitQuaternion::from_euler_angles(roll, pitch, 0.0) * m;
+            (-level.y).atan2(level.x)
+        });
+
+        self.q = *na::UnitQuaternion::from_euler_angles(roll, pitch, yaw).quaternion();
+        self.integral = na::Vector3::zeros();
+        self.initialized = true;
+    }
+
+    /// Integrate rotation rate `w` in rad/s, less a correction step
+    fn step(&mut self, w: &na::Vector3<f32>, correction: na::Quaternion<f32>, dt: f32) {
+        let rate = self.q * na::Quaternion::from_imag(*w) * 0.5 - correction;
+        self.q = (self.q + rate * dt).normalize();
+    }
+
+    fn integrate(&mut self, w: &na::Vector3<f32>, dt: f32) {
+        self.step(w, na::Quaternion::new(0.0, 0.0, 0.0, 0.0), dt);
+    }
+
+    /// Earth magnetic field in the earth frame as (horizontal, vertical),
+    /// estimated from the current orientation
+    fn reference_field(&self, m: &na::Vector3<f32>) -> (f32, f32) {
+        let h = self.q * na::Quaternion::from_imag(*m) * self.q.conjugate();
+        ((h.i * h.i + h.j * h.j).sqrt(), h.k)
+    }
+
+    fn madgwick(
+        &mut self,
+        w: &na::Vector3<f32>,
+        a: &na::Vector3<f32>,
+        m: Option<&na::Vector3<f32>>,
+        beta: f32,
+        dt: f32,
+    ) {
+        let (q0, q1, q2, q3) = (self.q.w, self.q.i, self.q.j, self.q.k);
+
+        // gravity objective function and its jacobian
+        let f_g = na::Vector3::new(
+            2.0 * (q1 * q3 - q0 * q2) - a.x,
+            2.0 * (q0 * q1 + q2 * q3) - a.y,
+            2.0 * (0.5 - q1 * q1 - q2 * q2) - a.z,
+        );
+        #[rustfmt::skip]
+        let j_g = na::Matrix3x4::new(
+            -2.0 * q2, 2.0 * q3, -2.0 * q0, 2.0 * q1,
+            2.0 * q1, 2.0 * q0, 2.0 * q3, 2.0 * q2,
+            0.0, -4.0 * q1, -4.0 * q2, 0.0,
+        );
+        let mut gradient = j_g.transpose() * f_g;
+
+        if let Some(m) = m {
+            let (bx, bz) = self.reference_field(m);
+
+            let f_b = na::Vector3::new(
+                2.0 * bx * (0.5 - q2 * q2 - q3 * q3) + 2.0 * bz * (q1 * q3 - q0 * q2) - m.x,
+                2.0 * bx * (q1 * q2 - q0 * q3) + 2.0 * bz * (q0 * q1 + q2 * q3) - m.y,
+                2.0 * bx * (q0 * q2 + q1 * q3) + 2.0 * bz * (0.5 - q1 * q1 - q2 * q2) - m.z,
+            );
+            #[rustfmt::skip]
+            let j_b = na::Matrix3x4::new(
+                -2.0 * bz * q2,
+                2.0 * bz * q3,
+                -4.0 * bx * q2 - 2.0 * bz * q0,
+                -4.0 * bx * q3 + 2.0 * bz * q1,
+
+                -2.0 * bx * q3 + 2.0 * bz * q1,
+                2.0 * bx * q2 + 2.0 * bz * q0,
+                2.0 * bx * q1 + 2.0 * bz * q3,
+                -2.0 * bx * q0 + 2.0 * bz * q2,
+
+                2.0 * bx * q2,
+                2.0 * bx * q3 - 4.0 * bz * q1,
+                2.0 * bx * q0 - 4.0 * bz * q2,
+                2.0 * bx * q1,
+            );
+            gradient += j_b.transpose() * f_b;
+        }
+
+        let correction = match gradient.try_normalize(0.0) {
+            Some(g) => na::Quaternion::new(g[0], g[1], g[2], g[3]) * beta,
+            None => na::Quaternion::new(0.0, 0.0, 0.0, 0.0),
+        };
+
+        self.step(w, correction, dt);
+    }
+
+    fn mahony(
+        &mut self,
+        w: &na::Vector3<f32>,
+        a: &na::Vector3<f32>,
+        m: Option<&na::Vector3<f32>>,
+        kp: f32,
+        ki: f32,
+        dt: f32,
+    ) {
+        let (q0, q1, q2, q3) = (self.q.w, self.q.i, self.q.j, self.q.k);
+
+        // estimated direction of gravity in the sensor frame
+        let v = na::Vector3::new(
+            2.0 * (q1 * q3 - q0 * q2),
+            2.0 * (q0 * q1 + q2 * q3),
+            q0 * q0 - q1 * q1 - q2 * q2 + q3 * q3,
+        );
+        let mut error = a.cross(&v);
+
+        if let Some(m) = m {
+            let (bx, bz) = self.reference_field(m);
+
+            // estimated direction of the magnetic field in the sensor frame
+            let field = na::Vector3::new(
+                2.0 * bx * (0.5 - q2 * q2 - q3 * q3) + 2.0 * bz * (q1 * q3 - q0 * q2),
+                2.0 * bx * (q1 * q2 - q0 * q3) + 2.0 * bz * (q0 * q1 + q2 * q3),
+                2.0 * bx * (q0 * q2 + q1 * q3) + 2.0 * bz * (0.5 - q1 * q1 - q2 * q2),
+            );
+            error += m.cross(&field);
+        }
+
+        if ki > 0.0 {
+            self.integral += error * ki * dt;
+        }
+
+        let corrected = w + error * kp + self.integral;
+        self.integrate(&corrected, dt);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Ahrs;
+    use crate::hardware::config;
+    use nalgebra as na;
+
+    fn config(filter: config::AhrsFilter) -> config::Ahrs {
+        config::Ahrs {
+            filter,
+            beta: None,
+            kp: None,
+            ki: None,
+            magnetometer: None,
+        }
+    }
+
+    #[test]
+    fn initializes_from_gravity() {
+        let mut ahrs = Ahrs::new(&config(config::AhrsFilter::Madgwick));
+        let tilt = 10f32.to_radians();
+
+        // rolled 10 degrees: gravity reaction leans into +y
+        let a = na::Vector3::new(0.0, tilt.sin(), tilt.cos());
+        ahrs.update(&na::Vector3::zeros(), &a, None, 0.01);
+
+        let o = ahrs.orientation();
+        assert!((o.roll - 10.0).abs() < 0.01);
+        assert!(o.pitch.abs() < 0.01);
+    }
+
+    #[test]
+    fn converges_on_gravity() {
+        for filter in [config::AhrsFilter::Madgwick, config::AhrsFilter::Mahony] {
+            let mut ahrs = Ahrs::new(&config(filter));
+            let level = na::Vector3::new(0.0, 0.0, 1.0);
+            ahrs.update(&na::Vector3::zeros(), &level, None, 0.01);
+
+            // pitched 20 degrees about y, nose down, while stationary
+            let tilt = 20f32.to_radians();
+            let a = na::Vector3::new(-tilt.sin(), 0.0, tilt.cos());
+            for _ in 0..2000 {
+                ahrs.update(&na::Vector3::zeros(), &a, None, 0.01);
+            }
+
+            let o = ahrs.orientation();
+            assert!((o.pitch - 20.0).abs() < 0.5, "{:?}", o);
+            assert!(o.roll.abs() < 0.5, "{:?}", o);
+        }
+    }
+
+    #[test]
+    fn integrates_rotation() {
+        let mut ahrs = Ahrs::new(&config(config::AhrsFilter::Madgwick));
+        let level = na::Vector3::new(0.0, 0.0, 1.0);
+        ahrs.update(&na::Vector3::zeros(), &level, None, 0.01);
+
+        // 90 degrees about z over one second, which gravity cannot observe
+        let w = na::Vector3::new(0.0, 0.0, 90.0);
+        for _ in 0..100 {
+            ahrs.update(&w, &level, None, 0.01);
+        }
+
+        assert!((ahrs.orientation().yaw - 90.0).abs() < 0.5);
+    }
+}
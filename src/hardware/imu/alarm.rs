@@ -0,0 +1,195 @@
+//! Motion and tamper alarm
+//!
+//! While armed, the attitude when the alarm is armed is taken as a baseline.
+//! Samples that deviate from it by more than the acceleration threshold raise
+//! a motion event, and frames whose mean tilts away from it by more than the
+//! tilt threshold raise a tilt event and become the new baseline.
+
+use super::ImuEvent;
+use crate::hardware::config;
+use crate::state;
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+/// Default acceleration threshold, in g
+const DEFAULT_ACCELERATION: f32 = 0.05;
+
+/// Default tilt threshold, in degrees
+const DEFAULT_TILT: f32 = 1.0;
+
+/// Default time between motion events, in seconds
+const DEFAULT_HOLDOFF: i64 = 10;
+
+/// Persisted alarm state
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Armed {
+    pub armed: bool,
+}
+
+pub struct Alarm {
+    /// Persistent state name of the armed state
+    state: String,
+
+    armed: bool,
+    acceleration: f32,
+    tilt: f32,
+    holdoff: Duration,
+
+    /// Mean acceleration when armed, in g
+    baseline: Option<na::Vector3<f32>>,
+
+    last_motion: Option<DateTime<Utc>>,
+}
+
+impl Alarm {
+    pub fn new(name: &str, config: &config::Alarm) -> Self {
+        let state = format!("imu-{}-alarm", name);
+
+        let armed: Option<Armed> = state::load(&state).unwrap_or_else(|e| {
+            log::error!("IMU {}: alarm: {}", name, e);
+            None
+        });
+
+        Self {
+            state,
+            armed: armed.unwrap_or_default().armed,
+            acceleration: config.acceleration.unwrap_or(DEFAULT_ACCELERATION),
+            tilt: config.tilt.unwrap_or(DEFAULT_TILT),
+            holdoff: Duration::seconds(config.holdoff.unwrap_or(DEFAULT_HOLDOFF)),
+            baseline: None,
+            last_motion: None,
+        }
+    }
+
+    pub fn armed(&self) -> Armed {
+        Armed { armed: self.armed }
+    }
+
+    /// Threshold for the wake-on-motion interrupt while armed, in g
+    pub fn wake_threshold(&self) -> Option<f32> {
+        Some(self.acceleration).filter(|_| self.armed)
+    }
+
+    /// Arm or disarm, taking a new baseline when armed
+    pub fn set_armed(&mut self, armed: bool) -> Result<Armed> {
+        state::save(&self.state, &Armed { armed })?;
+
+        self.armed = armed;
+        self.baseline = None;
+        self.last_motion = None;
+
+        Ok(self.armed())
+    }
+
+    /// Check a full-rate sample for motion
+    pub fn sample(&mut self, timestamp: DateTime<Utc>, a: &na::Vector3<f32>) -> Option<ImuEvent> {
+        let baseline = self.baseline.filter(|_| self.armed)?;
+        let magnitude = (a - baseline).norm();
+
+        let quiet = !matches!(self.last_motion, Some(last) if timestamp - last < self.holdoff);
+
+        if magnitude > self.acceleration && quiet {
+            self.last_motion = Some(timestamp);
+            Some(ImuEvent::Motion {
+                timestamp,
+                magnitude,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Check the mean acceleration of a frame for tilt
+    pub fn frame(&mut self, timestamp: DateTime<Utc>, a: &na::Vector3<f32>) -> Option<ImuEvent> {
+        if !self.armed {
+            return None;
+        }
+
+        let baseline = match self.baseline {
+            Some(baseline) => baseline,
+            None => {
+                self.baseline = Some(*a);
+                return None;
+            }
+        };
+
+        let degrees = baseline.angle(a).to_degrees();
+
+        if degrees > self.tilt {
+            self.baseline = Some(*a);
+            Some(ImuEvent::Tilt { timestamp, degrees })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Alarm;
+    use crate::hardware::imu::ImuEvent;
+    use chrono::{Duration, Utc};
+    use nalgebra as na;
+
+    fn armed() -> Alarm {
+        Alarm {
+            state: String::new(),
+            armed: true,
+            acceleration: 0.05,
+            tilt: 1.0,
+            holdoff: Duration::seconds(10),
+            baseline: None,
+            last_motion: None,
+        }
+    }
+
+    #[test]
+    fn motion() {
+        let mut alarm = armed();
+        let now = Utc::now();
+        let still = na::Vector3::z();
+        let bump = na::Vector3::new(0.1, 0.0, 1.0);
+
+        // nothing until a baseline is established
+        assert!(alarm.sample(now, &bump).is_none());
+        assert!(alarm.frame(now, &still).is_none());
+
+        assert!(alarm.sample(now, &still).is_none());
+        match alarm.sample(now, &bump) {
+            Some(ImuEvent::Motion { magnitude, .. }) => assert!((magnitude - 0.1).abs() < 0.001),
+            e => panic!("{:?}", e),
+        }
+
+        // held off, then raised again
+        assert!(alarm.sample(now + Duration::seconds(1), &bump).is_none());
+        assert!(alarm.sample(now + Duration::seconds(11), &bump).is_some());
+    }
+
+    #[test]
+    fn tilt() {
+        let mut alarm = armed();
+        let now = Utc::now();
+        let tilted = na::Vector3::new(2f32.to_radians().sin(), 0.0, 2f32.to_radians().cos());
+
+        alarm.frame(now, &na::Vector3::z());
+        match alarm.frame(now, &tilted) {
+            Some(ImuEvent::Tilt { degrees, .. }) => assert!((degrees - 2.0).abs() < 0.01),
+            e => panic!("{:?}", e),
+        }
+
+        // settled at the new attitude
+        assert!(alarm.frame(now, &tilted).is_none());
+    }
+
+    #[test]
+    fn disarmed() {
+        let mut alarm = armed();
+        alarm.armed = false;
+
+        let now = Utc::now();
+        alarm.frame(now, &na::Vector3::z());
+        assert!(alarm.sample(now, &na::Vector3::x()).is_none());
+    }
+}
@@ -0,0 +1,483 @@
+//! Guided IMU calibration
+//!
+//! Each routine collects raw samples for a while and then updates one part of
+//! the calibration, which is saved and applied to every subsequent sample:
+//!
+//! * gyrometer: bias, measured while the IMU is still
+//! * accelerometer: offset and scale, from the IMU resting still on each of its
+//!   six faces, one capture per face
+//! * magnetometer: hard iron offset and soft iron scale, from the extent of the
+//!   field seen while the IMU is rotated through every orientation
+
+use super::ImuSample;
+use crate::hardware::config;
+use crate::state;
+use anyhow::{Error, Result};
+use chrono::{DateTime, Utc};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// Default time to collect a still capture, in seconds
+const DEFAULT_DURATION: f32 = 5.0;
+
+/// Default time to collect a magnetometer sweep, in seconds
+const DEFAULT_SWEEP_DURATION: f32 = 60.0;
+
+/// Largest accelerometer deviation while still, in g
+const STILL_ACCELEROMETER: f32 = 0.05;
+
+/// Largest gyrometer deviation while still, in degrees/s
+const STILL_GYROMETER: f32 = 2.0;
+
+/// Smallest component along the axis facing up for an accelerometer capture, in g
+const AXIS_ALIGNED: f32 = 0.8;
+
+/// Smallest field radius along each axis for a magnetometer sweep, in microtesla
+const MINIMUM_SWEEP: f32 = 5.0;
+
+/// Corrections applied to raw samples
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Calibration {
+    /// Subtracted from the gyrometer, in degrees/s
+    pub gyrometer_bias: na::Vector3<f32>,
+
+    /// Subtracted from the accelerometer, in g
+    pub accelerometer_offset: na::Vector3<f32>,
+
+    /// Multiplies the accelerometer after removing the offset
+    pub accelerometer_scale: na::Vector3<f32>,
+
+    /// Hard iron offset subtracted from the magnetometer, in microtesla
+    pub magnetometer_offset: na::Vector3<f32>,
+
+    /// Soft iron scale multiplying the magnetometer after removing the offset
+    pub magnetometer_scale: na::Vector3<f32>,
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self {
+            gyrometer_bias: na::Vector3::zeros(),
+            accelerometer_offset: na::Vector3::zeros(),
+            accelerometer_scale: na::Vector3::repeat(1.0),
+            magnetometer_offset: na::Vector3::zeros(),
+            magnetometer_scale: na::Vector3::repeat(1.0),
+        }
+    }
+}
+
+impl Calibration {
+    pub fn apply(&self, sample: &mut ImuSample) {
+        sample.gyrometer -= self.gyrometer_bias;
+        sample.accelerometer = (sample.accelerometer - self.accelerometer_offset)
+            .component_mul(&self.accelerometer_scale);
+        sample.magnetometer = sample
+            .magnetometer
+            .map(|m| (m - self.magnetometer_offset).component_mul(&self.magnetometer_scale));
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Routine {
+    Gyrometer,
+    Accelerometer,
+    Magnetometer,
+}
+
+impl FromStr for Routine {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gyrometer" => Ok(Routine::Gyrometer),
+            "accelerometer" => Ok(Routine::Accelerometer),
+            "magnetometer" => Ok(Routine::Magnetometer),
+            _ => Err(Error::msg(format!("unknown calibration routine {}", s))),
+        }
+    }
+}
+
+/// Calibration and the progress of any routine
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub calibration: Calibration,
+
+    /// Routine in progress
+    pub routine: Option<Routine>,
+
+    /// Fraction of the routine in progress complete
+    pub progress: f32,
+
+    /// Faces captured so far for accelerometer calibration, e.g. "-z"
+    pub faces: Vec<&'static str>,
+
+    /// Outcome of the most recent routine
+    pub result: Option<String>,
+}
+
+/// Faces in the order of `Calibrator::faces`
+const FACES: [&str; 6] = ["+x", "-x", "+y", "-y", "+z", "-z"];
+
+/// Running statistics of samples collected by a routine
+struct Collection {
+    routine: Routine,
+    start: Option<DateTime<Utc>>,
+    duration: f32,
+    progress: f32,
+    count: u32,
+    gyrometer: Moments,
+    accelerometer: Moments,
+    magnetometer_min: na::Vector3<f32>,
+    magnetometer_max: na::Vector3<f32>,
+}
+
+#[derive(Default)]
+struct Moments {
+    sum: na::Vector3<f32>,
+    sum_squares: na::Vector3<f32>,
+}
+
+impl Moments {
+    fn add(&mut self, value: &na::Vector3<f32>) {
+        self.sum += value;
+        self.sum_squares += value.component_mul(value);
+    }
+
+    fn mean(&self, count: u32) -> na::Vector3<f32> {
+        self.sum / count as f32
+    }
+
+    /// Largest standard deviation of any axis
+    fn deviation(&self, count: u32) -> f32 {
+        let mean = self.mean(count);
+        (self.sum_squares / count as f32 - mean.component_mul(&mean))
+            .map(|v| v.max(0.0).sqrt())
+            .max()
+    }
+}
+
+pub struct Calibrator {
+    /// Persistent state name of the calibration
+    state: String,
+
+    calibration: Calibration,
+    duration: f32,
+    sweep_duration: f32,
+    collection: Option<Collection>,
+
+    /// Mean accelerometer reading with each face up
+    faces: [Option<na::Vector3<f32>>; 6],
+
+    result: Option<String>,
+
+    /// Whether the calibration changed since it was last taken to save
+    unsaved: bool,
+}
+
+impl Calibrator {
+    pub fn new(name: &str, config: &config::Calibration) -> Self {
+        let state = format!("imu-{}-calibration", name);
+
+        let calibration = state::load(&state).unwrap_or_else(|e| {
+            log::error!("IMU {}: calibration: {}", name, e);
+            None
+        });
+
+        Self {
+            state,
+            calibration: calibration.unwrap_or_default(),
+            duration: config.duration.unwrap_or(DEFAULT_DURATION),
+            sweep_duration: config.sweep_duration.unwrap_or(DEFAULT_SWEEP_DURATION),
+            collection: None,
+            faces: Default::default(),
+            result: None,
+            unsaved: false,
+        }
+    }
+
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            calibration: self.calibration.clone(),
+            routine: self.collection.as_ref().map(|c| c.routine),
+            progress: self.collection.as_ref().map_or(0.0, |c| c.progress),
+            faces: FACES
+                .iter()
+                .zip(self.faces.iter())
+                .filter(|(_, face)| face.is_some())
+                .map(|(name, _)| *name)
+                .collect(),
+            result: self.result.clone(),
+        }
+    }
+
+    /// Start a routine, replacing any in progress
+    pub fn start(&mut self, routine: Routine) {
+        let duration = match routine {
+            Routine::Magnetometer => self.sweep_duration,
+            _ => self.duration,
+        };
+
+        self.result = None;
+        self.collection = Some(Collection {
+            routine,
+            start: None,
+            duration,
+            progress: 0.0,
+            count: 0,
+            gyrometer: Moments::default(),
+            accelerometer: Moments::default(),
+            magnetometer_min: na::Vector3::repeat(f32::MAX),
+            magnetometer_max: na::Vector3::repeat(f32::MIN),
+        });
+    }
+
+    /// Abandon any routine in progress and accelerometer faces captured so far
+    pub fn cancel(&mut self) {
+        self.collection = None;
+        self.faces = Default::default();
+        self.result = Some("cancelled".to_owned());
+    }
+
+    /// Collect a raw, uncalibrated sample for the routine in progress
+    pub fn add(&mut self, sample: &ImuSample) {
+        let collection = match &mut self.collection {
+            Some(collection) => collection,
+            None => return,
+        };
+
+        let start = *collection.start.get_or_insert(sample.timestamp);
+        let elapsed = (sample.timestamp - start).num_milliseconds() as f32 / 1000.0;
+
+        collection.count += 1;
+        collection.gyrometer.add(&sample.gyrometer);
+        collection.accelerometer.add(&sample.accelerometer);
+        if let Some(m) = sample.magnetometer {
+            collection.magnetometer_min = collection.magnetometer_min.inf(&m);
+            collection.magnetometer_max = collection.magnetometer_max.sup(&m);
+        }
+        collection.progress = (elapsed / collection.duration).min(1.0);
+
+        if elapsed >= collection.duration {
+            if let Some(collection) = self.collection.take() {
+                let result = self.finish(collection);
+                log::info!("{}: {:?}", self.state, result);
+
+                self.result = Some(match result {
+                    Ok(message) => message,
+                    Err(e) => format!("failed: {}", e),
+                });
+            }
+        }
+    }
+
+    fn finish(&mut self, collection: Collection) -> Result<String> {
+        let count = collection.count;
+
+        let message = match collection.routine {
+            Routine::Gyrometer => {
+                is_still(&collection)?;
+                self.calibration.gyrometer_bias = collection.gyrometer.mean(count);
+                "gyrometer calibrated".to_owned()
+            }
+            Routine::Accelerometer => {
+                is_still(&collection)?;
+                let face = self.add_face(collection.accelerometer.mean(count))?;
+
+                match self.accelerometer() {
+                    Some((offset, scale)) => {
+                        self.calibration.accelerometer_offset = offset;
+                        self.calibration.accelerometer_scale = scale;
+                        self.faces = Default::default();
+                        "accelerometer calibrated".to_owned()
+                    }
+                    None => return Ok(format!("captured {}", face)),
+                }
+            }
+            Routine::Magnetometer => {
+                let (min, max) = (collection.magnetometer_min, collection.magnetometer_max);
+                let radius = (max - min) / 2.0;
+
+                if radius.min() < MINIMUM_SWEEP {
+                    return Err(Error::msg("not rotated through enough orientations"));
+                }
+
+                self.calibration.magnetometer_offset = (max + min) / 2.0;
+                self.calibration.magnetometer_scale = radius.map(|r| radius.mean() / r);
+                "magnetometer calibrated".to_owned()
+            }
+        };
+
+        self.unsaved = true;
+        Ok(message)
+    }
+
+    /// Copy of the calibration to save, if it changed since last taken
+    pub fn unsaved(&mut self) -> Option<state::Snapshot<Calibration>> {
+        std::mem::take(&mut self.unsaved)
+            .then(|| state::Snapshot::new(&self.state, self.calibration.clone()))
+    }
+
+    /// Record the mean reading with one face up, returning the face
+    fn add_face(&mut self, mean: na::Vector3<f32>) -> Result<&'static str> {
+        let axis = mean.iamax();
+
+        if mean[axis].abs() < AXIS_ALIGNED {
+            return Err(Error::msg("no axis is pointing up"));
+        }
+
+        let index = axis * 2 + if mean[axis] < 0.0 { 1 } else { 0 };
+        self.faces[index] = Some(mean);
+
+        Ok(FACES[index])
+    }
+
+    /// Offset and scale once every face has been captured
+    fn accelerometer(&self) -> Option<(na::Vector3<f32>, na::Vector3<f32>)> {
+        let mut offset = na::Vector3::zeros();
+        let mut scale = na::Vector3::zeros();
+
+        for axis in 0..3 {
+            let up = self.faces[axis * 2]?[axis];
+            let down = self.faces[axis * 2 + 1]?[axis];
+
+            offset[axis] = (up + down) / 2.0;
+            scale[axis] = 2.0 / (up - down);
+        }
+
+        Some((offset, scale))
+    }
+}
+
+fn is_still(collection: &Collection) -> Result<()> {
+    let count = collection.count;
+
+    if collection.accelerometer.deviation(count) > STILL_ACCELEROMETER
+        || collection.gyrometer.deviation(count) > STILL_GYROMETER
+    {
+        Err(Error::msg("moved during capture"))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Calibration, Calibrator, Routine};
+    use crate::hardware::imu::ImuSample;
+    use chrono::{Duration, Utc};
+    use nalgebra as na;
+
+    fn calibrator() -> Calibrator {
+        Calibrator {
+            state: String::new(),
+            calibration: Calibration::default(),
+            duration: 1.0,
+            sweep_duration: 1.0,
+            collection: None,
+            faces: Default::default(),
+            result: None,
+            unsaved: false,
+        }
+    }
+
+    fn sample(gyrometer: na::Vector3<f32>, accelerometer: na::Vector3<f32>) -> ImuSample {
+        ImuSample {
+            timestamp: Utc::now(),
+            gyrometer,
+            accelerometer,
+            magnetometer: None,
+        }
+    }
+
+    #[test]
+    fn accelerometer_faces() {
+        let mut calibrator = calibrator();
+
+        // offset of 0.1g and scale error of 2% on every axis
+        let faces = [
+            na::Vector3::x(),
+            -na::Vector3::x(),
+            na::Vector3::y(),
+            -na::Vector3::y(),
+            na::Vector3::z(),
+            -na::Vector3::z(),
+        ];
+        for face in faces.iter() {
+            calibrator
+                .add_face(face * 1.02 + na::Vector3::repeat(0.1))
+                .unwrap();
+        }
+
+        let (offset, scale) = calibrator.accelerometer().unwrap();
+        calibrator.calibration.accelerometer_offset = offset;
+        calibrator.calibration.accelerometer_scale = scale;
+
+        let mut s = sample(na::Vector3::zeros(), na::Vector3::new(0.1, 0.1, 1.12));
+        calibrator.calibration.apply(&mut s);
+        assert!((s.accelerometer - na::Vector3::z()).norm() < 0.001);
+    }
+
+    #[test]
+    fn rejects_tilted_face() {
+        let mut calibrator = calibrator();
+        let tilted = na::Vector3::new(0.7, 0.0, 0.7);
+
+        assert!(calibrator.add_face(tilted).is_err());
+        assert!(calibrator.accelerometer().is_none());
+    }
+
+    #[test]
+    fn moments() {
+        let mut moments = super::Moments::default();
+        moments.add(&na::Vector3::repeat(1.0));
+        moments.add(&na::Vector3::repeat(3.0));
+
+        assert_eq!(na::Vector3::repeat(2.0), moments.mean(2));
+        assert!((moments.deviation(2) - 1.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn gyrometer_in_progress() {
+        let mut calibrator = calibrator();
+        calibrator.start(Routine::Gyrometer);
+
+        let bias = na::Vector3::new(0.5, -0.25, 1.0);
+        let mut s = sample(bias, na::Vector3::z());
+        calibrator.add(&s);
+        s.timestamp = s.timestamp + Duration::milliseconds(500);
+        calibrator.add(&s);
+
+        let status = calibrator.status();
+        assert_eq!(Some(Routine::Gyrometer), status.routine);
+        assert!((status.progress - 0.5).abs() < 0.001);
+        assert_eq!(
+            bias,
+            calibrator.collection.as_ref().unwrap().gyrometer.mean(2)
+        );
+    }
+
+    #[test]
+    fn saved_once_finished() {
+        let mut calibrator = calibrator();
+        calibrator.start(Routine::Gyrometer);
+
+        let mut s = sample(na::Vector3::new(0.5, -0.25, 1.0), na::Vector3::z());
+        calibrator.add(&s);
+        assert!(calibrator.unsaved().is_none());
+
+        s.timestamp = s.timestamp + Duration::seconds(1);
+        calibrator.add(&s);
+        assert_eq!(
+            Some("gyrometer calibrated".into()),
+            calibrator.status().result
+        );
+        assert!(calibrator.unsaved().is_some());
+        assert!(calibrator.unsaved().is_none());
+    }
+}
@@ -0,0 +1,122 @@
+//! Tilt-compensated compass
+//!
+//! Gravity and the magnetic field, both measured in the sensor frame, define
+//! the north-west-up earth frame directly, so the heading needs neither a
+//! level IMU nor a converged orientation filter.
+
+use crate::hardware::config;
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Heading {
+    /// Vehicle heading, in degrees clockwise from magnetic north
+    pub magnetic: f32,
+
+    /// Vehicle heading, in degrees clockwise from true north
+    pub true_north: f32,
+
+    /// Rotation from the vehicle frame into the true north-west-up frame
+    pub attitude: na::UnitQuaternion<f32>,
+}
+
+pub struct Compass {
+    /// Rotation from the sensor frame into the vehicle frame
+    mounting: na::UnitQuaternion<f32>,
+
+    /// Magnetic declination, in degrees east of true north
+    declination: f32,
+}
+
+impl Compass {
+    pub fn new(config: &config::Compass, mounting: na::UnitQuaternion<f32>) -> Self {
+        Self {
+            mounting,
+            declination: config.declination.unwrap_or(0.0),
+        }
+    }
+
+    /// Heading from mean accelerometer and magnetometer readings of a vehicle
+    /// that is not accelerating
+    pub fn heading(
+        &self,
+        accelerometer: &na::Vector3<f32>,
+        magnetometer: &na::Vector3<f32>,
+    ) -> Option<Heading> {
+        // earth axes in the sensor frame
+        let up = accelerometer.try_normalize(0.0)?;
+        let west = up.cross(magnetometer).try_normalize(0.0)?;
+        let north = west.cross(&up);
+
+        let sensor = na::Rotation3::from_matrix_unchecked(na::Matrix3::from_rows(&[
+            north.transpose(),
+            west.transpose(),
+            up.transpose(),
+        ]));
+        let magnetic = na::UnitQuaternion::from_rotation_matrix(&sensor) * self.mounting.inverse();
+
+        // east declination puts magnetic north clockwise of true north
+        let declination = na::UnitQuaternion::from_axis_angle(
+            &na::Vector3::z_axis(),
+            -self.declination.to_radians(),
+        );
+
+        Some(Heading {
+            magnetic: heading(&magnetic),
+            true_north: heading(&(declination * magnetic)),
+            attitude: declination * magnetic,
+        })
+    }
+}
+
+/// Degrees clockwise from north of the vehicle's forward axis
+fn heading(attitude: &na::UnitQuaternion<f32>) -> f32 {
+    let forward = attitude * na::Vector3::x();
+    (-forward.y).atan2(forward.x).to_degrees().rem_euclid(360.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Compass;
+    use nalgebra as na;
+
+    /// Field with a steep downward inclination, as at mid northern latitudes
+    fn field() -> na::Vector3<f32> {
+        na::Vector3::new(20.0, 0.0, -45.0)
+    }
+
+    #[test]
+    fn level() {
+        let compass = Compass {
+            mounting: na::UnitQuaternion::identity(),
+            declination: 0.0,
+        };
+
+        // facing east, north is to the left
+        let east = na::UnitQuaternion::from_axis_angle(&na::Vector3::z_axis(), 90f32.to_radians());
+        let heading = compass
+            .heading(&na::Vector3::z(), &(east * field()))
+            .unwrap();
+
+        assert!((heading.magnetic - 90.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn tilt_compensated() {
+        let compass = Compass {
+            mounting: na::UnitQuaternion::identity(),
+            declination: 15.0,
+        };
+
+        // facing north, nose up 10 degrees and rolled 5 degrees
+        let tilt =
+            na::UnitQuaternion::from_euler_angles(5f32.to_radians(), -10f32.to_radians(), 0.0);
+        let sensor = tilt.inverse();
+        let heading = compass
+            .heading(&(sensor * na::Vector3::z()), &(sensor * field()))
+            .unwrap();
+
+        assert!(heading.magnetic < 0.01 || heading.magnetic > 359.99);
+        assert!((heading.true_north - 15.0).abs() < 0.01);
+    }
+}
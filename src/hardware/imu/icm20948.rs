@@ -0,0 +1,479 @@
+//! ICM-20948 register access, over any embedded-hal I2C bus
+
+use crate::hardware::config::InterruptSource;
+use anyhow::{Error, Result};
+use embedded_hal::i2c::I2c;
+use nalgebra as na;
+
+/// Size of the FIFO, in bytes
+pub const FIFO_SIZE: usize = 512;
+
+/// FIFO packet of accelerometer and gyrometer, each three 16-bit axes
+pub const PACKET_SIZE: usize = 12;
+
+/// Internal sample clock of the accelerometer and gyrometer, in Hz
+pub const BASE_SAMPLE_RATE: f32 = 1125.0;
+
+/// Slowest rate the sample rate divider allows, in Hz
+pub const MIN_SAMPLE_RATE: f32 = BASE_SAMPLE_RATE / 256.0;
+
+/// LSB per g at +/-2g full scale, halving with each doubling of the range
+pub const ACCEL_SENSITIVITY: f32 = 16384.0;
+
+/// LSB per degree/s at +/-250 dps full scale
+pub const GYRO_SENSITIVITY: f32 = 131.0;
+
+/// LSB per degree C, offset from 21 degrees C
+pub const TEMP_SENSITIVITY: f32 = 333.87;
+
+/// Microtesla per LSB of the AK09916 magnetometer
+pub const MAG_SCALE: f32 = 0.15;
+
+/// ICM-20948 I2C address with AD0 high
+pub const I2C_ADDRESS: u8 = 0x69;
+
+/// Expected value of `WHO_AM_I`
+pub const DEVICE_ID: u8 = 0xea;
+
+/// AK09916 magnetometer address on the auxiliary I2C bus
+pub const MAG_ADDRESS: u8 = 0x0c;
+
+/// Expected value of the AK09916 `WIA2`
+pub const MAG_DEVICE_ID: u8 = 0x09;
+
+/// Register addresses as (bank, address)
+pub mod register {
+    pub type Register = (u8, u8);
+
+    pub const WHO_AM_I: Register = (0, 0x00);
+    pub const USER_CTRL: Register = (0, 0x03);
+    pub const PWR_MGMT_1: Register = (0, 0x06);
+    pub const PWR_MGMT_2: Register = (0, 0x07);
+    pub const INT_PIN_CFG: Register = (0, 0x0f);
+    pub const INT_ENABLE: Register = (0, 0x10);
+    pub const INT_ENABLE_1: Register = (0, 0x11);
+    pub const INT_ENABLE_2: Register = (0, 0x12);
+    pub const INT_ENABLE_3: Register = (0, 0x13);
+    pub const INT_STATUS_2: Register = (0, 0x1b);
+    pub const TEMP_OUT_H: Register = (0, 0x39);
+    pub const EXT_SLV_SENS_DATA_00: Register = (0, 0x3b);
+    pub const FIFO_EN_2: Register = (0, 0x67);
+    pub const FIFO_RST: Register = (0, 0x68);
+    pub const FIFO_MODE: Register = (0, 0x69);
+    pub const FIFO_COUNTH: Register = (0, 0x70);
+    pub const FIFO_R_W: Register = (0, 0x72);
+
+    pub const GYRO_SMPLRT_DIV: Register = (2, 0x00);
+    pub const GYRO_CONFIG_1: Register = (2, 0x01);
+    pub const ODR_ALIGN_EN: Register = (2, 0x09);
+    pub const ACCEL_SMPLRT_DIV_1: Register = (2, 0x10);
+    pub const ACCEL_SMPLRT_DIV_2: Register = (2, 0x11);
+    pub const ACCEL_INTEL_CTRL: Register = (2, 0x12);
+    pub const ACCEL_WOM_THR: Register = (2, 0x13);
+    pub const ACCEL_CONFIG: Register = (2, 0x14);
+
+    pub const I2C_MST_CTRL: Register = (3, 0x01);
+    pub const I2C_SLV0_ADDR: Register = (3, 0x03);
+    pub const I2C_SLV0_REG: Register = (3, 0x04);
+    pub const I2C_SLV0_CTRL: Register = (3, 0x05);
+    pub const I2C_SLV0_DO: Register = (3, 0x06);
+
+    /// AK09916 registers, reached through the I2C master
+    pub mod mag {
+        pub const WIA2: u8 = 0x01;
+        pub const HXL: u8 = 0x11;
+        pub const CNTL2: u8 = 0x31;
+        pub const CNTL3: u8 = 0x32;
+    }
+
+    /// Bank select is available from every bank
+    pub const REG_BANK_SEL: u8 = 0x7f;
+}
+
+/// Result of draining the FIFO
+#[derive(Debug, PartialEq)]
+pub enum Drain {
+    /// Number of bytes read, a whole number of packets
+    Packets(usize),
+
+    /// FIFO overflowed and was reset, its contents discarded
+    Overflow,
+}
+
+/// Banked register access to the ICM-20948
+pub struct Registers<B> {
+    bus: B,
+
+    /// Bank last selected, if known
+    bank: Option<u8>,
+}
+
+impl<B> Registers<B>
+where
+    B: I2c,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    pub fn new(bus: B) -> Self {
+        Self { bus, bank: None }
+    }
+
+    fn select(&mut self, bank: u8) -> Result<()> {
+        if self.bank != Some(bank) {
+            // a failed write leaves the bank unknown
+            self.bank = None;
+            self.bus
+                .write(I2C_ADDRESS, &[register::REG_BANK_SEL, bank << 4])?;
+            self.bank = Some(bank);
+        }
+
+        Ok(())
+    }
+
+    fn read(&mut self, register: register::Register) -> Result<u8> {
+        let mut value = [0u8];
+        self.read_block(register, &mut value)?;
+
+        Ok(value[0])
+    }
+
+    fn read_block(&mut self, (bank, address): register::Register, data: &mut [u8]) -> Result<()> {
+        self.select(bank)?;
+        Ok(self.bus.write_read(I2C_ADDRESS, &[address], data)?)
+    }
+
+    fn write(&mut self, (bank, address): register::Register, value: u8) -> Result<()> {
+        self.select(bank)?;
+        Ok(self.bus.write(I2C_ADDRESS, &[address, value])?)
+    }
+
+    /// Reset the device and start the FIFO at the nearest available rate, which is returned,
+    /// with the accelerometer at `accel_range` g full scale
+    pub fn configure(&mut self, sample_rate: f32, accel_range: u8) -> Result<f32> {
+        let accel_full_scale = match accel_range {
+            2 => 0,
+            4 => 1,
+            8 => 2,
+            16 => 3,
+            _ => {
+                return Err(Error::msg(format!(
+                    "unsupported accelerometer range {}g",
+                    accel_range
+                )))
+            }
+        };
+
+        // also rejects NaN, which the divider would not survive
+        if !(MIN_SAMPLE_RATE..=BASE_SAMPLE_RATE).contains(&sample_rate) {
+            return Err(Error::msg(format!(
+                "unsupported sample rate {} Hz, must be {:.1} to {}",
+                sample_rate, MIN_SAMPLE_RATE, BASE_SAMPLE_RATE
+            )));
+        }
+
+        // reset, then power on with the best available clock
+        self.bank = None;
+        self.write(register::PWR_MGMT_1, 0x80)?;
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        self.bank = None;
+        self.write(register::PWR_MGMT_1, 0x01)?;
+
+        let id = self.read(register::WHO_AM_I)?;
+        if id != DEVICE_ID {
+            return Err(Error::msg(format!("unexpected device id {:#04x}", id)));
+        }
+
+        // enable all accelerometer and gyrometer axes
+        self.write(register::PWR_MGMT_2, 0x00)?;
+
+        // rate = 1125 / (1 + divider), shared by accelerometer and gyrometer
+        let divider = (BASE_SAMPLE_RATE / sample_rate).round().clamp(1.0, 256.0) as u16 - 1;
+
+        // low pass filter enabled at ~120Hz bandwidth, lowest gyrometer full scale range
+        self.write(register::GYRO_SMPLRT_DIV, divider as u8)?;
+        self.write(register::GYRO_CONFIG_1, (2 << 3) | 0x01)?;
+        self.write(register::ACCEL_SMPLRT_DIV_1, (divider >> 8) as u8)?;
+        self.write(register::ACCEL_SMPLRT_DIV_2, divider as u8)?;
+        self.write(
+            register::ACCEL_CONFIG,
+            (2 << 3) | (accel_full_scale << 1) | 0x01,
+        )?;
+        self.write(register::ODR_ALIGN_EN, 0x01)?;
+
+        // snapshot mode stops writing when full, so packets stay aligned on overflow
+        self.write(register::FIFO_MODE, 0x01)?;
+        self.write(register::FIFO_EN_2, 0x1e)?;
+        self.write(register::INT_ENABLE_2, 0x01)?;
+        self.write(register::USER_CTRL, 0x40)?;
+        self.reset_fifo()?;
+
+        Ok(BASE_SAMPLE_RATE / (1.0 + divider as f32))
+    }
+
+    /// Raise the INT pin on `source`, latched until any register is read
+    pub fn enable_interrupt(&mut self, source: InterruptSource) -> Result<()> {
+        self.write(register::INT_PIN_CFG, 0x30)?;
+
+        let (data_ready, watermark) = match source {
+            InterruptSource::DataReady => (0x01, 0x00),
+            InterruptSource::Watermark => (0x00, 0x1f),
+        };
+        self.write(register::INT_ENABLE_1, data_ready)?;
+        self.write(register::INT_ENABLE_3, watermark)
+    }
+
+    /// Also raise the INT pin when consecutive samples differ by more than
+    /// `threshold` g on any axis, or stop if `None`
+    pub fn wake_on_motion(&mut self, threshold: Option<f32>) -> Result<()> {
+        match threshold {
+            Some(threshold) => {
+                // 4mg per LSB
+                let threshold = (threshold * 250.0).round().clamp(1.0, 255.0) as u8;
+                self.write(register::ACCEL_WOM_THR, threshold)?;
+                self.write(register::ACCEL_INTEL_CTRL, 0x03)?;
+                self.write(register::INT_ENABLE, 0x08)
+            }
+            None => {
+                self.write(register::INT_ENABLE, 0x00)?;
+                self.write(register::ACCEL_INTEL_CTRL, 0x00)
+            }
+        }
+    }
+
+    /// Write a magnetometer register through the I2C master
+    fn write_magnetometer(&mut self, address: u8, value: u8) -> Result<()> {
+        self.write(register::I2C_SLV0_ADDR, MAG_ADDRESS)?;
+        self.write(register::I2C_SLV0_REG, address)?;
+        self.write(register::I2C_SLV0_DO, value)?;
+        self.write(register::I2C_SLV0_CTRL, 0x81)?;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        Ok(())
+    }
+
+    /// Continuously copy `len` magnetometer registers into the external sensor data
+    fn mirror_magnetometer(&mut self, address: u8, len: u8) -> Result<()> {
+        self.write(register::I2C_SLV0_ADDR, 0x80 | MAG_ADDRESS)?;
+        self.write(register::I2C_SLV0_REG, address)?;
+        self.write(register::I2C_SLV0_CTRL, 0x80 | len)?;
+        std::thread::sleep(std::time::Duration::from_millis(10));
+
+        Ok(())
+    }
+
+    /// Start the magnetometer in continuous 100Hz mode through the I2C master
+    pub fn start_magnetometer(&mut self) -> Result<()> {
+        self.write(register::USER_CTRL, 0x60)?;
+        self.write(register::I2C_MST_CTRL, 0x17)?;
+
+        self.write_magnetometer(register::mag::CNTL3, 0x01)?;
+        self.mirror_magnetometer(register::mag::WIA2, 1)?;
+
+        let id = self.read(register::EXT_SLV_SENS_DATA_00)?;
+        if id != MAG_DEVICE_ID {
+            return Err(Error::msg(format!("unexpected device id {:#04x}", id)));
+        }
+
+        self.write_magnetometer(register::mag::CNTL2, 0x08)?;
+
+        // data and status 2, which must be read to release the next sample
+        self.mirror_magnetometer(register::mag::HXL, 8)
+    }
+
+    /// Latest magnetometer sample in microtesla, unless the sensor overflowed
+    pub fn magnetometer(&mut self) -> Result<Option<na::Vector3<f32>>> {
+        let mut data = [0u8; 8];
+        self.read_block(register::EXT_SLV_SENS_DATA_00, &mut data)?;
+
+        if data[7] & 0x08 != 0 {
+            return Ok(None);
+        }
+
+        let axis = |i: usize| i16::from_le_bytes([data[2 * i], data[2 * i + 1]]) as f32 * MAG_SCALE;
+
+        // the magnetometer y and z axes are opposite the accelerometer's
+        Ok(Some(na::Vector3::new(axis(0), -axis(1), -axis(2))))
+    }
+
+    fn reset_fifo(&mut self) -> Result<()> {
+        self.write(register::FIFO_RST, 0x1f)?;
+        self.write(register::FIFO_RST, 0x00)
+    }
+
+    /// Read all complete packets from the FIFO into `buffer`
+    pub fn drain(&mut self, buffer: &mut [u8]) -> Result<Drain> {
+        let overflow = self.read(register::INT_STATUS_2)? & 0x1f != 0;
+
+        let mut count = [0u8; 2];
+        self.read_block(register::FIFO_COUNTH, &mut count)?;
+        let count = (u16::from_be_bytes(count) & 0x1fff) as usize;
+
+        if overflow || count >= FIFO_SIZE {
+            self.reset_fifo()?;
+            return Ok(Drain::Overflow);
+        }
+
+        let len = count - count % PACKET_SIZE;
+        if len > 0 {
+            self.read_block(register::FIFO_R_W, &mut buffer[..len])?;
+        }
+
+        Ok(Drain::Packets(len))
+    }
+
+    /// Die temperature in degrees C
+    pub fn temperature(&mut self) -> Result<f32> {
+        let mut temp = [0u8; 2];
+        self.read_block(register::TEMP_OUT_H, &mut temp)?;
+
+        Ok(i16::from_be_bytes(temp) as f32 / TEMP_SENSITIVITY + 21.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        register, Drain, Registers, ACCEL_SENSITIVITY, BASE_SAMPLE_RATE, I2C_ADDRESS,
+        MIN_SAMPLE_RATE, PACKET_SIZE,
+    };
+    use crate::hardware::config::InterruptSource;
+    use crate::hardware::i2c::simulated::{RegisterMap, SimulatedBus};
+    use crate::hardware::imu::{simulator, ImuSample};
+    use chrono::Utc;
+    use embedded_hal::i2c::ErrorKind;
+
+    fn configured() -> (SimulatedBus, Registers<SimulatedBus>) {
+        let bus = SimulatedBus::new();
+        bus.attach(I2C_ADDRESS, simulator::device());
+
+        let mut imu = Registers::new(bus.clone());
+        assert_eq!(187.5, imu.configure(200.0, 2).unwrap());
+
+        (bus, imu)
+    }
+
+    fn device<T>(bus: &SimulatedBus, f: impl FnOnce(&mut RegisterMap) -> T) -> T {
+        bus.device(I2C_ADDRESS, f).unwrap()
+    }
+
+    #[test]
+    fn scaling() {
+        let (bus, mut imu) = configured();
+
+        // +0.5g on x and -1 dps on z, then a partial packet
+        let packet = [0x20, 0x00, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0x7d];
+        device(&bus, |d| {
+            d.push(register::FIFO_R_W, &packet);
+            d.push(register::FIFO_R_W, &packet);
+            d.push(register::FIFO_R_W, &[0; 6]);
+        });
+
+        let mut buffer = [0u8; 64];
+        assert_eq!(
+            Drain::Packets(2 * PACKET_SIZE),
+            imu.drain(&mut buffer).unwrap()
+        );
+
+        let sample = ImuSample::from_packet(
+            Utc::now(),
+            &buffer[PACKET_SIZE..2 * PACKET_SIZE],
+            ACCEL_SENSITIVITY,
+        );
+        assert_eq!(0.5, sample.accelerometer.x);
+        assert_eq!(-1.0, sample.gyrometer.z);
+
+        assert!((imu.temperature().unwrap() - 25.0).abs() < 0.01);
+
+        imu.start_magnetometer().unwrap();
+        let field = imu.magnetometer().unwrap().unwrap();
+        assert!((field.x - 20.0).abs() < 0.2);
+        assert!((field.z + 45.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn bank_switching() {
+        let (bus, mut imu) = configured();
+
+        // the divider landed in bank 2 without disturbing bank 0
+        device(&bus, |d| {
+            assert_eq!(5, d.get(register::GYRO_SMPLRT_DIV));
+            assert_eq!(0xea, d.get(register::WHO_AM_I));
+        });
+
+        let before = device(&bus, |d| d.writes().len());
+        imu.read(register::GYRO_SMPLRT_DIV).unwrap();
+        imu.read(register::ACCEL_CONFIG).unwrap();
+        imu.read(register::WHO_AM_I).unwrap();
+
+        // bank select is only written when the bank changes
+        let selects: Vec<u8> = device(&bus, |d| {
+            d.writes()[before..]
+                .iter()
+                .filter(|(r, _)| *r == (0, register::REG_BANK_SEL))
+                .map(|(_, value)| *value)
+                .collect()
+        });
+        assert_eq!(vec![2 << 4, 0], selects);
+        assert_eq!(0, device(&bus, |d| d.bank()));
+    }
+
+    #[test]
+    fn interrupts() {
+        let (bus, mut imu) = configured();
+
+        imu.enable_interrupt(InterruptSource::Watermark).unwrap();
+        imu.wake_on_motion(Some(0.1)).unwrap();
+        device(&bus, |d| {
+            assert_eq!(0x00, d.get(register::INT_ENABLE_1));
+            assert_eq!(0x1f, d.get(register::INT_ENABLE_3));
+            assert_eq!(0x08, d.get(register::INT_ENABLE));
+            assert_eq!(25, d.get(register::ACCEL_WOM_THR));
+        });
+
+        imu.wake_on_motion(None).unwrap();
+        assert_eq!(0x00, device(&bus, |d| d.get(register::INT_ENABLE)));
+    }
+
+    #[test]
+    fn errors() {
+        // unsupported range
+        let (_, mut imu) = configured();
+        assert!(imu.configure(200.0, 3).is_err());
+
+        // unsupported sample rates
+        for rate in [f32::NAN, f32::INFINITY, 0.0, -200.0, 4.0, 1200.0].iter() {
+            assert!(imu.configure(*rate, 2).is_err());
+        }
+        assert_eq!(
+            BASE_SAMPLE_RATE,
+            imu.configure(BASE_SAMPLE_RATE, 2).unwrap()
+        );
+        assert_eq!(MIN_SAMPLE_RATE, imu.configure(MIN_SAMPLE_RATE, 2).unwrap());
+
+        // nothing at the address
+        let mut imu = Registers::new(SimulatedBus::new());
+        assert!(imu.configure(200.0, 2).is_err());
+
+        // something else at the address
+        let bus = SimulatedBus::new();
+        bus.attach(I2C_ADDRESS, RegisterMap::banked(register::REG_BANK_SEL, 4));
+        let error = Registers::new(bus).configure(200.0, 2).unwrap_err();
+        assert_eq!("unexpected device id 0x00", error.to_string());
+
+        // a failed bank select is retried
+        let (bus, mut imu) = configured();
+        device(&bus, |d| d.fail(ErrorKind::ArbitrationLoss));
+        assert!(imu.read(register::GYRO_SMPLRT_DIV).is_err());
+        assert_eq!(5, imu.read(register::GYRO_SMPLRT_DIV).unwrap());
+
+        // overflow discards the FIFO
+        device(&bus, |d| d.push(register::FIFO_R_W, &[0; 516]));
+        let mut buffer = [0u8; 512];
+        assert_eq!(Drain::Overflow, imu.drain(&mut buffer).unwrap());
+        assert_eq!(0, device(&bus, |d| d.queued(register::FIFO_R_W)));
+
+        // magnetometer overflow has no reading
+        let (bank, address) = register::EXT_SLV_SENS_DATA_00;
+        device(&bus, |d| d.set((bank, address + 7), 0x08));
+        assert!(imu.magnetometer().unwrap().is_none());
+    }
+}
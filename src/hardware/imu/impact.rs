@@ -0,0 +1,222 @@
+//! Impact detection and recording
+//!
+//! The dynamic acceleration of each full-rate sample is its deviation from
+//! gravity.  When it exceeds the threshold, the samples from shortly before
+//! the trigger until shortly after are recorded as CSV in the state directory,
+//! so that pothole strikes and other shocks can be inspected afterwards.
+
+use super::ImuSample;
+use crate::hardware::config;
+use crate::state;
+use anyhow::{Error, Result};
+use chrono::{DateTime, Duration, Utc};
+use nalgebra as na;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{BufWriter, Write};
+
+pub use habctl_protocol::imu::Impact;
+
+/// Default dynamic acceleration that triggers a recording, in g
+const DEFAULT_THRESHOLD: f32 = 1.5;
+
+/// Default time recorded before the trigger, in seconds
+const DEFAULT_PRE_TRIGGER: f32 = 2.0;
+
+/// Default time recorded after the trigger, in seconds
+const DEFAULT_POST_TRIGGER: f32 = 5.0;
+
+/// State directory holding recordings
+const DIRECTORY: &str = "impacts";
+
+/// Samples around an impact, ready to be saved
+pub struct Recording {
+    pub impact: Impact,
+    samples: Vec<ImuSample>,
+}
+
+/// Recording in progress
+struct Capture {
+    trigger: DateTime<Utc>,
+    peak: f32,
+    samples: Vec<ImuSample>,
+}
+
+pub struct ImpactRecorder {
+    name: String,
+    threshold: f32,
+    pre_trigger: Duration,
+    post_trigger: Duration,
+
+    /// Samples within the pre-trigger window
+    history: VecDeque<ImuSample>,
+
+    capture: Option<Capture>,
+}
+
+impl ImpactRecorder {
+    pub fn new(name: &str, config: &config::Impact) -> Self {
+        let seconds = |s: f32| Duration::milliseconds((s * 1000.0) as i64);
+
+        Self {
+            name: name.to_owned(),
+            threshold: config.threshold.unwrap_or(DEFAULT_THRESHOLD),
+            pre_trigger: seconds(config.pre_trigger.unwrap_or(DEFAULT_PRE_TRIGGER)),
+            post_trigger: seconds(config.post_trigger.unwrap_or(DEFAULT_POST_TRIGGER)),
+            history: VecDeque::new(),
+            capture: None,
+        }
+    }
+
+    /// Add a calibrated sample, given the direction of up in the sensor frame,
+    /// returning the recording once the post-trigger window is complete
+    pub fn sample(&mut self, sample: &ImuSample, up: &na::Vector3<f32>) -> Option<Recording> {
+        let magnitude = (sample.accelerometer - up).norm();
+
+        if let Some(capture) = &mut self.capture {
+            capture.samples.push(*sample);
+            capture.peak = capture.peak.max(magnitude);
+
+            if sample.timestamp - capture.trigger < self.post_trigger {
+                return None;
+            }
+
+            let capture = self.capture.take()?;
+            return Some(Recording {
+                impact: Impact {
+                    timestamp: capture.trigger,
+                    peak: capture.peak,
+                    recording: format!(
+                        "{}-{}.csv",
+                        self.name,
+                        capture.trigger.format("%Y%m%dT%H%M%S%.3fZ")
+                    ),
+                },
+                samples: capture.samples,
+            });
+        }
+
+        while matches!(self.history.front(), Some(s) if sample.timestamp - s.timestamp > self.pre_trigger)
+        {
+            self.history.pop_front();
+        }
+
+        if magnitude > self.threshold {
+            let mut samples: Vec<ImuSample> = self.history.drain(..).collect();
+            samples.push(*sample);
+
+            self.capture = Some(Capture {
+                trigger: sample.timestamp,
+                peak: magnitude,
+                samples,
+            });
+        } else {
+            self.history.push_back(*sample);
+        }
+
+        None
+    }
+}
+
+impl Recording {
+    /// Write the samples as CSV
+    pub fn save(&self) -> Result<()> {
+        let path = state::directory(DIRECTORY)?.join(&self.impact.recording);
+        let mut file = BufWriter::new(fs::File::create(path)?);
+
+        writeln!(file, "timestamp,ax,ay,az,gx,gy,gz")?;
+        for s in &self.samples {
+            let (a, g) = (&s.accelerometer, &s.gyrometer);
+            writeln!(
+                file,
+                "{},{},{},{},{},{},{}",
+                s.timestamp.to_rfc3339(),
+                a.x,
+                a.y,
+                a.z,
+                g.x,
+                g.y,
+                g.z
+            )?;
+        }
+
+        file.into_inner()?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Names of the saved recordings for the IMU `name`, oldest first
+pub fn recordings(name: &str) -> Result<Vec<String>> {
+    let prefix = format!("{}-", name);
+    let mut names = Vec::new();
+
+    for entry in fs::read_dir(state::directory(DIRECTORY)?)? {
+        if let Some(file) = entry?.file_name().to_str() {
+            if file.starts_with(&prefix) && file.ends_with(".csv") {
+                names.push(file.to_owned());
+            }
+        }
+    }
+
+    // the timestamp in the name sorts chronologically
+    names.sort();
+    Ok(names)
+}
+
+/// Contents of a saved recording for the IMU `name`
+pub fn recording(name: &str, recording: &str) -> Result<String> {
+    if !recordings(name)?.iter().any(|r| r == recording) {
+        return Err(Error::msg(format!("no recording named {}", recording)));
+    }
+
+    Ok(fs::read_to_string(
+        state::directory(DIRECTORY)?.join(recording),
+    )?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::ImpactRecorder;
+    use crate::hardware::imu::ImuSample;
+    use chrono::{Duration, Utc};
+    use nalgebra as na;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn records_around_trigger() {
+        let mut recorder = ImpactRecorder {
+            name: "hab".to_owned(),
+            threshold: 1.5,
+            pre_trigger: Duration::seconds(2),
+            post_trigger: Duration::seconds(5),
+            history: VecDeque::new(),
+            capture: None,
+        };
+
+        let start = Utc::now();
+        let up = na::Vector3::z();
+        let mut recordings = Vec::new();
+
+        // 10 seconds at 100Hz with a 3g jolt sideways at 4 seconds
+        for i in 0..1000 {
+            let x = if i == 400 { 3.0 } else { 0.0 };
+            let sample = ImuSample {
+                timestamp: start + Duration::milliseconds(i * 10),
+                gyrometer: na::Vector3::zeros(),
+                accelerometer: na::Vector3::new(x, 0.0, 1.0),
+                magnetometer: None,
+            };
+
+            recordings.extend(recorder.sample(&sample, &up));
+        }
+
+        assert_eq!(1, recordings.len());
+        let recording = &recordings[0];
+        assert_eq!(start + Duration::seconds(4), recording.impact.timestamp);
+        assert_eq!(3.0, recording.impact.peak);
+        assert!(recording.impact.recording.starts_with("hab-"));
+
+        // 2 seconds before through 5 seconds after
+        assert_eq!(701, recording.samples.len());
+    }
+}
@@ -0,0 +1,231 @@
+//! Camper leveling assistant
+//!
+//! The vehicle frame is x forward, y left and z up, centered between the
+//! wheels.  Gravity measured by the IMU is rotated into the vehicle frame,
+//! compared against a stored level reference, and turned into how far each
+//! wheel or jack must be raised so that the lowest ones meet the highest.
+
+use crate::hardware::config;
+use crate::state;
+use anyhow::{Error, Result};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+pub use habctl_protocol::imu::{Correction, Level};
+
+/// Default tolerance within which the camper is considered level, in degrees
+const DEFAULT_TOLERANCE: f32 = 0.5;
+
+/// Default leveling block height, in inches
+const DEFAULT_BLOCK_HEIGHT: f32 = 1.0;
+
+/// Attitude of the vehicle when parked level
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Reference {
+    pub pitch: f32,
+    pub roll: f32,
+}
+
+/// Point on the vehicle that can be raised
+struct Support {
+    name: String,
+
+    /// Position in the vehicle frame, in inches
+    x: f32,
+    y: f32,
+}
+
+pub struct Leveling {
+    /// Persistent state name of the level reference
+    state: String,
+
+    /// Rotation from the sensor frame into the vehicle frame
+    mounting: na::UnitQuaternion<f32>,
+
+    supports: Vec<Support>,
+    tolerance: f32,
+    block_height: f32,
+    reference: Reference,
+
+    /// Attitude at the most recent update, without the reference applied
+    attitude: Option<Reference>,
+}
+
+impl Leveling {
+    pub fn new(name: &str, config: &config::Leveling, mounting: na::UnitQuaternion<f32>) -> Self {
+        let state = format!("imu-{}-level", name);
+
+        let reference = state::load(&state).unwrap_or_else(|e| {
+            log::error!("IMU {}: level reference: {}", name, e);
+            None
+        });
+
+        let (front, left) = (config.wheelbase / 2.0, config.track / 2.0);
+
+        let mut supports = vec![
+            Support::new("front_left", front, left),
+            Support::new("front_right", front, -left),
+            Support::new("rear_left", -front, left),
+            Support::new("rear_right", -front, -left),
+        ];
+        supports.extend(
+            config
+                .jacks
+                .iter()
+                .map(|jack| Support::new(&jack.name, jack.x, jack.y)),
+        );
+
+        Self {
+            state,
+            mounting,
+            supports,
+            tolerance: config.tolerance.unwrap_or(DEFAULT_TOLERANCE),
+            block_height: config.block_height.unwrap_or(DEFAULT_BLOCK_HEIGHT),
+            reference: reference.unwrap_or_default(),
+            attitude: None,
+        }
+    }
+
+    /// Update from the mean accelerometer reading of a stationary vehicle, in g
+    pub fn update(&mut self, accelerometer: &na::Vector3<f32>) -> Option<Level> {
+        // the accelerometer measures the reaction to gravity, which points up
+        let up = (self.mounting * accelerometer).try_normalize(0.0)?;
+
+        let attitude = Reference {
+            pitch: up.x.atan2(up.z).to_degrees(),
+            roll: up.y.atan2(up.z).to_degrees(),
+        };
+        self.attitude = Some(attitude);
+
+        let pitch = attitude.pitch - self.reference.pitch;
+        let roll = attitude.roll - self.reference.roll;
+
+        // height of each support above the center of the vehicle
+        let (dx, dy) = (pitch.to_radians().tan(), roll.to_radians().tan());
+        let heights: Vec<f32> = self.supports.iter().map(|s| s.x * dx + s.y * dy).collect();
+        let highest = heights.iter().cloned().fold(f32::MIN, f32::max);
+
+        let corrections = self
+            .supports
+            .iter()
+            .zip(heights)
+            .map(|(support, height)| {
+                let raise = highest - height;
+
+                Correction {
+                    name: support.name.clone(),
+                    raise,
+                    blocks: (raise / self.block_height).round() as u32,
+                }
+            })
+            .collect();
+
+        Some(Level {
+            pitch,
+            roll,
+            level: pitch.abs() <= self.tolerance && roll.abs() <= self.tolerance,
+            corrections,
+        })
+    }
+
+    /// Take the current attitude as level
+    pub fn set_reference(&mut self) -> Result<Reference> {
+        let attitude = self
+            .attitude
+            .ok_or_else(|| Error::msg("no accelerometer data yet"))?;
+
+        state::save(&self.state, &attitude)?;
+        self.reference = attitude;
+
+        Ok(attitude)
+    }
+
+    /// Return to the IMU mounting as the level reference
+    pub fn clear_reference(&mut self) -> Result<()> {
+        state::remove(&self.state)?;
+        self.reference = Reference::default();
+
+        Ok(())
+    }
+}
+
+impl Support {
+    fn new(name: &str, x: f32, y: f32) -> Self {
+        Self {
+            name: name.to_owned(),
+            x,
+            y,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Leveling, Reference, Support};
+    use nalgebra as na;
+
+    fn leveling() -> Leveling {
+        Leveling {
+            state: String::new(),
+            mounting: na::UnitQuaternion::identity(),
+            supports: vec![
+                Support::new("front_left", 70.0, 35.0),
+                Support::new("front_right", 70.0, -35.0),
+                Support::new("rear_left", -70.0, 35.0),
+                Support::new("rear_right", -70.0, -35.0),
+            ],
+            tolerance: 0.5,
+            block_height: 1.0,
+            reference: Reference::default(),
+            attitude: None,
+        }
+    }
+
+    fn raise(level: &super::Level, name: &str) -> f32 {
+        level
+            .corrections
+            .iter()
+            .find(|c| c.name == name)
+            .unwrap()
+            .raise
+    }
+
+    #[test]
+    fn level() {
+        let level = leveling().update(&na::Vector3::new(0.0, 0.0, 1.0)).unwrap();
+
+        assert!(level.level);
+        assert!(level.corrections.iter().all(|c| c.raise == 0.0));
+    }
+
+    #[test]
+    fn nose_down() {
+        // nose down by 2 degrees: up leans backwards in the vehicle frame
+        let tilt = 2f32.to_radians();
+        let level = leveling()
+            .update(&na::Vector3::new(-tilt.sin(), 0.0, tilt.cos()))
+            .unwrap();
+
+        assert!(!level.level);
+        assert!((level.pitch + 2.0).abs() < 0.001);
+
+        // 140 inch wheelbase, 2 degrees
+        let expected = 140.0 * tilt.tan();
+        assert!((raise(&level, "front_left") - expected).abs() < 0.001);
+        assert!((raise(&level, "front_right") - expected).abs() < 0.001);
+        assert_eq!(0.0, raise(&level, "rear_left"));
+        assert_eq!(5, level.corrections[0].blocks);
+    }
+
+    #[test]
+    fn reference() {
+        let mut leveling = leveling();
+        let tilt = 1f32.to_radians();
+        let a = na::Vector3::new(0.0, tilt.sin(), tilt.cos());
+
+        assert!((leveling.update(&a).unwrap().roll - 1.0).abs() < 0.001);
+
+        leveling.reference = leveling.attitude.unwrap();
+        assert!(leveling.update(&a).unwrap().level);
+    }
+}
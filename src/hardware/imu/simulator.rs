@@ -0,0 +1,213 @@
+//! Simulated ICM-20948 for loopback mode and tests
+//!
+//! Models as much of the device as the driver uses: reset and identification,
+//! the FIFO in snapshot mode, and an AK09916 magnetometer behind the I2C
+//! master.  The simulated IMU sits level and still, facing north.
+
+use super::icm20948::{
+    register, ACCEL_SENSITIVITY, BASE_SAMPLE_RATE, DEVICE_ID, FIFO_SIZE, I2C_ADDRESS, MAG_ADDRESS,
+    MAG_DEVICE_ID, MAG_SCALE, PACKET_SIZE, TEMP_SENSITIVITY,
+};
+use crate::hardware::gpio::SimulatedLine;
+use crate::hardware::i2c::simulated::{RegisterMap, SimulatedBus};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+/// Die temperature, in degrees C
+const TEMPERATURE: f32 = 25.0;
+
+/// Earth's field in the sensor frame, north and down, in microtesla
+const FIELD: [f32; 3] = [20.0, 0.0, -45.0];
+
+/// FIFO fill at which the watermark interrupt is raised, in bytes
+const WATERMARK: usize = FIFO_SIZE / 2;
+
+/// Peak noise added to each accelerometer and gyrometer axis, in LSB
+const NOISE: i32 = 16;
+
+/// Bus with a simulated IMU producing samples in real time, and its INT line
+///
+/// Samples are produced whenever the driver checks the FIFO, and also in the
+/// background while the line is held, so that its interrupts are raised on
+/// time.  The IMU is still, so wake-on-motion never fires.
+pub fn bus() -> (SimulatedBus, SimulatedLine) {
+    let bus = SimulatedBus::new();
+    let mut device = device();
+    let generator = Arc::new(Mutex::new(Generator {
+        last: Instant::now(),
+        noise: Noise(0x2545_f491),
+        overflow: false,
+    }));
+
+    // reading the status clears overflow
+    let status = generator.clone();
+    device.on_read(register::INT_STATUS_2, move |device| {
+        let mut generator = status.lock().unwrap();
+        generator.fill(device);
+
+        let overflow = std::mem::take(&mut generator.overflow);
+        device.set(register::INT_STATUS_2, if overflow { 0x01 } else { 0x00 });
+    });
+
+    bus.attach(I2C_ADDRESS, device);
+
+    let (line, trigger) = SimulatedLine::new();
+    let background = bus.clone();
+
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_millis(1));
+
+        while !trigger.is_closed() {
+            interval.tick().await;
+
+            let raised = background.device(I2C_ADDRESS, |device| {
+                let mut generator = generator.lock().unwrap();
+                let before = device.queued(register::FIFO_R_W);
+                let packets = generator.fill(device);
+                let after = device.queued(register::FIFO_R_W);
+
+                let data_ready = packets > 0 && device.get(register::INT_ENABLE_1) & 0x01 != 0;
+                let watermark = before < WATERMARK
+                    && after >= WATERMARK
+                    && device.get(register::INT_ENABLE_3) & 0x1f != 0;
+                let overflow = generator.overflow && device.get(register::INT_ENABLE_2) & 0x1f != 0;
+
+                data_ready || watermark || overflow
+            });
+
+            if raised == Some(true) {
+                trigger.raise();
+            }
+        }
+    });
+
+    (bus, line)
+}
+
+/// Simulated IMU with an empty FIFO, which tests fill as needed
+pub fn device() -> RegisterMap {
+    let mut device = RegisterMap::banked(register::REG_BANK_SEL, 4);
+    power_on(&mut device);
+
+    device.on_write(register::PWR_MGMT_1, |device, value| {
+        if value & 0x80 != 0 {
+            device.reset();
+            power_on(device);
+        }
+    });
+
+    device.on_write(register::FIFO_RST, |device, value| {
+        if value & 0x1f != 0 {
+            device.clear(register::FIFO_R_W);
+        }
+    });
+
+    device.on_read(register::FIFO_COUNTH, |device| {
+        let count = device.queued(register::FIFO_R_W) as u16;
+        device.set_block(register::FIFO_COUNTH, &count.to_be_bytes());
+    });
+
+    // AK09916 registers, transferred by the I2C master when slave 0 is enabled
+    let mut magnetometer = [0u8; 0x40];
+    magnetometer[register::mag::WIA2 as usize] = MAG_DEVICE_ID;
+    for (i, field) in FIELD.iter().enumerate() {
+        // the magnetometer y and z axes are opposite the accelerometer's
+        let field = if i == 0 { *field } else { -*field };
+        let raw = ((field / MAG_SCALE) as i16).to_le_bytes();
+        magnetometer[register::mag::HXL as usize + 2 * i..][..2].copy_from_slice(&raw);
+    }
+
+    device.on_write(register::I2C_SLV0_CTRL, move |device, value| {
+        let slave = device.get(register::I2C_SLV0_ADDR);
+        let address = device.get(register::I2C_SLV0_REG) as usize;
+
+        if value & 0x80 == 0 || slave & 0x7f != MAG_ADDRESS || address >= magnetometer.len() {
+            return;
+        }
+
+        if slave & 0x80 == 0 {
+            magnetometer[address] = device.get(register::I2C_SLV0_DO);
+        } else {
+            let len = (value & 0x0f) as usize;
+            let end = (address + len).min(magnetometer.len());
+            device.set_block(register::EXT_SLV_SENS_DATA_00, &magnetometer[address..end]);
+        }
+    });
+
+    device
+}
+
+fn power_on(device: &mut RegisterMap) {
+    device.set(register::WHO_AM_I, DEVICE_ID);
+    device.set(register::PWR_MGMT_1, 0x41);
+
+    let temperature = ((TEMPERATURE - 21.0) * TEMP_SENSITIVITY) as i16;
+    device.set_block(register::TEMP_OUT_H, &temperature.to_be_bytes());
+}
+
+/// FIFO packet of a level, still IMU
+fn packet(device: &RegisterMap, noise: &mut Noise) -> [u8; PACKET_SIZE] {
+    let full_scale = (device.get(register::ACCEL_CONFIG) >> 1) & 0x03;
+    let one_g = ACCEL_SENSITIVITY as i32 >> full_scale;
+
+    let axes = [0, 0, one_g, 0, 0, 0];
+    let mut packet = [0u8; PACKET_SIZE];
+
+    for (i, axis) in axes.iter().enumerate() {
+        let value = (axis + noise.sample()).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        packet[2 * i..][..2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    packet
+}
+
+/// Produces samples at the configured rate while the FIFO is enabled
+struct Generator {
+    last: Instant,
+    noise: Noise,
+
+    /// Samples were dropped because the FIFO was full
+    overflow: bool,
+}
+
+impl Generator {
+    /// Add the samples produced since the last fill, returning how many
+    fn fill(&mut self, device: &mut RegisterMap) -> u32 {
+        let enabled =
+            device.get(register::USER_CTRL) & 0x40 != 0 && device.get(register::FIFO_EN_2) != 0;
+        let rate = BASE_SAMPLE_RATE / (1.0 + device.get(register::GYRO_SMPLRT_DIV) as f32);
+
+        let packets = (self.last.elapsed().as_secs_f32() * rate) as u32;
+        self.last += Duration::from_secs_f32(packets as f32 / rate);
+
+        if !enabled {
+            return 0;
+        }
+
+        for i in 0..packets {
+            // snapshot mode drops new samples when full
+            if device.queued(register::FIFO_R_W) + PACKET_SIZE > FIFO_SIZE {
+                self.overflow = true;
+                return i;
+            }
+
+            device.push(register::FIFO_R_W, &packet(device, &mut self.noise));
+        }
+
+        packets
+    }
+}
+
+/// Xorshift noise, uniform within +/-`NOISE`
+struct Noise(u32);
+
+impl Noise {
+    fn sample(&mut self) -> i32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+
+        (self.0 % (2 * NOISE as u32 + 1)) as i32 - NOISE
+    }
+}
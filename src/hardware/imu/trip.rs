@@ -0,0 +1,303 @@
+//! Driving detection and trip segmentation
+//!
+//! Samples are grouped into one second windows.  The vibration of a window is
+//! the standard deviation of the acceleration magnitude, which is near zero
+//! while parked and grows with road surface and engine vibration.  Sustained
+//! vibration above the driving threshold starts a trip, and sustained quiet
+//! ends it, so that stops at traffic lights do not split a trip.
+
+use super::{ImuEvent, ImuSample};
+use crate::hardware::config;
+use crate::state;
+use chrono::{DateTime, Duration, Utc};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+pub use habctl_protocol::imu::Trip;
+
+/// Default vibration above which the vehicle is driving, in g
+const DEFAULT_DRIVING: f32 = 0.02;
+
+/// Default vibration above which the road is rough, in g
+const DEFAULT_ROUGH: f32 = 0.15;
+
+/// Default speed above which the vehicle is driving, in m/s
+const DEFAULT_SPEED: f32 = 2.0;
+
+/// Default time driving before a trip starts, in seconds
+const DEFAULT_START_DELAY: i64 = 30;
+
+/// Default time parked before a trip ends, in seconds
+const DEFAULT_STOP_DELAY: i64 = 180;
+
+/// Number of completed trips kept
+const HISTORY: usize = 100;
+
+fn set_end(trip: &mut Trip, end: DateTime<Utc>) {
+    trip.end = end;
+    trip.duration = seconds(end - trip.start);
+}
+
+fn seconds(duration: Duration) -> f32 {
+    duration.num_milliseconds() as f32 / 1000.0
+}
+
+/// Trip history and the trip in progress
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Trips {
+    pub current: Option<Trip>,
+    pub history: VecDeque<Trip>,
+}
+
+/// Statistics of one window of samples
+#[derive(Default)]
+struct Window {
+    start: Option<DateTime<Utc>>,
+    count: u32,
+    sum: f32,
+    sum_squares: f32,
+    max_lateral: f32,
+}
+
+pub struct TripDetector {
+    /// Persistent state name of the trip history
+    state: String,
+
+    /// Rotation from the sensor frame into the vehicle frame
+    mounting: na::UnitQuaternion<f32>,
+
+    driving_threshold: f32,
+    rough_threshold: f32,
+    speed_threshold: f32,
+    start_delay: Duration,
+    stop_delay: Duration,
+
+    window: Window,
+    driving: bool,
+
+    /// Since when the vibration has disagreed with `driving`
+    pending: Option<DateTime<Utc>>,
+
+    trips: Trips,
+}
+
+impl TripDetector {
+    pub fn new(name: &str, config: &config::Trip, mounting: na::UnitQuaternion<f32>) -> Self {
+        let state = format!("imu-{}-trips", name);
+
+        let trips: Option<Trips> = state::load(&state).unwrap_or_else(|e| {
+            log::error!("IMU {}: trips: {}", name, e);
+            None
+        });
+
+        // a trip interrupted by a restart is abandoned
+        let mut trips = trips.unwrap_or_default();
+        trips.current = None;
+
+        Self {
+            state,
+            mounting,
+            driving_threshold: config.driving.unwrap_or(DEFAULT_DRIVING),
+            rough_threshold: config.rough.unwrap_or(DEFAULT_ROUGH),
+            speed_threshold: config.speed.unwrap_or(DEFAULT_SPEED),
+            start_delay: Duration::seconds(config.start_delay.unwrap_or(DEFAULT_START_DELAY)),
+            stop_delay: Duration::seconds(config.stop_delay.unwrap_or(DEFAULT_STOP_DELAY)),
+            window: Window::default(),
+            driving: false,
+            pending: None,
+            trips,
+        }
+    }
+
+    pub fn driving(&self) -> bool {
+        self.driving
+    }
+
+    pub fn trips(&self) -> Trips {
+        self.trips.clone()
+    }
+
+    /// Copy of the trip history to save
+    pub fn snapshot(&self) -> state::Snapshot<Trips> {
+        state::Snapshot::new(&self.state, self.trips.clone())
+    }
+
+    /// Add a calibrated sample, given the direction of up in the sensor frame
+    /// and ground speed in m/s if known
+    pub fn sample(
+        &mut self,
+        sample: &ImuSample,
+        up: &na::Vector3<f32>,
+        speed: Option<f32>,
+    ) -> Option<ImuEvent> {
+        let a = &sample.accelerometer;
+        let magnitude = a.norm();
+        let lateral = (self.mounting * (a - up)).y.abs();
+
+        let window = &mut self.window;
+        let start = *window.start.get_or_insert(sample.timestamp);
+        window.count += 1;
+        window.sum += magnitude;
+        window.sum_squares += magnitude * magnitude;
+        window.max_lateral = window.max_lateral.max(lateral);
+
+        if sample.timestamp - start < Duration::seconds(1) {
+            return None;
+        }
+
+        let window = std::mem::take(&mut self.window);
+        let mean = window.sum / window.count as f32;
+        let vibration = (window.sum_squares / window.count as f32 - mean * mean)
+            .max(0.0)
+            .sqrt();
+
+        let moving = vibration > self.driving_threshold
+            || matches!(speed, Some(speed) if speed > self.speed_threshold);
+
+        if self.driving {
+            if let Some(trip) = &mut self.trips.current {
+                set_end(trip, sample.timestamp);
+                trip.max_lateral = trip.max_lateral.max(window.max_lateral);
+                trip.max_vibration = trip.max_vibration.max(vibration);
+
+                if vibration > self.rough_threshold {
+                    trip.rough_seconds += seconds(sample.timestamp - start);
+                }
+            }
+        }
+
+        if moving == self.driving {
+            self.pending = None;
+            return None;
+        }
+
+        let since = *self.pending.get_or_insert(start);
+
+        if moving && sample.timestamp - since >= self.start_delay {
+            self.driving = true;
+            self.pending = None;
+            self.trips.current = Some(Trip {
+                start: since,
+                end: sample.timestamp,
+                duration: seconds(sample.timestamp - since),
+                max_lateral: window.max_lateral,
+                max_vibration: vibration,
+                rough_seconds: 0.0,
+            });
+
+            Some(ImuEvent::TripStart { timestamp: since })
+        } else if !moving && sample.timestamp - since >= self.stop_delay {
+            self.driving = false;
+            self.pending = None;
+
+            let mut trip = self.trips.current.take()?;
+            set_end(&mut trip, since);
+
+            if self.trips.history.len() == HISTORY {
+                self.trips.history.pop_front();
+            }
+            self.trips.history.push_back(trip.clone());
+
+            Some(ImuEvent::TripEnd { trip })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::TripDetector;
+    use crate::hardware::imu::{ImuEvent, ImuSample};
+    use chrono::{DateTime, Duration, Utc};
+    use nalgebra as na;
+
+    fn detector() -> TripDetector {
+        TripDetector {
+            state: String::new(),
+            mounting: na::UnitQuaternion::identity(),
+            driving_threshold: 0.02,
+            rough_threshold: 0.15,
+            speed_threshold: 2.0,
+            start_delay: Duration::seconds(30),
+            stop_delay: Duration::seconds(180),
+            window: Default::default(),
+            driving: false,
+            pending: None,
+            trips: Default::default(),
+        }
+    }
+
+    /// Feed `seconds` of 10Hz samples alternating by `vibration` about 1g
+    fn run(
+        detector: &mut TripDetector,
+        start: DateTime<Utc>,
+        seconds: i64,
+        vibration: f32,
+    ) -> Vec<ImuEvent> {
+        let up = na::Vector3::z();
+        let mut events = Vec::new();
+
+        for i in 0..seconds * 10 {
+            let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+            let sample = ImuSample {
+                timestamp: start + Duration::milliseconds(i * 100),
+                gyrometer: na::Vector3::zeros(),
+                accelerometer: na::Vector3::new(0.0, 0.0, 1.0 + sign * vibration),
+                magnetometer: None,
+            };
+
+            events.extend(detector.sample(&sample, &up, None));
+        }
+
+        events
+    }
+
+    #[test]
+    fn starts_after_delay() {
+        let mut detector = detector();
+        let start = Utc::now();
+
+        assert!(run(&mut detector, start, 20, 0.05).is_empty());
+        assert!(!detector.driving());
+
+        let events = run(&mut detector, start + Duration::seconds(20), 20, 0.05);
+        assert!(matches!(events[..], [ImuEvent::TripStart { timestamp }] if timestamp == start));
+        assert!(detector.driving());
+    }
+
+    #[test]
+    fn brief_vibration_is_not_a_trip() {
+        let mut detector = detector();
+        let start = Utc::now();
+
+        run(&mut detector, start, 20, 0.05);
+        run(&mut detector, start + Duration::seconds(20), 5, 0.0);
+        run(&mut detector, start + Duration::seconds(25), 20, 0.05);
+
+        assert!(!detector.driving());
+    }
+
+    #[test]
+    fn summarizes_trip() {
+        let mut detector = detector();
+        let start = Utc::now();
+
+        run(&mut detector, start, 60, 0.2);
+        run(&mut detector, start + Duration::seconds(60), 60, 0.05);
+        let events = run(&mut detector, start + Duration::seconds(120), 200, 0.0);
+
+        match &events[..] {
+            [ImuEvent::TripEnd { trip }] => {
+                assert_eq!(start, trip.start);
+                assert!((trip.duration - 120.0).abs() < 1.5);
+                assert!((trip.max_vibration - 0.2).abs() < 0.01);
+                assert!(trip.rough_seconds > 20.0 && trip.rough_seconds < 40.0);
+            }
+            e => panic!("{:?}", e),
+        }
+        assert!(!detector.driving());
+        assert_eq!(1, detector.trips().history.len());
+    }
+}
@@ -0,0 +1,348 @@
+//! Vibration spectrum analysis
+//!
+//! The dynamic acceleration of each full-rate sample is split into segments
+//! that are Hann windowed and transformed.  The power of each axis within a
+//! band is summed, averaged over the segments of each wall clock minute and
+//! reported as the RMS acceleration in that band, so that body roll, road
+//! input and structural resonances can be compared across minutes and trips.
+
+use super::ImuSample;
+use crate::hardware::config;
+use crate::state;
+use anyhow::{Error, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use nalgebra as na;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Default samples per transform
+const DEFAULT_FFT_SIZE: usize = 512;
+
+/// Number of minute summaries kept, a day's worth
+const HISTORY: usize = 1440;
+
+/// Number of minute summaries between saves of the history
+const SAVE_INTERVAL: u32 = 10;
+
+/// RMS acceleration within a frequency band
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BandPower {
+    pub name: String,
+
+    /// Lower edge, in Hz
+    pub low: f32,
+
+    /// Upper edge, in Hz
+    pub high: f32,
+
+    /// RMS acceleration within the band, in g
+    pub rms: f32,
+}
+
+/// Band powers over one minute
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Vibration {
+    /// Start of the minute
+    pub start: DateTime<Utc>,
+
+    /// Whether the vehicle was driving at the end of the minute
+    pub driving: bool,
+
+    /// Number of transforms averaged
+    pub segments: u32,
+
+    pub bands: Vec<BandPower>,
+}
+
+/// Minute summaries, oldest first
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct History {
+    pub minutes: VecDeque<Vibration>,
+}
+
+pub struct VibrationAnalyzer {
+    /// Persistent state name of the history
+    state: String,
+
+    bands: Vec<config::Band>,
+    sample_rate: f32,
+
+    fft: Arc<dyn Fft<f32>>,
+    hann: Vec<f32>,
+
+    /// Dynamic acceleration of the segment being collected
+    segment: Vec<na::Vector3<f32>>,
+
+    /// Minute being summarized, and its summed band powers in g^2
+    minute: Option<i64>,
+    power: Vec<f32>,
+    segments: u32,
+
+    history: History,
+
+    /// Summaries added since the history was saved
+    unsaved: u32,
+}
+
+impl VibrationAnalyzer {
+    pub fn new(name: &str, config: &config::Vibration, sample_rate: f32) -> Result<Self> {
+        let state = format!("imu-{}-vibration", name);
+
+        let history = state::load(&state).unwrap_or_else(|e| {
+            log::error!("IMU {}: vibration: {}", name, e);
+            None
+        });
+
+        Self::with_history(state, config, sample_rate, history.unwrap_or_default())
+    }
+
+    fn with_history(
+        state: String,
+        config: &config::Vibration,
+        sample_rate: f32,
+        history: History,
+    ) -> Result<Self> {
+        let fft_size = config.fft_size.unwrap_or(DEFAULT_FFT_SIZE);
+        if !fft_size.is_power_of_two() || fft_size < 16 {
+            return Err(Error::msg(format!(
+                "FFT size {} is not a power of two of at least 16",
+                fft_size
+            )));
+        }
+
+        let bands = config.bands.clone().unwrap_or_else(default_bands);
+        if let Some(band) = bands.iter().find(|b| b.low < 0.0 || b.high <= b.low) {
+            return Err(Error::msg(format!("band {} is empty", band.name)));
+        }
+
+        let hann = (0..fft_size)
+            .map(|i| (PI * i as f32 / fft_size as f32).sin().powi(2))
+            .collect();
+
+        Ok(Self {
+            state,
+            power: vec![0.0; bands.len()],
+            bands,
+            sample_rate,
+            fft: FftPlanner::new().plan_fft_forward(fft_size),
+            hann,
+            segment: Vec::with_capacity(fft_size),
+            minute: None,
+            segments: 0,
+            history,
+            unsaved: 0,
+        })
+    }
+
+    /// Set the actual output data rate, in Hz, discarding partial results
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.segment.clear();
+        self.minute = None;
+        self.power.iter_mut().for_each(|p| *p = 0.0);
+        self.segments = 0;
+    }
+
+    pub fn history(&self) -> History {
+        self.history.clone()
+    }
+
+    /// Copy of the history to save, once enough summaries have been added
+    pub fn unsaved(&mut self) -> Option<state::Snapshot<History>> {
+        if self.unsaved < SAVE_INTERVAL {
+            return None;
+        }
+
+        self.unsaved = 0;
+        Some(state::Snapshot::new(&self.state, self.history.clone()))
+    }
+
+    /// Add a calibrated sample, given the direction of up in the sensor frame
+    /// and whether the vehicle is driving, returning the summary of each
+    /// minute as it completes
+    pub fn sample(
+        &mut self,
+        sample: &ImuSample,
+        up: &na::Vector3<f32>,
+        driving: bool,
+    ) -> Option<Vibration> {
+        let minute = sample.timestamp.timestamp().div_euclid(60);
+        let mut summary = None;
+
+        if let Some(current) = self.minute {
+            if current != minute {
+                summary = self.summarize(current, driving);
+            }
+        }
+        self.minute = Some(minute);
+
+        self.segment.push(sample.accelerometer - up);
+        if self.segment.len() == self.hann.len() {
+            self.transform();
+            self.segment.clear();
+        }
+
+        summary
+    }
+
+    /// Add the band powers of the collected segment
+    fn transform(&mut self) {
+        let n = self.hann.len();
+        let resolution = self.sample_rate / n as f32;
+
+        // one-sided power with the window's energy removed
+        let scale = 2.0 / (n as f32 * self.hann.iter().map(|w| w * w).sum::<f32>());
+
+        let mean = self.segment.iter().sum::<na::Vector3<f32>>() / n as f32;
+        let mut spectrum = vec![0.0; n / 2];
+
+        for axis in 0..3 {
+            let mut buffer: Vec<Complex<f32>> = self
+                .segment
+                .iter()
+                .zip(&self.hann)
+                .map(|(a, w)| Complex::new((a[axis] - mean[axis]) * w, 0.0))
+                .collect();
+            self.fft.process(&mut buffer);
+
+            for (power, bin) in spectrum.iter_mut().zip(&buffer).skip(1) {
+                *power += bin.norm_sqr() * scale;
+            }
+        }
+
+        for (band, power) in self.bands.iter().zip(&mut self.power) {
+            *power += spectrum
+                .iter()
+                .enumerate()
+                .filter(|(k, _)| {
+                    let frequency = *k as f32 * resolution;
+                    frequency >= band.low && frequency < band.high
+                })
+                .map(|(_, p)| p)
+                .sum::<f32>();
+        }
+
+        self.segments += 1;
+    }
+
+    /// Complete the summary of `minute`, if it saw any full segments
+    fn summarize(&mut self, minute: i64, driving: bool) -> Option<Vibration> {
+        let segments = std::mem::take(&mut self.segments);
+        let power = std::mem::replace(&mut self.power, vec![0.0; self.bands.len()]);
+
+        if segments == 0 {
+            return None;
+        }
+
+        let summary = Vibration {
+            start: Utc.timestamp(minute * 60, 0),
+            driving,
+            segments,
+            bands: self
+                .bands
+                .iter()
+                .zip(power)
+                .map(|(band, power)| BandPower {
+                    name: band.name.clone(),
+                    low: band.low,
+                    high: band.high,
+                    rms: (power / segments as f32).sqrt(),
+                })
+                .collect(),
+        };
+
+        let minutes = &mut self.history.minutes;
+        if minutes.len() == HISTORY {
+            minutes.pop_front();
+        }
+        minutes.push_back(summary.clone());
+        self.unsaved += 1;
+
+        Some(summary)
+    }
+}
+
+fn default_bands() -> Vec<config::Band> {
+    [
+        ("body", 1.0, 5.0),
+        ("road", 10.0, 30.0),
+        ("structure", 30.0, 90.0),
+    ]
+    .iter()
+    .map(|(name, low, high)| config::Band {
+        name: name.to_string(),
+        low: *low,
+        high: *high,
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::VibrationAnalyzer;
+    use crate::hardware::config;
+    use crate::hardware::imu::ImuSample;
+    use chrono::{Duration, TimeZone, Utc};
+    use nalgebra as na;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn band_power() {
+        let rate = 200.0;
+        let mut analyzer = VibrationAnalyzer::with_history(
+            String::new(),
+            &config::Vibration::default(),
+            rate,
+            Default::default(),
+        )
+        .unwrap();
+
+        let start = Utc.ymd(2021, 6, 1).and_hms(12, 0, 0);
+        let up = na::Vector3::z();
+        let mut summaries = Vec::new();
+
+        // a minute of 3Hz body roll at 0.1g and 20Hz road vibration at 0.02g
+        for i in 0..(60 * rate as i64 + 1) {
+            let t = i as f32 / rate;
+            let x = 0.1 * (2.0 * PI * 3.0 * t).sin();
+            let z = 0.02 * (2.0 * PI * 20.0 * t).sin();
+
+            let sample = ImuSample {
+                timestamp: start + Duration::milliseconds(i * 5),
+                gyrometer: na::Vector3::zeros(),
+                accelerometer: na::Vector3::new(x, 0.0, 1.0 + z),
+                magnetometer: None,
+            };
+
+            summaries.extend(analyzer.sample(&sample, &up, true));
+        }
+
+        assert_eq!(1, summaries.len());
+        let summary = &summaries[0];
+        assert_eq!(start, summary.start);
+        assert_eq!(23, summary.segments);
+
+        // a sine's RMS is its amplitude over root two
+        let rms: Vec<f32> = summary.bands.iter().map(|b| b.rms).collect();
+        assert!((rms[0] - 0.1 / 2f32.sqrt()).abs() < 0.002);
+        assert!((rms[1] - 0.02 / 2f32.sqrt()).abs() < 0.001);
+        assert!(rms[2] < 0.001);
+    }
+
+    #[test]
+    fn invalid_config() {
+        let config = config::Vibration {
+            fft_size: Some(500),
+            bands: None,
+        };
+
+        assert!(
+            VibrationAnalyzer::with_history(String::new(), &config, 200.0, Default::default())
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,261 @@
+//! Sun position and solar panel aiming
+//!
+//! The sun position follows the NOAA solar calculator, which is accurate to
+//! well under a degree for dates near the present.  Panel aiming compares the
+//! sunlight falling on the panels at the vehicle's current attitude against
+//! the best available by parking at another heading or tilting the panels.
+
+use crate::hardware::config;
+use crate::hardware::imu::compass::Heading;
+use chrono::{DateTime, Timelike, Utc};
+use nalgebra as na;
+use serde::{Deserialize, Serialize};
+
+/// Sunlight fraction below which the panel power is too small to scale from
+const MIN_INCIDENCE: f32 = 0.1;
+
+/// Position of the sun in the sky
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct Sun {
+    /// Degrees clockwise from true north
+    pub azimuth: f32,
+
+    /// Degrees above the horizon
+    pub elevation: f32,
+}
+
+impl Sun {
+    /// Position of the sun at `time` from a site at `latitude` north and
+    /// `longitude` east, in degrees
+    pub fn at(time: DateTime<Utc>, latitude: f32, longitude: f32) -> Self {
+        let julian_day = time.timestamp() as f64 / 86400.0 + 2440587.5;
+        let t = (julian_day - 2451545.0) / 36525.0;
+
+        let mean_longitude = (280.46646 + t * (36000.76983 + t * 0.0003032)).rem_euclid(360.0);
+        let mean_anomaly = (357.52911 + t * (35999.05029 - 0.0001537 * t)).to_radians();
+        let eccentricity = 0.016708634 - t * (0.000042037 + 0.0000001267 * t);
+
+        let center = mean_anomaly.sin() * (1.914602 - t * (0.004817 + 0.000014 * t))
+            + (2.0 * mean_anomaly).sin() * (0.019993 - 0.000101 * t)
+            + (3.0 * mean_anomaly).sin() * 0.000289;
+
+        let omega = (125.04 - 1934.136 * t).to_radians();
+        let apparent_longitude =
+            (mean_longitude + center - 0.00569 - 0.00478 * omega.sin()).to_radians();
+
+        let mean_obliquity =
+            23.0 + (26.0 + (21.448 - t * (46.815 + t * (0.00059 - t * 0.001813))) / 60.0) / 60.0;
+        let obliquity = (mean_obliquity + 0.00256 * omega.cos()).to_radians();
+
+        let declination = (obliquity.sin() * apparent_longitude.sin()).asin();
+
+        // equation of time, in minutes
+        let y = (obliquity / 2.0).tan().powi(2);
+        let l0 = mean_longitude.to_radians();
+        let equation_of_time = 4.0
+            * (y * (2.0 * l0).sin() - 2.0 * eccentricity * mean_anomaly.sin()
+                + 4.0 * eccentricity * y * mean_anomaly.sin() * (2.0 * l0).cos()
+                - 0.5 * y * y * (4.0 * l0).sin()
+                - 1.25 * eccentricity * eccentricity * (2.0 * mean_anomaly).sin())
+            .to_degrees();
+
+        let minutes = time.num_seconds_from_midnight() as f64 / 60.0;
+        let solar_time = minutes + equation_of_time + 4.0 * longitude as f64;
+        let hour_angle = (solar_time / 4.0 - 180.0).to_radians();
+
+        let latitude = (latitude as f64).to_radians();
+        let zenith = (latitude.sin() * declination.sin()
+            + latitude.cos() * declination.cos() * hour_angle.cos())
+        .clamp(-1.0, 1.0)
+        .acos();
+
+        // measured from south towards west, then turned to be from north
+        let azimuth = hour_angle
+            .sin()
+            .atan2(hour_angle.cos() * latitude.sin() - declination.tan() * latitude.cos());
+
+        Self {
+            azimuth: (azimuth.to_degrees() + 180.0).rem_euclid(360.0) as f32,
+            elevation: 90.0 - zenith.to_degrees() as f32,
+        }
+    }
+
+    /// Unit vector towards the sun in the true north-west-up frame
+    fn direction(&self) -> na::Vector3<f32> {
+        let (azimuth, elevation) = (self.azimuth.to_radians(), self.elevation.to_radians());
+
+        na::Vector3::new(
+            elevation.cos() * azimuth.cos(),
+            -elevation.cos() * azimuth.sin(),
+            elevation.sin(),
+        )
+    }
+}
+
+/// Recommendation for getting more sun on the panels
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Aiming {
+    pub timestamp: DateTime<Utc>,
+    pub sun: Sun,
+
+    /// Current vehicle heading, in degrees clockwise from true north
+    pub heading: f32,
+
+    /// Fraction of full sun on the panels at the current attitude
+    pub incidence: f32,
+
+    /// Heading to park at for the most sun with the panels as mounted, in
+    /// degrees clockwise from true north
+    pub park_heading: f32,
+
+    /// Fraction of full sun on the panels when parked at `park_heading`
+    pub park_incidence: f32,
+
+    /// Panel tilt facing the sun, in degrees from the roof
+    pub tilt: f32,
+
+    /// Direction to tilt the panels, in degrees clockwise from vehicle forward
+    pub tilt_azimuth: f32,
+
+    /// Panel power from all charge controllers, in watts
+    pub panel_power: Option<f32>,
+
+    /// Estimated panel power when parked at `park_heading`, in watts
+    pub park_power: Option<f32>,
+
+    /// Estimated panel power with the panels tilted to face the sun, in watts
+    pub tilt_power: Option<f32>,
+}
+
+impl Aiming {
+    pub fn new(
+        config: &config::Solar,
+        timestamp: DateTime<Utc>,
+        heading: &Heading,
+        panel_power: Option<f32>,
+    ) -> Self {
+        let sun = Sun::at(timestamp, config.latitude, config.longitude);
+        let panel = panel_normal(config);
+        let towards = sun.direction();
+
+        let incidence = sunlight(&(heading.attitude * panel), &towards);
+
+        // the heading only matters through the panel tilt, so assume a level vehicle
+        let (park_heading, park_incidence) = (0..360)
+            .map(|degrees| {
+                let attitude = na::UnitQuaternion::from_axis_angle(
+                    &na::Vector3::z_axis(),
+                    -(degrees as f32).to_radians(),
+                );
+                (degrees as f32, sunlight(&(attitude * panel), &towards))
+            })
+            .fold((0.0, f32::MIN), |best, candidate| {
+                if candidate.1 > best.1 {
+                    candidate
+                } else {
+                    best
+                }
+            });
+
+        let (tilt, tilt_incidence) = if sun.elevation > 0.0 {
+            (90.0 - sun.elevation, 1.0)
+        } else {
+            (0.0, 0.0)
+        };
+
+        // scale from the current power while enough sun falls on the panels to measure
+        let scale = |target: f32| match panel_power {
+            Some(power) if incidence >= MIN_INCIDENCE => Some(power * target / incidence),
+            _ => None,
+        };
+
+        Self {
+            timestamp,
+            sun,
+            heading: heading.true_north,
+            incidence,
+            park_heading,
+            park_incidence,
+            tilt,
+            tilt_azimuth: (sun.azimuth - heading.true_north).rem_euclid(360.0),
+            panel_power,
+            park_power: scale(park_incidence),
+            tilt_power: scale(tilt_incidence),
+        }
+    }
+}
+
+/// Unit normal of the panels in the vehicle frame
+fn panel_normal(config: &config::Solar) -> na::Vector3<f32> {
+    let tilt = config.tilt.unwrap_or(0.0).to_radians();
+    let azimuth = config.azimuth.unwrap_or(0.0).to_radians();
+
+    // clockwise from forward is towards the right, which is -y
+    na::Vector3::new(
+        tilt.sin() * azimuth.cos(),
+        -tilt.sin() * azimuth.sin(),
+        tilt.cos(),
+    )
+}
+
+/// Fraction of full sun on a surface with normal `panel`, with sun towards `sun`
+fn sunlight(panel: &na::Vector3<f32>, sun: &na::Vector3<f32>) -> f32 {
+    if sun.z <= 0.0 {
+        0.0
+    } else {
+        panel.dot(sun).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Aiming, Sun};
+    use crate::hardware::config;
+    use crate::hardware::imu::compass::Heading;
+    use chrono::{TimeZone, Utc};
+    use nalgebra as na;
+
+    #[test]
+    fn solstice_noon() {
+        // Greenwich at solar noon on the June solstice
+        let sun = Sun::at(Utc.ymd(2021, 6, 21).and_hms(12, 2, 0), 51.48, 0.0);
+
+        assert!((sun.elevation - (90.0 - 51.48 + 23.44)).abs() < 0.2);
+        assert!((sun.azimuth - 180.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn morning() {
+        // equator at the March equinox, 6 hours before noon the sun rises due east
+        let sun = Sun::at(Utc.ymd(2021, 3, 20).and_hms(6, 7, 0), 0.0, 0.0);
+
+        assert!(sun.elevation.abs() < 1.0);
+        assert!((sun.azimuth - 90.0).abs() < 1.0);
+    }
+
+    #[test]
+    fn park_to_face_the_sun() {
+        // panels tilted 30 degrees towards the right of the vehicle, parked facing north
+        let config = config::Solar {
+            imu: String::new(),
+            latitude: 51.48,
+            longitude: 0.0,
+            tilt: Some(30.0),
+            azimuth: Some(90.0),
+        };
+        let heading = Heading {
+            magnetic: 0.0,
+            true_north: 0.0,
+            attitude: na::UnitQuaternion::identity(),
+        };
+
+        let time = Utc.ymd(2021, 6, 21).and_hms(12, 2, 0);
+        let aiming = Aiming::new(&config, time, &heading, Some(100.0));
+
+        // facing east puts the panels towards the southern sun
+        assert!((aiming.park_heading - 90.0).abs() <= 2.0);
+        assert!(aiming.park_incidence > aiming.incidence);
+        assert!(aiming.park_power.unwrap() > 100.0);
+        assert!((aiming.tilt_azimuth - 180.0).abs() < 1.0);
+    }
+}
@@ -0,0 +1,63 @@
+//! Capture times of frames
+//!
+//! Each frame is stamped when its first byte arrives, with both the wall clock
+//! time and a monotonic time.  The wall clock is for display and storage, and
+//! may step when it is set from NTP or GPS; the monotonic time never goes
+//! backwards, so it is the one to use for intervals and rates between frames.
+
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::{Serialize, Serializer};
+use std::time::{Duration, Instant};
+
+/// Origin of monotonic times, when the process started
+static EPOCH: Lazy<Instant> = Lazy::new(Instant::now);
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize)]
+pub struct Timestamp {
+    /// Wall clock time
+    pub utc: DateTime<Utc>,
+
+    /// Monotonic time since the process started, in seconds
+    #[serde(serialize_with = "seconds")]
+    pub monotonic: Duration,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        // the epoch is taken first, so the first timestamp is not before it
+        let epoch = *EPOCH;
+
+        Self {
+            utc: Utc::now(),
+            monotonic: epoch.elapsed(),
+        }
+    }
+}
+
+fn seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod test {
+    use super::Timestamp;
+    use serde_json::json;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn now() {
+        let first = Timestamp::now();
+        sleep(Duration::from_millis(5));
+        let second = Timestamp::now();
+
+        // sub-second resolution, unlike seconds since 1970 as f32
+        assert!(second.monotonic - first.monotonic >= Duration::from_millis(5));
+        assert!(second.utc > first.utc);
+
+        let value = serde_json::to_value(second).unwrap();
+        assert_eq!(json!(second.monotonic.as_secs_f64()), value["monotonic"]);
+        assert!(value["utc"].as_str().unwrap().contains('.'));
+    }
+}
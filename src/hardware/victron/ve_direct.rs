@@ -1,12 +1,21 @@
 //! Victron VE-Direct interface
-use crate::hardware::device::Device;
-use anyhow::Result;
+use crate::hardware::config;
+use crate::hardware::device::{self, Device, Driver, Health, Metadata, Monitor};
+use crate::hardware::timestamp::Timestamp;
+use crate::telemetry::unit::{Amperes, Unit, Volts, WattHours, Watts};
+use crate::telemetry::{self, Signals, Spec, Value};
+use anyhow::{Error, Result};
 use bytes::{Buf, BytesMut};
+use chrono::Utc;
 use serde::Serialize;
 use serial_io::{build, AsyncSerial};
+use std::any::Any;
+use std::future::Future;
 use std::num::Wrapping;
+use std::pin::Pin;
 use std::str;
 use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
 use tokio::time::{sleep, Duration};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{Decoder, FramedRead};
@@ -16,68 +25,141 @@ pub struct VeDirectMppt {
     loopback: bool,
     name: String,
     port: String,
-    pub telemetry: Mutex<MpptFrame>,
+
+    #[serde(skip)]
+    monitor: Mutex<Monitor>,
+
+    /// Latest frame, also published to the telemetry bus
+    #[serde(rename = "telemetry", serialize_with = "device::serialize_latest")]
+    frames: watch::Sender<MpptFrame>,
 }
 
 impl Device for VeDirectMppt {
-    fn device(name: &str, path: &str) -> Arc<VeDirectMppt> {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            name: self.name.clone(),
+            kind: Self::KIND.to_owned(),
+            port: if self.loopback {
+                None
+            } else {
+                Some(self.port.clone())
+            },
+            commands: Vec::new(),
+        }
+    }
+
+    fn health(&self) -> Health {
+        let monitor = self.monitor.lock().unwrap();
+        monitor.health(Utc::now(), telemetry::bus().stale_after())
+    }
+
+    fn snapshot(&self) -> &dyn erased_serde::Serialize {
+        self
+    }
+
+    fn run(&self) -> Pin<Box<dyn Future<Output = Result<()>> + Send + '_>> {
+        Box::pin(VeDirectMppt::run(self))
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Driver for VeDirectMppt {
+    const KIND: &'static str = "mppt";
+
+    type Config = config::Mppt;
+
+    fn port(config: &config::Mppt) -> Option<&str> {
+        config.port.as_deref()
+    }
+
+    fn device(name: &str, path: &str, _config: &config::Mppt) -> Arc<VeDirectMppt> {
         Arc::new(VeDirectMppt {
             loopback: false,
             name: name.to_owned(),
             port: path.to_owned(),
-            telemetry: Mutex::default(),
+            monitor: Mutex::new(Monitor::new(false)),
+            frames: watch::channel(MpptFrame::default()).0,
         })
     }
 
-    fn loopback(name: &str) -> Arc<VeDirectMppt> {
+    fn loopback(name: &str, _config: &config::Mppt) -> Arc<VeDirectMppt> {
         Arc::new(VeDirectMppt {
             loopback: true,
             name: name.to_owned(),
             port: String::new(),
-            telemetry: Mutex::default(),
+            monitor: Mutex::new(Monitor::new(true)),
+            frames: watch::channel(MpptFrame::default()).0,
         })
     }
 }
 
 impl VeDirectMppt {
-    pub async fn run(&self) -> Result<()> {
+    async fn run(&self) -> Result<()> {
+        telemetry::bus().describe::<MpptFrame>(&self.name);
+
         if self.loopback {
             loop {
                 log::debug!("VeDirectMppt {} is in loopback mode.", self.name);
                 sleep(Duration::from_secs(600)).await;
             }
         } else {
-            let builder = build(self.port.as_str(), 19200);
-            let serial = AsyncSerial::from_builder(&builder)?;
+            loop {
+                if let Err(e) = self.read().await {
+                    log::error!("VeDirectMppt {}: {}", self.name, e);
+                    self.monitor.lock().unwrap().disconnected(Utc::now(), &e);
+                }
+
+                sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
 
-            let decoder = VeDirectMpptDecoder::default();
-            let mut frame_reader = FramedRead::new(serial, decoder);
+    /// Open the serial port and publish its frames, until it fails or closes
+    async fn read(&self) -> Result<()> {
+        let builder = build(self.port.as_str(), 19200);
 
-            while let Some(result) = frame_reader.next().await {
-                match result {
-                    Ok(frame) => {
-                        log::info!("{}: {}", self.name, frame);
-                        *self.telemetry.lock().unwrap() = frame;
-                    }
-                    Err(e) => {
-                        log::error!("error: {}", e);
-                    }
+        // serial-io panics on a port it can't open, so check it opens first
+        drop(builder.clone().open()?);
+        let serial = AsyncSerial::from_builder(&builder)?;
+
+        let decoder = VeDirectMpptDecoder::default();
+        let mut frame_reader = FramedRead::new(serial, decoder);
+
+        while let Some(result) = frame_reader.next().await {
+            match result {
+                Ok(frame) => {
+                    log::info!("{}: {}", self.name, frame);
+                    let timestamp = frame.timestamp.map_or_else(Utc::now, |t| t.utc);
+                    telemetry::bus().publish(&self.name, timestamp, &frame);
+                    self.frames.send_replace(frame);
+                    self.monitor.lock().unwrap().frame(timestamp);
+                }
+                Err(e) => {
+                    log::error!("error: {}", e);
+                    self.monitor.lock().unwrap().error(Utc::now(), &e);
                 }
             }
         }
 
-        Ok(())
+        Err(Error::msg("serial port closed"))
     }
 }
 
 pub struct VeDirectMpptDecoder {
     state: State,
+
+    /// Arrival of the first byte of the frame being decoded
+    started: Option<Timestamp>,
 }
 
 impl Default for VeDirectMpptDecoder {
     fn default() -> Self {
         Self {
             state: State::Unsynchronized,
+            started: None,
         }
     }
 }
@@ -169,22 +251,23 @@ enum State {
 
 #[derive(Default, Clone, Debug, Serialize)]
 pub struct MpptFrame {
-    timestamp: Option<f32>,
+    /// Arrival of the first byte of the frame
+    timestamp: Option<Timestamp>,
 
-    /// V: Battery voltage (mV)
-    battery_voltage: Option<f32>,
+    /// V: Battery voltage, reported in mV
+    battery_voltage: Option<Volts>,
 
-    /// VPV: Panel voltage (mV)
-    panel_voltage: Option<f32>,
+    /// VPV: Panel voltage, reported in mV
+    panel_voltage: Option<Volts>,
 
-    /// PPV: Panel power (W)
-    panel_power: Option<u16>,
+    /// PPV: Panel power
+    panel_power: Option<Watts>,
 
-    /// I: Battery current (A): >0 charging, <0 discharging
-    battery_current: Option<f32>,
+    /// I: Battery current, reported in mA: >0 charging, <0 discharging
+    battery_current: Option<Amperes>,
 
-    /// IL: Load current (A)
-    load_current: Option<f32>,
+    /// IL: Load current, reported in mA
+    load_current: Option<Amperes>,
 
     /// LOAD: Load status
     load_state: Option<bool>,
@@ -195,20 +278,20 @@ pub struct MpptFrame {
     /// OR: Off reason
     off_reason: Option<OffReason>,
 
-    /// H19: Yield total (W)
-    yield_total: Option<u32>,
+    /// H19: Yield total, reported in 0.01 kWh
+    yield_total: Option<WattHours>,
 
-    /// H20: Yield today (W)
-    yield_today: Option<u16>,
+    /// H20: Yield today, reported in 0.01 kWh
+    yield_today: Option<WattHours>,
 
-    /// H21: Maximum power today (W)
-    maximum_power_today: Option<u16>,
+    /// H21: Maximum power today
+    maximum_power_today: Option<Watts>,
 
-    /// H22: Yield yesterday (W)
-    yield_yesterday: Option<u16>,
+    /// H22: Yield yesterday, reported in 0.01 kWh
+    yield_yesterday: Option<WattHours>,
 
-    /// H23: Maximum power yesterday (W)
-    maximum_power_yesterday: Option<u16>,
+    /// H23: Maximum power yesterday
+    maximum_power_yesterday: Option<Watts>,
 
     /// ERR: Error code
     error: Option<ErrorCode>,
@@ -233,6 +316,108 @@ pub struct MpptFrame {
     mppt_status: Option<Mppt>,
 }
 
+impl Signals for MpptFrame {
+    const SPECS: &'static [Spec] = &[
+        Spec::new("battery_voltage", Unit::Volt, "Battery voltage")
+            .scale(0.001)
+            .min(0.0),
+        Spec::new("panel_voltage", Unit::Volt, "Panel voltage")
+            .scale(0.001)
+            .min(0.0),
+        Spec::new("panel_power", Unit::Watt, "Panel power").min(0.0),
+        Spec::new(
+            "battery_current",
+            Unit::Ampere,
+            "Battery current, positive when charging",
+        )
+        .scale(0.001),
+        Spec::new("load_current", Unit::Ampere, "Load output current").scale(0.001),
+        Spec::new("load_state", Unit::None, "Whether the load output is on"),
+        Spec::new("relay_state", Unit::None, "Whether the relay is closed"),
+        Spec::new("off_reason", Unit::None, "Reasons the charger is off"),
+        Spec::new("yield_total", Unit::WattHour, "Energy yield since reset")
+            .scale(10.0)
+            .min(0.0),
+        Spec::new("yield_today", Unit::WattHour, "Energy yield today")
+            .scale(10.0)
+            .min(0.0),
+        Spec::new(
+            "maximum_power_today",
+            Unit::Watt,
+            "Maximum panel power today",
+        )
+        .min(0.0),
+        Spec::new("yield_yesterday", Unit::WattHour, "Energy yield yesterday")
+            .scale(10.0)
+            .min(0.0),
+        Spec::new(
+            "maximum_power_yesterday",
+            Unit::Watt,
+            "Maximum panel power yesterday",
+        )
+        .min(0.0),
+        Spec::new("error", Unit::None, "Charger error"),
+        Spec::new("state", Unit::None, "State of operation"),
+        Spec::new("firmware_version", Unit::None, "Firmware version"),
+        Spec::new("product_id", Unit::None, "Product ID"),
+        Spec::new("serial_number", Unit::None, "Serial number"),
+        Spec::new(
+            "day_number",
+            Unit::None,
+            "Day sequence number of the history",
+        )
+        .range(0.0, 364.0),
+        Spec::new("mppt_status", Unit::None, "Tracker operation"),
+    ];
+
+    fn signals(&self) -> Vec<(String, Value)> {
+        let mut signals = Vec::new();
+        let mut add = |name: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                signals.push((name.to_owned(), value));
+            }
+        };
+
+        add("battery_voltage", self.battery_voltage.map(Value::from));
+        add("panel_voltage", self.panel_voltage.map(Value::from));
+        add("panel_power", self.panel_power.map(Value::from));
+        add("battery_current", self.battery_current.map(Value::from));
+        add("load_current", self.load_current.map(Value::from));
+        add("load_state", self.load_state.map(Value::from));
+        add("relay_state", self.relay_state.map(Value::from));
+        add(
+            "off_reason",
+            self.off_reason.map(|v| Value::from(format!("{:?}", v))),
+        );
+        add("yield_total", self.yield_total.map(Value::from));
+        add("yield_today", self.yield_today.map(Value::from));
+        add(
+            "maximum_power_today",
+            self.maximum_power_today.map(Value::from),
+        );
+        add("yield_yesterday", self.yield_yesterday.map(Value::from));
+        add(
+            "maximum_power_yesterday",
+            self.maximum_power_yesterday.map(Value::from),
+        );
+        add("error", self.error.map(|v| Value::from(format!("{:?}", v))));
+        add("state", self.state.map(|v| Value::from(format!("{:?}", v))));
+        add(
+            "firmware_version",
+            self.firmware_version.clone().map(Value::from),
+        );
+        add("product_id", self.product_id.map(Value::from));
+        add("serial_number", self.serial_number.clone().map(Value::from));
+        add("day_number", self.day_number.map(Value::from));
+        add(
+            "mppt_status",
+            self.mppt_status.map(|v| Value::from(format!("{:?}", v))),
+        );
+
+        signals
+    }
+}
+
 impl std::fmt::Display for MpptFrame {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -259,6 +444,10 @@ impl Decoder for VeDirectMpptDecoder {
     }
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if self.started.is_none() && !src.is_empty() {
+            self.started = Some(Timestamp::now());
+        }
+
         let mut cursor = Cursor::new(src);
         let mut name = String::new();
         let mut frame = MpptFrame::default();
@@ -270,10 +459,19 @@ impl Decoder for VeDirectMpptDecoder {
                 State::Unsynchronized => {
                     if cursor.read_until(b"\r\n").is_none() {
                         cursor.consume_to_point();
+                        self.started = None;
                         return Ok(None);
                     };
 
+                    // the frame starts at the last line break, as a hex protocol message
+                    // ends with its own
+                    while cursor.bytes.get(cursor.point + 2..cursor.point + 4) == Some(b"\r\n") {
+                        cursor.point += 2;
+                    }
+
+                    cursor.consume_to_point();
                     cursor.clear_checksum();
+                    frame = MpptFrame::default();
 
                     self.state = State::Crlf;
                 }
@@ -318,41 +516,58 @@ impl Decoder for VeDirectMpptDecoder {
                     }
                 }
 
+                // the checksum is a single byte of any value, and may be followed by a
+                // hex protocol message rather than the line break of the next frame
+                State::Value if name == "Checksum" => {
+                    if cursor.byte().is_none() {
+                        break Ok(None);
+                    }
+
+                    // resynchronize on the next line break
+                    self.state = State::Unsynchronized;
+
+                    if cursor.is_checksum_valid() {
+                        cursor.consume_to_point();
+                        frame.timestamp = self.started.take();
+                        break Ok(Some(frame));
+                    }
+                }
+
                 State::Value => {
                     if let Some(value) = cursor.read_until(b"\r\n") {
                         match name.as_str() {
                             "V" => {
                                 if let Ok(value_str) = str::from_utf8(&value) {
                                     if let Ok(v) = u32::from_str_radix(&value_str, 10) {
-                                        frame.battery_voltage = Some(v as f32 / 1000.0);
+                                        frame.battery_voltage = Some(Volts(v as f32 / 1000.0));
                                     }
                                 }
                             }
                             "VPV" => {
                                 if let Ok(value_str) = str::from_utf8(&value) {
                                     if let Ok(v) = u32::from_str_radix(&value_str, 10) {
-                                        frame.panel_voltage = Some(v as f32 / 1000.0);
+                                        frame.panel_voltage = Some(Volts(v as f32 / 1000.0));
                                     }
                                 }
                             }
                             "PPV" => {
                                 if let Ok(value_str) = str::from_utf8(&value) {
                                     if let Ok(v) = u16::from_str_radix(&value_str, 10) {
-                                        frame.panel_power = Some(v);
+                                        frame.panel_power = Some(Watts(v.into()));
                                     }
                                 }
                             }
                             "I" => {
                                 if let Ok(value_str) = str::from_utf8(&value) {
                                     if let Ok(v) = i32::from_str_radix(&value_str, 10) {
-                                        frame.battery_current = Some(v as f32 / 1000.0);
+                                        frame.battery_current = Some(Amperes(v as f32 / 1000.0));
                                     }
                                 }
                             }
                             "IL" => {
                                 if let Ok(value_str) = str::from_utf8(&value) {
                                     if let Ok(v) = i32::from_str_radix(&value_str, 10) {
-                                        frame.load_current = Some(v as f32 / 1000.0);
+                                        frame.load_current = Some(Amperes(v as f32 / 1000.0));
                                     }
                                 }
                             }
@@ -386,35 +601,36 @@ impl Decoder for VeDirectMpptDecoder {
                             "H19" => {
                                 if let Ok(value_str) = str::from_utf8(&value) {
                                     if let Ok(v) = u32::from_str_radix(&value_str, 10) {
-                                        frame.yield_total = Some(v * 10);
+                                        frame.yield_total = Some(WattHours(v as f32 * 10.0));
                                     }
                                 }
                             }
                             "H20" => {
                                 if let Ok(value_str) = str::from_utf8(&value) {
                                     if let Ok(v) = u16::from_str_radix(&value_str, 10) {
-                                        frame.yield_today = Some(v * 10);
+                                        frame.yield_today = Some(WattHours(f32::from(v) * 10.0));
                                     }
                                 }
                             }
                             "H21" => {
                                 if let Ok(value_str) = str::from_utf8(&value) {
                                     if let Ok(v) = u16::from_str_radix(&value_str, 10) {
-                                        frame.maximum_power_today = Some(v);
+                                        frame.maximum_power_today = Some(Watts(v.into()));
                                     }
                                 }
                             }
                             "H22" => {
                                 if let Ok(value_str) = str::from_utf8(&value) {
                                     if let Ok(v) = u16::from_str_radix(&value_str, 10) {
-                                        frame.yield_yesterday = Some(v * 10);
+                                        frame.yield_yesterday =
+                                            Some(WattHours(f32::from(v) * 10.0));
                                     }
                                 }
                             }
                             "H23" => {
                                 if let Ok(value_str) = str::from_utf8(&value) {
                                     if let Ok(v) = u16::from_str_radix(&value_str, 10) {
-                                        frame.maximum_power_yesterday = Some(v);
+                                        frame.maximum_power_yesterday = Some(Watts(v.into()));
                                     }
                                 }
                             }
@@ -469,17 +685,6 @@ impl Decoder for VeDirectMpptDecoder {
                                     }
                                 }
                             }
-                            "Checksum" => {
-                                if cursor.is_checksum_valid() {
-                                    self.state = State::Crlf;
-                                    cursor.consume_to_point();
-                                    frame.timestamp = Some(crate::hardware::timestamp());
-                                    break Ok(Some(frame));
-                                } else {
-                                    self.state = State::Unsynchronized;
-                                    continue;
-                                }
-                            }
                             _ => {
                                 self.state = State::Unsynchronized;
                                 continue;
@@ -496,6 +701,15 @@ impl Decoder for VeDirectMpptDecoder {
 
         log::trace!("{} {:#?}", name, result);
 
+        match result {
+            // decode the partial frame again from its start once more bytes arrive
+            Ok(None) => self.state = State::Unsynchronized,
+
+            // the rest of the buffer arrived with the end of this frame
+            Ok(Some(_)) if !cursor.bytes.is_empty() => self.started = Some(Timestamp::now()),
+            _ => {}
+        }
+
         result
     }
 }
@@ -631,9 +845,10 @@ impl Mppt {
 #[cfg(test)]
 mod test {
     use super::{MpptFrame, VeDirectMpptDecoder};
+    use bytes::BytesMut;
     use futures::TryStreamExt;
     use std::io::Cursor;
-    use tokio_util::codec::FramedRead;
+    use tokio_util::codec::{Decoder, FramedRead};
 
     #[tokio::test]
     async fn parse() {
@@ -653,8 +868,31 @@ mod test {
         let result = FramedRead::new(reader, decoder).try_collect().await;
         let frames: Vec<MpptFrame> = result.unwrap();
 
-        // TODO: Fix
-        // should be 299, but the decoder doesn't handle checksum not immediately followed by \r\n
-        assert_eq!(291, frames.len());
+        // including the frames following hex protocol messages
+        assert_eq!(299, frames.len());
+
+        // each frame is stamped when its first byte arrives
+        assert!(frames.iter().all(|frame| frame.timestamp.is_some()));
+    }
+
+    #[test]
+    fn chunked() {
+        let input = std::include_bytes!(
+            "../../../test/usb-VictronEnergy_BV_VE_Direct_cable_VE46V0KW-if00-port0"
+        );
+
+        let mut decoder = VeDirectMpptDecoder::default();
+        let mut buffer = BytesMut::new();
+        let mut frames = 0;
+
+        // partial frames are decoded again once the rest arrives
+        for chunk in input.chunks(7) {
+            buffer.extend_from_slice(chunk);
+            while decoder.decode(&mut buffer).unwrap().is_some() {
+                frames += 1;
+            }
+        }
+
+        assert_eq!(299, frames);
     }
 }
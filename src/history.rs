@@ -0,0 +1,584 @@
+//! Telemetry history, stored on disk
+//!
+//! Every numeric and boolean signal published on the telemetry bus is recorded
+//! in an append-only store suited to SD cards.  Values are buffered and written
+//! in batches, each a checksummed block appended to the segment file covering
+//! its time, so a crash loses at most the batch being written and never what
+//! was written before.  Besides the raw values, each signal is summarized as
+//! the minimum, maximum and mean of each minute and of each hour, which are
+//! kept for longer.  Segments are deleted whole once older than the retention
+//! of their resolution.
+//!
+//! Summaries of the minute and hour in progress are only kept in memory, so a
+//! restart loses the summary of the minute and hour it happens in.
+
+pub mod config;
+mod segment;
+mod summary;
+
+use crate::config::Config;
+use crate::telemetry::{self, Topic, Update, Value};
+use anyhow::Result;
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use summary::Summarizer;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task;
+use tokio::time::{self, Duration};
+
+pub use summary::Summary;
+
+/// Directory of the history in the state directory
+const DIRECTORY: &str = "history";
+
+/// Default time between writes, in seconds
+const DEFAULT_FLUSH_INTERVAL: u64 = 60;
+
+/// Default days raw values are kept
+const DEFAULT_RAW_RETENTION: u32 = 7;
+
+/// Default days 1-minute summaries are kept
+const DEFAULT_MINUTE_RETENTION: u32 = 90;
+
+/// Default days 1-hour summaries are kept
+const DEFAULT_HOUR_RETENTION: u32 = 3650;
+
+/// Number of buffered values and summaries that forces an early write
+const MAX_BUFFERED: usize = 65536;
+
+const MINUTE: i64 = 60_000;
+const HOUR: i64 = 60 * MINUTE;
+const DAY: i64 = 24 * HOUR;
+
+/// Format of a segment's file name, from the start of the time it covers
+const SEGMENT_NAME: &str = "%Y%m%dT%H%MZ";
+
+static HISTORY: Lazy<History> = Lazy::new(|| {
+    let config = Config::get();
+    History::new(
+        PathBuf::from(&config.state_path).join(DIRECTORY),
+        &config.history,
+    )
+});
+
+/// The global telemetry history
+pub fn history() -> &'static History {
+    &HISTORY
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Resolution {
+    /// Every value as published
+    Raw,
+
+    /// Summary of each minute
+    Minute,
+
+    /// Summary of each hour
+    Hour,
+}
+
+impl Resolution {
+    const ALL: [Resolution; 3] = [Resolution::Raw, Resolution::Minute, Resolution::Hour];
+
+    fn name(self) -> &'static str {
+        match self {
+            Resolution::Raw => "raw",
+            Resolution::Minute => "minute",
+            Resolution::Hour => "hour",
+        }
+    }
+
+    /// Time covered by each segment, in ms
+    fn segment_period(self) -> i64 {
+        match self {
+            Resolution::Raw => HOUR,
+            Resolution::Minute => DAY,
+            Resolution::Hour => 30 * DAY,
+        }
+    }
+}
+
+/// Values or summaries of each topic, by topic
+type Batch = BTreeMap<String, Vec<Summary>>;
+
+pub struct History {
+    directory: PathBuf,
+
+    flush_interval: Duration,
+
+    /// Time each resolution is kept, in ms
+    retention: [i64; 3],
+
+    buffer: Mutex<Buffer>,
+
+    /// Held while writing, so that one flush writes at a time
+    writer: Mutex<()>,
+}
+
+/// Values and summaries not yet written
+struct Buffer {
+    /// Batch of each resolution
+    batches: [Batch; 3],
+
+    /// Number of values and summaries in the batches
+    len: usize,
+
+    /// Batches of each resolution being written, still read by queries
+    writing: [Batch; 3],
+
+    minutes: Summarizer,
+    hours: Summarizer,
+}
+
+impl Buffer {
+    fn push(&mut self, resolution: Resolution, topic: &str, summary: Summary) {
+        self.batches[resolution as usize]
+            .entry(topic.to_owned())
+            .or_default()
+            .push(summary);
+        self.len += 1;
+    }
+
+    /// Keep a closed minute, and add it to its hour
+    fn minute(&mut self, topic: &str, minute: Summary) {
+        self.push(Resolution::Minute, topic, minute);
+        if let Some(hour) = self.hours.add(topic, minute) {
+            self.push(Resolution::Hour, topic, hour);
+        }
+    }
+
+    /// Close the minutes and hours that ended by `now`, in ms since the epoch
+    fn close(&mut self, now: i64) {
+        for (topic, minute) in self.minutes.close(now) {
+            self.minute(&topic, minute);
+        }
+        for (topic, hour) in self.hours.close(now) {
+            self.push(Resolution::Hour, &topic, hour);
+        }
+    }
+}
+
+impl History {
+    pub fn new(directory: PathBuf, config: &config::History) -> Self {
+        let days = |days: Option<u32>, default| days.unwrap_or(default) as i64 * DAY;
+
+        Self {
+            directory,
+            flush_interval: Duration::from_secs(
+                config.flush_interval.unwrap_or(DEFAULT_FLUSH_INTERVAL),
+            ),
+            retention: [
+                days(config.raw_retention, DEFAULT_RAW_RETENTION),
+                days(config.minute_retention, DEFAULT_MINUTE_RETENTION),
+                days(config.hour_retention, DEFAULT_HOUR_RETENTION),
+            ],
+            buffer: Mutex::new(Buffer {
+                batches: Default::default(),
+                len: 0,
+                writing: Default::default(),
+                minutes: Summarizer::new(MINUTE),
+                hours: Summarizer::new(HOUR),
+            }),
+            writer: Mutex::new(()),
+        }
+    }
+
+    /// Record every update from the telemetry bus, writing them in batches
+    ///
+    /// Disk access blocks, so it is done with `block_in_place` to keep the
+    /// rest of the runtime going.  History that can't be recovered is moved
+    /// aside rather than stopping habctl.
+    pub async fn run(&self) -> Result<()> {
+        // subscribe first, so that nothing is missed while recovering
+        let mut updates = telemetry::bus().subscribe();
+        if let Err(e) = task::block_in_place(|| self.recover()) {
+            log::error!("Failed to recover history, starting over: {}", e);
+            task::block_in_place(|| self.set_aside());
+        }
+
+        let mut interval = time::interval(self.flush_interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let now = Utc::now();
+                    if let Err(e) = task::block_in_place(|| self.flush(now)) {
+                        log::warn!("Failed to write history: {}", e);
+                    }
+                    if let Err(e) = task::block_in_place(|| self.expire(now)) {
+                        log::warn!("Failed to expire history: {}", e);
+                    }
+                }
+                update = updates.recv() => match update {
+                    Ok(update) => {
+                        if self.record(&update) >= MAX_BUFFERED {
+                            if let Err(e) = task::block_in_place(|| self.flush(Utc::now())) {
+                                log::warn!("Failed to write history: {}", e);
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        log::warn!("History missed {} updates", missed);
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+            }
+        }
+    }
+
+    /// Buffer an update, returning the number of values and summaries buffered
+    pub fn record(&self, update: &Update) -> usize {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        let value = match update.value {
+            Value::Number(number) if number.is_finite() => number,
+            Value::Bool(value) => value as u8 as f32,
+            _ => return buffer.len,
+        };
+
+        let topic = update.topic.to_string();
+        let value = Summary::value(update.timestamp.timestamp_millis(), value);
+
+        buffer.push(Resolution::Raw, &topic, value);
+        if let Some(minute) = buffer.minutes.add(&topic, value) {
+            buffer.minute(&topic, minute);
+        }
+
+        buffer.len
+    }
+
+    /// Write the buffered values, and the summaries of minutes and hours
+    /// that ended by `now`
+    ///
+    /// The buffer is only locked to take the batches, so that recording
+    /// doesn't wait on the disk.
+    pub fn flush(&self, now: DateTime<Utc>) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+
+        let batches = {
+            let mut buffer = self.buffer.lock().unwrap();
+            buffer.close(now.timestamp_millis());
+            buffer.len = 0;
+            buffer.writing = std::mem::take(&mut buffer.batches);
+            buffer.writing.clone()
+        };
+
+        // write every resolution, even if one fails
+        let mut result = Ok(());
+        for (resolution, batch) in Resolution::ALL.iter().zip(batches) {
+            if let Err(e) = self.write(*resolution, batch) {
+                result = Err(e);
+            }
+        }
+
+        self.buffer.lock().unwrap().writing = Default::default();
+        result
+    }
+
+    /// Values or summaries of a topic from `since` until `until`, oldest first
+    pub fn query(
+        &self,
+        topic: &Topic,
+        resolution: Resolution,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) -> Result<Vec<Summary>> {
+        let (since, until) = (since.timestamp_millis(), until.timestamp_millis());
+        let topic = topic.to_string();
+        let in_range = |summary: &&Summary| summary.start >= since && summary.start <= until;
+
+        // copy the buffer before reading the disk, so that a batch written
+        // meanwhile is read twice rather than missed
+        let mut summaries: Vec<Summary> = {
+            let buffer = self.buffer.lock().unwrap();
+            let batches = [&buffer.writing, &buffer.batches];
+            batches
+                .iter()
+                .filter_map(|batch| batch[resolution as usize].get(&topic))
+                .flat_map(|values| values.iter().filter(in_range).copied())
+                .collect()
+        };
+
+        for (start, path) in self.segments(resolution)? {
+            if start + resolution.segment_period() <= since || start > until {
+                continue;
+            }
+
+            let (blocks, _) = segment::read(&path)?;
+            for block in blocks {
+                let batch = decode(resolution, &block)?;
+                if let Some(values) = batch.get(&topic) {
+                    summaries.extend(values.iter().filter(in_range));
+                }
+            }
+        }
+
+        // a topic has one value or summary at a time
+        summaries.sort_by_key(|summary| summary.start);
+        summaries.dedup_by_key(|summary| summary.start);
+        Ok(summaries)
+    }
+
+    /// Delete the segments of each resolution older than its retention
+    pub fn expire(&self, now: DateTime<Utc>) -> Result<()> {
+        let now = now.timestamp_millis();
+
+        for resolution in Resolution::ALL.iter().copied() {
+            let retention = self.retention[resolution as usize];
+            for (start, path) in self.segments(resolution)? {
+                if start + resolution.segment_period() + retention <= now {
+                    log::info!("Expiring history {}", path.display());
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Discard any block left incomplete by a crash
+    ///
+    /// A batch can hold values late enough for an older segment, so a crash
+    /// can tear the end of any of them, not only the latest.
+    fn recover(&self) -> Result<()> {
+        for resolution in Resolution::ALL.iter().copied() {
+            for (_, path) in self.segments(resolution)? {
+                let discarded = segment::recover(&path)?;
+                if discarded > 0 {
+                    log::warn!(
+                        "Discarded {} bytes of incomplete history from {}",
+                        discarded,
+                        path.display()
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Move the history out of the way, to start over with none
+    fn set_aside(&self) {
+        let mut aside = self.directory.clone().into_os_string();
+        aside.push(format!(".{}", Utc::now().format(SEGMENT_NAME)));
+
+        match fs::rename(&self.directory, &aside) {
+            Ok(()) => log::warn!("Moved unrecoverable history to {:?}", aside),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => log::error!("Failed to move history aside: {}", e),
+        }
+    }
+
+    /// Append a batch to the segments covering its times
+    fn write(&self, resolution: Resolution, batch: Batch) -> Result<()> {
+        let period = resolution.segment_period();
+
+        let mut segments = BTreeMap::<i64, Batch>::new();
+        for (topic, summaries) in batch {
+            for summary in summaries {
+                let start = summary.start - summary.start.rem_euclid(period);
+                segments
+                    .entry(start)
+                    .or_default()
+                    .entry(topic.clone())
+                    .or_default()
+                    .push(summary);
+            }
+        }
+
+        if segments.is_empty() {
+            return Ok(());
+        }
+
+        let directory = self.directory.join(resolution.name());
+        fs::create_dir_all(&directory)?;
+
+        for (start, batch) in segments {
+            let name = Utc.timestamp_millis(start).format(SEGMENT_NAME);
+            let path = directory.join(format!("{}.seg", name));
+
+            if let Err(e) = segment::append(&path, &encode(resolution, &batch)?) {
+                // don't leave a partial block for the next to follow
+                let _ = segment::recover(&path);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Segments of a resolution and the start of the time each covers, oldest first
+    fn segments(&self, resolution: Resolution) -> Result<Vec<(i64, PathBuf)>> {
+        let entries = match fs::read_dir(self.directory.join(resolution.name())) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut segments = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let start = path
+                .file_name()
+                .and_then(|name| name.to_str()?.strip_suffix(".seg"))
+                .and_then(|name| NaiveDateTime::parse_from_str(name, SEGMENT_NAME).ok());
+
+            if let Some(start) = start {
+                segments.push((start.timestamp_millis(), path));
+            }
+        }
+
+        segments.sort();
+        Ok(segments)
+    }
+}
+
+/// Encode a batch, as times and values alone if raw
+fn encode(resolution: Resolution, batch: &Batch) -> Result<Vec<u8>> {
+    Ok(match resolution {
+        Resolution::Raw => {
+            let raw: Vec<(&String, Vec<(i64, f32)>)> = batch
+                .iter()
+                .map(|(topic, values)| (topic, values.iter().map(|v| (v.start, v.mean)).collect()))
+                .collect();
+            bincode::serialize(&raw)?
+        }
+        _ => bincode::serialize(batch)?,
+    })
+}
+
+fn decode(resolution: Resolution, data: &[u8]) -> Result<Batch> {
+    Ok(match resolution {
+        Resolution::Raw => {
+            let raw: Vec<(String, Vec<(i64, f32)>)> = bincode::deserialize(data)?;
+            raw.into_iter()
+                .map(|(topic, values)| {
+                    let values = values
+                        .into_iter()
+                        .map(|(timestamp, value)| Summary::value(timestamp, value))
+                        .collect();
+                    (topic, values)
+                })
+                .collect()
+        }
+        _ => bincode::deserialize(data)?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{config, History, Resolution};
+    use crate::telemetry::{Topic, Update, Value};
+    use chrono::{Duration, TimeZone, Utc};
+    use std::fs;
+
+    #[test]
+    fn history() {
+        let dir = std::env::temp_dir().join(format!("habctl-history-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let config = config::History {
+            raw_retention: Some(1),
+            ..Default::default()
+        };
+        let history = History::new(dir.clone(), &config);
+
+        let start = Utc.ymd(2021, 6, 1).and_hms(12, 0, 0);
+        let at = |seconds| start + Duration::seconds(seconds);
+        let topic = Topic::new("big", "battery_voltage");
+
+        // a value every 30 s for an hour and a half, written every ten minutes
+        for i in 0..180 {
+            history.record(&Update {
+                topic: topic.clone(),
+                timestamp: at(i * 30),
+                value: Value::Number(12.0 + (i % 2) as f32),
+                stale: false,
+            });
+            history.record(&Update {
+                topic: Topic::new("big", "state"),
+                timestamp: at(i * 30),
+                value: Value::Text("Bulk".into()),
+                stale: false,
+            });
+
+            if i % 20 == 19 {
+                history.flush(at(i * 30 + 1)).unwrap();
+            }
+        }
+
+        let raw = history
+            .query(&topic, Resolution::Raw, at(0), at(5400))
+            .unwrap();
+        assert_eq!(180, raw.len());
+        assert_eq!(13.0, raw[179].mean);
+
+        // raw values span the two hourly segments, the rest one daily segment
+        let count =
+            |resolution: Resolution| fs::read_dir(dir.join(resolution.name())).unwrap().count();
+        assert_eq!(2, count(Resolution::Raw));
+        assert_eq!(1, count(Resolution::Minute));
+        assert_eq!(1, count(Resolution::Hour));
+        assert_eq!(
+            0,
+            history.segments(Resolution::Raw).unwrap()[0].0 % super::HOUR
+        );
+
+        // the minute in progress is not yet summarized
+        let minutes = history
+            .query(&topic, Resolution::Minute, at(0), at(5400))
+            .unwrap();
+        assert_eq!(89, minutes.len());
+        assert_eq!((12.0, 13.0, 12.5, 2), {
+            let m = minutes[0];
+            (m.min, m.max, m.mean, m.count)
+        });
+
+        let hours = history
+            .query(&topic, Resolution::Hour, at(0), at(5400))
+            .unwrap();
+        assert_eq!(1, hours.len());
+        assert_eq!(120, hours[0].count);
+        assert_eq!(12.5, hours[0].mean);
+
+        // ranges select within segments
+        let raw = history
+            .query(&topic, Resolution::Raw, at(60), at(119))
+            .unwrap();
+        assert_eq!(2, raw.len());
+
+        // the hour is summarized once it has passed, even with no later value
+        history.flush(at(7200)).unwrap();
+        let hours = history
+            .query(&topic, Resolution::Hour, at(0), at(7200))
+            .unwrap();
+        assert_eq!(2, hours.len());
+        assert_eq!(60, hours[1].count);
+
+        // survives a torn block, even in a segment before the latest
+        let path = history.segments(Resolution::Raw).unwrap()[0].1.clone();
+        let mut contents = fs::read(&path).unwrap();
+        contents.extend([9, 0, 0, 0, 1].iter());
+        fs::write(&path, contents).unwrap();
+        history.recover().unwrap();
+        assert_eq!(0, super::segment::recover(&path).unwrap());
+        let raw = history
+            .query(&topic, Resolution::Raw, at(0), at(7200))
+            .unwrap();
+        assert_eq!(180, raw.len());
+
+        // raw values older than a day are deleted, summaries kept
+        history.expire(at(3600 + 86400)).unwrap();
+        assert_eq!(1, count(Resolution::Raw));
+        assert_eq!(1, count(Resolution::Minute));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
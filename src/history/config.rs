@@ -0,0 +1,16 @@
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct History {
+    /// Time between writes of buffered values, in seconds (default 60)
+    pub flush_interval: Option<u64>,
+
+    /// Days raw values are kept (default 7)
+    pub raw_retention: Option<u32>,
+
+    /// Days 1-minute summaries are kept (default 90)
+    pub minute_retention: Option<u32>,
+
+    /// Days 1-hour summaries are kept (default 3650)
+    pub hour_retention: Option<u32>,
+}
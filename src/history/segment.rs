@@ -0,0 +1,111 @@
+//! Append-only segment files of checksummed blocks
+//!
+//! Each block is its length and CRC-32 as little-endian `u32`s, then its data.
+//! A block is written in one write and synced before the next, so a crash can
+//! only leave the last block of a segment incomplete, and reading stops at
+//! the first block that is incomplete or fails its checksum.
+
+use anyhow::Result;
+use std::convert::TryInto;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+/// Length of a block's header
+const HEADER: usize = 8;
+
+/// Append a block, creating the segment if need be
+pub fn append(path: &Path, data: &[u8]) -> Result<()> {
+    let mut block = Vec::with_capacity(HEADER + data.len());
+    block.extend((data.len() as u32).to_le_bytes());
+    block.extend(crc32fast::hash(data).to_le_bytes());
+    block.extend_from_slice(data);
+
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&block)?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+/// Valid blocks of a segment, and the length of the segment they span
+pub fn read(path: &Path) -> Result<(Vec<Vec<u8>>, u64)> {
+    let contents = fs::read(path)?;
+
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    while let Some(data) = block(&contents[offset..]) {
+        offset += HEADER + data.len();
+        blocks.push(data.to_vec());
+    }
+
+    Ok((blocks, offset as u64))
+}
+
+/// Truncate a segment after its last valid block, returning the number of
+/// bytes discarded
+pub fn recover(path: &Path) -> Result<u64> {
+    let (_, valid) = read(path)?;
+    let len = fs::metadata(path)?.len();
+
+    if valid < len {
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(valid)?;
+        file.sync_all()?;
+    }
+
+    Ok(len - valid)
+}
+
+/// Data of the block at the start of `bytes`, if it is complete and intact
+fn block(bytes: &[u8]) -> Option<&[u8]> {
+    let len = u32::from_le_bytes(bytes.get(0..4)?.try_into().ok()?) as usize;
+    let crc = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?);
+    let data = bytes.get(HEADER..HEADER + len)?;
+
+    (crc32fast::hash(data) == crc).then_some(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{append, read, recover};
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    #[test]
+    fn torn_block() {
+        let dir = std::env::temp_dir().join(format!("habctl-segment-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("torn.seg");
+        let _ = fs::remove_file(&path);
+
+        append(&path, b"first").unwrap();
+        append(&path, b"second").unwrap();
+        let (blocks, valid) = read(&path).unwrap();
+        assert_eq!(vec![b"first".to_vec(), b"second".to_vec()], blocks);
+
+        // a block cut short by a crash
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[5, 0, 0, 0, 1, 2, 3, 4, b't']).unwrap();
+        let (blocks, _) = read(&path).unwrap();
+        assert_eq!(2, blocks.len());
+
+        assert_eq!(9, recover(&path).unwrap());
+        assert_eq!(valid, fs::metadata(&path).unwrap().len());
+        assert_eq!(0, recover(&path).unwrap());
+
+        // appending carries on after the last valid block
+        append(&path, b"third").unwrap();
+        let (blocks, _) = read(&path).unwrap();
+        assert_eq!(b"third".to_vec(), blocks[2]);
+
+        // as does reading, past a corrupted block
+        let mut contents = fs::read(&path).unwrap();
+        let last = contents.len() - 1;
+        contents[last] ^= 0xff;
+        fs::write(&path, contents).unwrap();
+        assert_eq!(2, read(&path).unwrap().0.len());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,158 @@
+//! Summaries of signals over fixed intervals
+//!
+//! Values are summarized into the interval they fall in, and each interval is
+//! closed once a value falls in a later one or the interval has passed.
+//! Summaries of shorter intervals are summarized in turn, so the hour is built
+//! from its minutes rather than from every value.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Minimum, maximum and mean of a signal over an interval, or a single value
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    /// Start of the interval or time of the value, in ms since the epoch
+    pub start: i64,
+
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+
+    /// Number of values summarized
+    pub count: u32,
+}
+
+impl Summary {
+    pub fn value(timestamp: i64, value: f32) -> Self {
+        Self {
+            start: timestamp,
+            min: value,
+            max: value,
+            mean: value,
+            count: 1,
+        }
+    }
+
+    fn merge(&mut self, other: &Summary) {
+        let count = self.count + other.count;
+        self.mean = ((self.mean as f64 * self.count as f64
+            + other.mean as f64 * other.count as f64)
+            / count as f64) as f32;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.count = count;
+    }
+}
+
+/// Summarizes each topic over consecutive intervals
+pub struct Summarizer {
+    /// Length of each interval, in ms
+    interval: i64,
+
+    /// Summary of the current interval of each topic
+    open: BTreeMap<String, Summary>,
+}
+
+impl Summarizer {
+    pub fn new(interval: i64) -> Self {
+        Self {
+            interval,
+            open: BTreeMap::new(),
+        }
+    }
+
+    /// Add a value or summary of a topic, returning the summary of the
+    /// previous interval if it starts a new one
+    pub fn add(&mut self, topic: &str, summary: Summary) -> Option<Summary> {
+        let start = summary.start - summary.start.rem_euclid(self.interval);
+
+        match self.open.get_mut(topic) {
+            Some(open) if open.start == start => {
+                open.merge(&summary);
+                None
+            }
+            // too late for an interval already closed
+            Some(open) if open.start > start => None,
+            _ => self
+                .open
+                .insert(topic.to_owned(), Summary { start, ..summary }),
+        }
+    }
+
+    /// Close every interval that ended by `now`, in ms since the epoch
+    pub fn close(&mut self, now: i64) -> Vec<(String, Summary)> {
+        let interval = self.interval;
+        let ended: Vec<_> = self
+            .open
+            .iter()
+            .filter(|(_, summary)| summary.start + interval <= now)
+            .map(|(topic, _)| topic.clone())
+            .collect();
+
+        ended
+            .into_iter()
+            .map(|topic| {
+                let summary = self.open.remove(&topic).unwrap();
+                (topic, summary)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Summarizer, Summary};
+
+    #[test]
+    fn summarizes() {
+        let mut minutes = Summarizer::new(60_000);
+        assert_eq!(
+            None,
+            minutes.add("big/voltage", Summary::value(60_500, 13.0))
+        );
+        assert_eq!(
+            None,
+            minutes.add("big/voltage", Summary::value(61_000, 14.0))
+        );
+        assert_eq!(
+            None,
+            minutes.add("big/voltage", Summary::value(119_999, 12.0))
+        );
+        assert_eq!(
+            None,
+            minutes.add("lil/voltage", Summary::value(90_000, 12.5))
+        );
+
+        // a value in the next minute closes the first
+        let minute = minutes
+            .add("big/voltage", Summary::value(120_000, 13.5))
+            .unwrap();
+        assert_eq!(60_000, minute.start);
+        assert_eq!(12.0, minute.min);
+        assert_eq!(14.0, minute.max);
+        assert_eq!(13.0, minute.mean);
+        assert_eq!(3, minute.count);
+
+        // late values are dropped rather than reopening a minute
+        assert_eq!(
+            None,
+            minutes.add("big/voltage", Summary::value(100_000, 99.0))
+        );
+
+        // as are minutes that have passed without a later value
+        assert_eq!(0, minutes.close(119_999).len());
+        let closed = minutes.close(120_000);
+        assert_eq!(1, closed.len());
+        assert_eq!("lil/voltage", closed[0].0);
+
+        // minutes are summarized into hours, weighted by their counts
+        let mut hours = Summarizer::new(3_600_000);
+        hours.add("big/voltage", minute);
+        hours.add("big/voltage", Summary::value(3_599_000, 16.0));
+        let hour = &hours.close(3_600_000)[0].1;
+        assert_eq!(0, hour.start);
+        assert_eq!(13.75, hour.mean);
+        assert_eq!(16.0, hour.max);
+        assert_eq!(4, hour.count);
+    }
+}
@@ -0,0 +1,15 @@
+//! HAB truck camper backend services
+//!
+//! The binary runs the configured hardware and serves the web UI, API and
+//! websocket.  Messages shared with the habux client are defined by the
+//! `habctl-protocol` crate.
+
+#[macro_use]
+extern crate bitflags;
+
+pub mod config;
+pub mod hardware;
+pub mod history;
+pub mod state;
+pub mod telemetry;
+pub mod web;
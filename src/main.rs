@@ -33,18 +33,12 @@
 //     log::debug!("shutting down");
 // }
 
-#[macro_use]
-extern crate bitflags;
-
-mod config;
-mod hardware;
-mod web;
-
 use anyhow::Result;
+use habctl::{hardware, history, web};
 use std::sync::Arc;
 use tokio::runtime::Runtime;
 
-use crate::config::Config;
+use habctl::config::Config;
 
 fn main() -> Result<()> {
     let rt = Runtime::new()?;
@@ -59,12 +53,13 @@ fn main() -> Result<()> {
         dbg!(Config::get());
 
         log::debug!("building hardware interfaces");
-        let hardware = Arc::new(hardware::Hardware::default());
+        let hardware = Arc::new(hardware::Hardware::new()?);
 
         log::debug!("starting services");
         tokio::try_join!(
             web::serve(Config::get().web.listen_addr, hardware.clone()),
             hardware.run(),
+            history::history().run(),
         )?;
 
         log::debug!("exiting");
@@ -0,0 +1,75 @@
+//! Persistent state, stored as TOML files in the configured state directory
+
+use crate::config::Config;
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+
+fn path(name: &str) -> PathBuf {
+    PathBuf::from(&Config::get().state_path).join(format!("{}.toml", name))
+}
+
+/// Loads state saved under `name`, if any
+pub fn load<T: DeserializeOwned>(name: &str) -> Result<Option<T>> {
+    match fs::read_to_string(path(name)) {
+        Ok(contents) => Ok(Some(toml::from_str(&contents)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Saves state under `name`, replacing any previous value atomically
+pub fn save<T: Serialize>(name: &str, value: &T) -> Result<()> {
+    let path = path(name);
+    let temp = path.with_extension("toml.tmp");
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut file = fs::File::create(&temp)?;
+    file.write_all(toml::to_string(value)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(temp, path)?;
+
+    Ok(())
+}
+
+/// A copy of state to save under `name`, so that it can be written after
+/// releasing the lock on what it was copied from
+pub struct Snapshot<T> {
+    name: String,
+    value: T,
+}
+
+impl<T: Serialize> Snapshot<T> {
+    pub fn new(name: &str, value: T) -> Self {
+        Self {
+            name: name.to_owned(),
+            value,
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        save(&self.name, &self.value)
+    }
+}
+
+/// Directory for state kept in files of its own, created if needed
+pub fn directory(name: &str) -> Result<PathBuf> {
+    let path = PathBuf::from(&Config::get().state_path).join(name);
+    fs::create_dir_all(&path)?;
+
+    Ok(path)
+}
+
+/// Removes state saved under `name`
+pub fn remove(name: &str) -> Result<()> {
+    match fs::remove_file(path(name)) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,294 @@
+//! Telemetry publish/subscribe bus
+//!
+//! Devices publish each frame as a set of signals, each a scalar value under a
+//! topic of the device and signal name.  The bus keeps the latest value of
+//! every topic, which can be read or watched for changes, and broadcasts every
+//! update to subscribers such as the websocket, storage and alerts.  Values
+//! read back from the bus are marked stale once older than the configured age.
+//!
+//! Each kind of frame also describes its signals, with their units and valid
+//! ranges, so that clients can present any device without knowing its kind.
+
+pub mod config;
+
+use crate::config::Config;
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use tokio::sync::{broadcast, watch};
+use unit::Unit;
+
+pub use habctl_protocol::telemetry::{unit, Descriptor, Topic, Update, Value};
+
+/// Number of updates buffered for slow subscribers
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// Default age after which a value is stale, in seconds
+const DEFAULT_STALE_AFTER: f32 = 10.0;
+
+static BUS: Lazy<Bus> = Lazy::new(|| {
+    let stale_after = Config::get()
+        .telemetry
+        .stale_after
+        .unwrap_or(DEFAULT_STALE_AFTER);
+
+    Bus::new(
+        CHANNEL_CAPACITY,
+        Duration::milliseconds((stale_after * 1000.0) as i64),
+    )
+});
+
+/// The global telemetry bus
+pub fn bus() -> &'static Bus {
+    &BUS
+}
+
+/// Description of a signal of a kind of frame
+#[derive(Clone, Debug)]
+pub struct Spec {
+    pub signal: &'static str,
+    pub unit: Unit,
+
+    /// Factor from the raw value reported by the device to the unit
+    pub scale: f32,
+
+    /// Range of valid values, in the unit
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+
+    pub description: &'static str,
+}
+
+impl Spec {
+    pub const fn new(signal: &'static str, unit: Unit, description: &'static str) -> Self {
+        Self {
+            signal,
+            unit,
+            scale: 1.0,
+            min: None,
+            max: None,
+            description,
+        }
+    }
+
+    pub const fn scale(mut self, scale: f32) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub const fn range(mut self, min: f32, max: f32) -> Self {
+        self.min = Some(min);
+        self.max = Some(max);
+        self
+    }
+
+    pub const fn min(mut self, min: f32) -> Self {
+        self.min = Some(min);
+        self
+    }
+}
+
+/// Frame of telemetry that can be published as signals
+pub trait Signals {
+    /// Every signal the frame may contain
+    const SPECS: &'static [Spec];
+
+    /// Signal names and values present in the frame
+    fn signals(&self) -> Vec<(String, Value)>;
+}
+
+pub struct Bus {
+    /// Latest update of each topic
+    topics: Mutex<BTreeMap<Topic, watch::Sender<Option<Update>>>>,
+
+    updates: broadcast::Sender<Update>,
+
+    /// Registry of the signals of each device
+    descriptors: Mutex<BTreeMap<Topic, Descriptor>>,
+
+    stale_after: Duration,
+}
+
+impl Bus {
+    pub fn new(capacity: usize, stale_after: Duration) -> Self {
+        let (updates, _) = broadcast::channel(capacity);
+
+        Self {
+            topics: Mutex::default(),
+            updates,
+            descriptors: Mutex::default(),
+            stale_after,
+        }
+    }
+
+    /// Register the signals published by `device` in frames of kind `F`
+    pub fn describe<F: Signals>(&self, device: &str) {
+        let mut descriptors = self.descriptors.lock().unwrap();
+
+        for spec in F::SPECS {
+            let topic = Topic::new(device, spec.signal);
+            let descriptor = Descriptor {
+                id: topic.to_string(),
+                device: topic.device.clone(),
+                signal: topic.signal.clone(),
+                unit: spec.unit,
+                scale: spec.scale,
+                min: spec.min,
+                max: spec.max,
+                description: spec.description.to_owned(),
+            };
+
+            descriptors.insert(topic, descriptor);
+        }
+    }
+
+    /// Descriptions of every registered signal, ordered by topic
+    pub fn descriptors(&self) -> Vec<Descriptor> {
+        let descriptors = self.descriptors.lock().unwrap();
+        descriptors.values().cloned().collect()
+    }
+
+    /// Age after which a value is stale
+    pub fn stale_after(&self) -> Duration {
+        self.stale_after
+    }
+
+    /// Publish the signals of a frame from `device`
+    pub fn publish(&self, device: &str, timestamp: DateTime<Utc>, frame: &impl Signals) {
+        let mut topics = self.topics.lock().unwrap();
+
+        for (signal, value) in frame.signals() {
+            let update = Update {
+                topic: Topic::new(device, &signal),
+                timestamp,
+                value,
+                stale: false,
+            };
+
+            topics
+                .entry(update.topic.clone())
+                .or_insert_with(|| watch::channel(None).0)
+                .send_replace(Some(update.clone()));
+
+            // no subscribers is not an error
+            let _ = self.updates.send(update);
+        }
+    }
+
+    /// Latest update of a topic
+    pub fn latest(&self, topic: &Topic) -> Option<Update> {
+        let topics = self.topics.lock().unwrap();
+        let update = topics.get(topic)?.borrow().clone();
+        update.map(|update| self.aged(update, Utc::now()))
+    }
+
+    /// Latest update of every topic, ordered by topic
+    pub fn snapshot(&self) -> Vec<Update> {
+        let now = Utc::now();
+        let topics = self.topics.lock().unwrap();
+        topics
+            .values()
+            .filter_map(|sender| sender.borrow().clone())
+            .map(|update| self.aged(update, now))
+            .collect()
+    }
+
+    fn aged(&self, mut update: Update, now: DateTime<Utc>) -> Update {
+        update.stale = now - update.timestamp > self.stale_after;
+        update
+    }
+
+    /// Watch the latest update of a topic, which may not have been published yet
+    pub fn watch(&self, topic: &Topic) -> watch::Receiver<Option<Update>> {
+        let mut topics = self.topics.lock().unwrap();
+        topics
+            .entry(topic.clone())
+            .or_insert_with(|| watch::channel(None).0)
+            .subscribe()
+    }
+
+    /// Subscribe to every update as it is published
+    pub fn subscribe(&self) -> broadcast::Receiver<Update> {
+        self.updates.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::unit::{Unit, Volts};
+    use super::{Bus, Signals, Spec, Topic, Value};
+    use chrono::{Duration, Utc};
+
+    struct Frame {
+        voltage: Volts,
+        charging: bool,
+    }
+
+    impl Signals for Frame {
+        const SPECS: &'static [Spec] = &[
+            Spec::new("voltage", Volts::UNIT, "Battery voltage").range(0.0, 20.0),
+            Spec::new("charging", Unit::None, "Whether the battery is charging"),
+        ];
+
+        fn signals(&self) -> Vec<(String, Value)> {
+            vec![
+                ("voltage".to_owned(), self.voltage.into()),
+                ("charging".to_owned(), self.charging.into()),
+            ]
+        }
+    }
+
+    #[tokio::test]
+    async fn publish() {
+        let bus = Bus::new(16, Duration::seconds(10));
+        let voltage = Topic::new("big", "voltage");
+
+        let mut watch = bus.watch(&voltage);
+        let mut updates = bus.subscribe();
+        assert!(bus.latest(&voltage).is_none());
+
+        let frame = Frame {
+            voltage: Volts(13.2),
+            charging: true,
+        };
+        bus.publish("big", Utc::now(), &frame);
+
+        watch.changed().await.unwrap();
+        let latest = watch.borrow().clone().unwrap();
+        assert_eq!(Value::Number(13.2), latest.value);
+
+        assert_eq!(voltage, updates.recv().await.unwrap().topic);
+        assert_eq!(Value::Bool(true), updates.recv().await.unwrap().value);
+
+        // ordered by topic, charging before voltage
+        let snapshot = bus.snapshot();
+        assert_eq!(2, snapshot.len());
+        assert_eq!("charging", snapshot[0].topic.signal);
+        assert_eq!(Some(13.2), bus.latest(&voltage).unwrap().value.as_number());
+        assert!(!bus.latest(&voltage).unwrap().stale);
+
+        // values from an hour ago are stale
+        bus.publish("big", Utc::now() - Duration::hours(1), &frame);
+        assert!(bus.latest(&voltage).unwrap().stale);
+    }
+
+    #[test]
+    fn describe() {
+        let bus = Bus::new(16, Duration::seconds(10));
+        bus.describe::<Frame>("big");
+        bus.describe::<Frame>("lil");
+
+        let descriptors = bus.descriptors();
+        assert_eq!(4, descriptors.len());
+
+        let voltage = &descriptors[1];
+        assert_eq!("big/voltage", voltage.id);
+        assert_eq!(Unit::Volt, voltage.unit);
+        assert_eq!(Some(20.0), voltage.max);
+
+        let json = serde_json::to_value(voltage).unwrap();
+        assert_eq!("V", json["unit"]);
+        assert_eq!("big", json["device"]);
+    }
+}
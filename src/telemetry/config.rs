@@ -0,0 +1,7 @@
+use serde::Deserialize;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Telemetry {
+    /// Age after which a value is stale and its device late, in seconds (default 10)
+    pub stale_after: Option<f32>,
+}
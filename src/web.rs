@@ -1,8 +1,10 @@
 pub mod config;
 
 mod api;
+mod encoding;
 mod files;
 mod socket;
+mod sse;
 
 use crate::hardware::Hardware;
 use anyhow::Result;
@@ -11,7 +13,8 @@ use std::sync::Arc;
 use warp::Filter;
 
 pub async fn serve(addr: impl Into<SocketAddr>, hardware: Arc<Hardware>) -> Result<()> {
-    let routes = socket::ui_socket()
+    let routes = socket::ui_socket(hardware.clone())
+        .or(sse::telemetry(hardware.clone()))
         .or(api::api(hardware))
         .or(files::static_files());
 
@@ -1,22 +1,514 @@
+mod v1;
+
+use crate::hardware::device::{Health, Metadata};
+use crate::hardware::imu::alarm::Armed;
+use crate::hardware::imu::calibration::Routine;
+use crate::hardware::imu::Icm20948;
 use crate::hardware::Hardware;
+use crate::telemetry;
+use crate::web::encoding::{self, Encoding};
+use crate::web::socket::session;
+use serde_json::Value;
 use std::convert::Infallible;
 use std::sync::Arc;
 use warp::Filter;
 
+/// REST API at /api
+///
+/// The unversioned routes serialize habctl's own structs, so they change with
+/// them; other programs should use the stable /api/v1.
 pub fn api(
     hardware: Arc<Hardware>,
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
-    warp::path!("api")
+    let v1 = v1::api(hardware.clone());
+
+    let telemetry = warp::path!("api")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::telemetry);
+
+    let devices = warp::path!("api" / "devices")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::devices);
+
+    let command = warp::path!("api" / "devices" / String / String)
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::command);
+
+    let latest = warp::path!("api" / "telemetry")
+        .and(warp::get())
+        .and(encoding::accept())
+        .and_then(reply::latest);
+
+    let device_latest = warp::path!("api" / "telemetry" / String)
+        .and(warp::get())
+        .and(encoding::accept())
+        .and_then(reply::device_latest);
+
+    let signals = warp::path!("api" / "signals")
+        .and(warp::get())
+        .and(encoding::accept())
+        .and_then(reply::signals);
+
+    let sessions = warp::path!("api" / "sessions")
+        .and(warp::get())
+        .and(encoding::accept())
+        .and_then(reply::sessions);
+
+    let aiming = warp::path!("api" / "solar" / "aiming")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::aiming);
+
+    let set_level_reference = warp::path!("api" / "imu" / String / "level" / "reference")
+        .and(warp::post())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::set_level_reference);
+
+    let clear_level_reference = warp::path!("api" / "imu" / String / "level" / "reference")
+        .and(warp::delete())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::clear_level_reference);
+
+    let calibration = warp::path!("api" / "imu" / String / "calibration")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::calibration);
+
+    let start_calibration = warp::path!("api" / "imu" / String / "calibration" / Routine)
+        .and(warp::post())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::start_calibration);
+
+    let cancel_calibration = warp::path!("api" / "imu" / String / "calibration")
+        .and(warp::delete())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::cancel_calibration);
+
+    let alarm = warp::path!("api" / "imu" / String / "alarm")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::alarm);
+
+    let set_alarm = warp::path!("api" / "imu" / String / "alarm")
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::set_alarm);
+
+    let trips = warp::path!("api" / "imu" / String / "trips")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::trips);
+
+    let vibration = warp::path!("api" / "imu" / String / "vibration")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::vibration);
+
+    let impacts = warp::path!("api" / "imu" / String / "impacts")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::impacts);
+
+    let impact = warp::path!("api" / "imu" / String / "impacts" / String)
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::impact);
+
+    let events = warp::path!("api" / "imu" / String / "events")
         .and(warp::get())
         .and(with_hardware(hardware))
-        .and_then(reply::telemetry)
+        .and(encoding::accept())
+        .and_then(reply::events);
+
+    v1.or(telemetry)
+        .or(devices)
+        .or(command)
+        .or(latest)
+        .or(device_latest)
+        .or(signals)
+        .or(sessions)
+        .or(aiming)
+        .or(set_level_reference)
+        .or(clear_level_reference)
+        .or(calibration)
+        .or(start_calibration)
+        .or(cancel_calibration)
+        .or(alarm)
+        .or(set_alarm)
+        .or(trips)
+        .or(vibration)
+        .or(impacts)
+        .or(impact)
+        .or(events)
 }
 
 mod reply {
     use super::*;
+    use serde::Serialize;
+    use tokio::task;
+    use warp::http::header::CONTENT_TYPE;
+    use warp::http::StatusCode;
+    use warp::reply::{json, with_header, with_status, Response};
+    use warp::Reply;
+
+    pub async fn telemetry(
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(encoded(encoding, &*hardware, StatusCode::OK))
+    }
+
+    pub async fn devices(
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let devices: Vec<_> = hardware
+            .devices()
+            .iter()
+            .map(|device| DeviceStatus {
+                metadata: device.metadata(),
+                health: device.health(),
+            })
+            .collect();
+
+        Ok(encoded(encoding, &devices, StatusCode::OK))
+    }
+
+    pub async fn command(
+        name: String,
+        command: String,
+        arguments: Value,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        // commands may read or write state files
+        let value = {
+            let (hardware, name) = (hardware.clone(), name.clone());
+            task::spawn_blocking(move || {
+                let device = hardware.device(&name)?;
+                Some(device.command(&command, arguments))
+            })
+            .await
+            .unwrap_or_else(|e| Some(Err(e.into())))
+        };
+
+        Ok(match value {
+            Some(value) => result(encoding, value),
+            None => not_found(encoding, &name),
+        })
+    }
+
+    pub async fn latest(encoding: Encoding) -> Result<impl warp::Reply, Infallible> {
+        Ok(encoded(
+            encoding,
+            &telemetry::bus().snapshot(),
+            StatusCode::OK,
+        ))
+    }
+
+    pub async fn device_latest(
+        name: String,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let updates: Vec<_> = telemetry::bus()
+            .snapshot()
+            .into_iter()
+            .filter(|update| update.topic.device == name)
+            .collect();
+
+        Ok(encoded(encoding, &updates, StatusCode::OK))
+    }
+
+    pub async fn signals(encoding: Encoding) -> Result<impl warp::Reply, Infallible> {
+        Ok(encoded(
+            encoding,
+            &telemetry::bus().descriptors(),
+            StatusCode::OK,
+        ))
+    }
+
+    /// Websocket sessions, with how far behind their clients are
+    pub async fn sessions(encoding: Encoding) -> Result<impl warp::Reply, Infallible> {
+        Ok(encoded(
+            encoding,
+            &session::sessions().stats(),
+            StatusCode::OK,
+        ))
+    }
+
+    pub async fn aiming(
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(result(encoding, hardware.aiming()))
+    }
+
+    pub async fn set_level_reference(
+        name: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let value = imu_blocking(&hardware, &name, |imu| imu.set_level_reference()).await;
+        Ok(match value {
+            Some(value) => result(encoding, value),
+            None => not_imu(encoding, &hardware, &name),
+        })
+    }
+
+    pub async fn clear_level_reference(
+        name: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let value = imu_blocking(&hardware, &name, |imu| imu.clear_level_reference()).await;
+        Ok(match value {
+            Some(value) => result(encoding, value),
+            None => not_imu(encoding, &hardware, &name),
+        })
+    }
+
+    pub async fn calibration(
+        name: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match hardware.find::<Icm20948>(&name) {
+            Some(imu) => result(encoding, Ok(imu.calibration())),
+            None => not_imu(encoding, &hardware, &name),
+        })
+    }
+
+    pub async fn start_calibration(
+        name: String,
+        routine: Routine,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match hardware.find::<Icm20948>(&name) {
+            Some(imu) => result(encoding, Ok(imu.start_calibration(routine))),
+            None => not_imu(encoding, &hardware, &name),
+        })
+    }
+
+    pub async fn cancel_calibration(
+        name: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match hardware.find::<Icm20948>(&name) {
+            Some(imu) => result(encoding, Ok(imu.cancel_calibration())),
+            None => not_imu(encoding, &hardware, &name),
+        })
+    }
+
+    pub async fn alarm(
+        name: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match hardware.find::<Icm20948>(&name) {
+            Some(imu) => result(encoding, Ok(imu.alarm())),
+            None => not_imu(encoding, &hardware, &name),
+        })
+    }
+
+    pub async fn set_alarm(
+        name: String,
+        armed: Armed,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let value = imu_blocking(&hardware, &name, move |imu| imu.set_alarm(armed.armed)).await;
+        Ok(match value {
+            Some(value) => result(encoding, value),
+            None => not_imu(encoding, &hardware, &name),
+        })
+    }
+
+    pub async fn trips(
+        name: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match hardware.find::<Icm20948>(&name) {
+            Some(imu) => result(encoding, Ok(imu.trips())),
+            None => not_imu(encoding, &hardware, &name),
+        })
+    }
+
+    pub async fn vibration(
+        name: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match hardware.find::<Icm20948>(&name) {
+            Some(imu) => result(encoding, imu.vibration()),
+            None => not_imu(encoding, &hardware, &name),
+        })
+    }
+
+    pub async fn impacts(
+        name: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        let value = imu_blocking(&hardware, &name, |imu| imu.impacts()).await;
+        Ok(match value {
+            Some(value) => result(encoding, value),
+            None => not_imu(encoding, &hardware, &name),
+        })
+    }
+
+    /// Samples of a recording as CSV, or the failure as JSON
+    pub async fn impact(
+        name: String,
+        recording: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<Response, Infallible> {
+        let csv = imu_blocking(&hardware, &name, move |imu| imu.impact(&recording)).await;
+        Ok(match csv {
+            Some(Ok(csv)) => with_header(csv, CONTENT_TYPE, "text/csv").into_response(),
+            Some(Err(e)) => encoded(
+                encoding,
+                &Failure {
+                    code: "not_found",
+                    error: e.to_string(),
+                },
+                StatusCode::NOT_FOUND,
+            ),
+            None => not_imu(encoding, &hardware, &name),
+        })
+    }
+
+    pub async fn events(
+        name: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<impl warp::Reply, Infallible> {
+        Ok(match hardware.find::<Icm20948>(&name) {
+            Some(imu) => result(encoding, Ok(imu.events())),
+            None => not_imu(encoding, &hardware, &name),
+        })
+    }
+
+    /// Call `f` on the IMU named `name` on a blocking thread, for calls that
+    /// read or write state files, or `None` if there is no such IMU
+    async fn imu_blocking<T, F>(
+        hardware: &Arc<Hardware>,
+        name: &str,
+        f: F,
+    ) -> Option<anyhow::Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Icm20948) -> anyhow::Result<T> + Send + 'static,
+    {
+        let (hardware, name) = (hardware.clone(), name.to_owned());
+        task::spawn_blocking(move || hardware.find::<Icm20948>(&name).map(f))
+            .await
+            .unwrap_or_else(|e| Some(Err(e.into())))
+    }
+
+    #[derive(Serialize)]
+    struct DeviceStatus {
+        #[serde(flatten)]
+        metadata: Metadata,
+        health: Health,
+    }
+
+    #[derive(Serialize)]
+    struct Failure {
+        /// Kind of error, as in v1
+        code: &'static str,
+
+        error: String,
+    }
+
+    fn result<T: Serialize>(encoding: Encoding, result: anyhow::Result<T>) -> Response {
+        match result {
+            Ok(value) => encoded(encoding, &value, StatusCode::OK),
+            Err(e) => encoded(
+                encoding,
+                &Failure {
+                    code: "conflict",
+                    error: e.to_string(),
+                },
+                StatusCode::CONFLICT,
+            ),
+        }
+    }
+
+    fn not_found(encoding: Encoding, name: &str) -> Response {
+        encoded(
+            encoding,
+            &Failure {
+                code: "not_found",
+                error: format!("no device named {}", name),
+            },
+            StatusCode::NOT_FOUND,
+        )
+    }
+
+    /// Failure for a device that isn't an IMU, or doesn't exist
+    fn not_imu(encoding: Encoding, hardware: &Hardware, name: &str) -> Response {
+        match hardware.device(name) {
+            Some(device) => encoded(
+                encoding,
+                &Failure {
+                    code: "wrong_kind",
+                    error: format!(
+                        "{} is not an IMU but a device of kind {}",
+                        name,
+                        device.metadata().kind
+                    ),
+                },
+                StatusCode::CONFLICT,
+            ),
+            None => not_found(encoding, name),
+        }
+    }
 
-    pub async fn telemetry(hardware: Arc<Hardware>) -> Result<impl warp::Reply, Infallible> {
-        Ok(warp::reply::json(&hardware))
+    /// Value in the accepted encoding, or a JSON failure if it can't be
+    /// encoded that way, as bincode can't encode flattened structs
+    fn encoded<T: Serialize + ?Sized>(
+        encoding: Encoding,
+        value: &T,
+        status: StatusCode,
+    ) -> Response {
+        match encoding.encode(value) {
+            Ok(body) => {
+                let reply = with_header(body, CONTENT_TYPE, encoding.media_type());
+                with_status(reply, status).into_response()
+            }
+            Err(e) => with_status(
+                json(&Failure {
+                    code: "not_acceptable",
+                    error: format!("Can't encode as {}: {}", encoding.name(), e),
+                }),
+                StatusCode::NOT_ACCEPTABLE,
+            )
+            .into_response(),
+        }
     }
 }
 
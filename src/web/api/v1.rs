@@ -0,0 +1,525 @@
+//! Version 1 of the REST API, at /api/v1
+//!
+//! Each resource has a representation of its own rather than serializing the
+//! device or frame behind it, so its field names and shape stay the same as
+//! the drivers change.  Fields may be added within a version, but renaming or
+//! removing one needs a new version.  Bodies are encoded as the `Accept`
+//! header asks, but errors are always JSON of the form
+//! `{"error": {"code": "not_found", "message": "No device named nope"}}`.
+//!
+//! The history of a signal is at `/devices/{name}/history/{signal}`, as
+//! `?resolution=raw|minute|hour` (default minute) from `since` until `until`
+//! (default the last day until now), both RFC 3339 times.
+
+use super::with_hardware;
+use crate::hardware::device::{self, Health};
+use crate::hardware::Hardware;
+use crate::history::{self, Resolution, Summary};
+use crate::telemetry::{self, Descriptor, Topic, Update, Value};
+use crate::web::encoding::{self, Encoding};
+use anyhow::Error;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::task;
+use warp::http::header::CONTENT_TYPE;
+use warp::http::StatusCode;
+use warp::reject::{InvalidQuery, MethodNotAllowed, Rejection};
+use warp::reply::{json, with_header, with_status, Response};
+use warp::{Filter, Reply};
+
+pub fn api(
+    hardware: Arc<Hardware>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    let devices = warp::path!("devices")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::devices);
+
+    let device = warp::path!("devices" / String)
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::device);
+
+    let telemetry = warp::path!("devices" / String / "telemetry")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::telemetry);
+
+    let status = warp::path!("devices" / String / "status")
+        .and(warp::get())
+        .and(with_hardware(hardware.clone()))
+        .and(encoding::accept())
+        .and_then(reply::status);
+
+    let history = warp::path!("devices" / String / "history" / String)
+        .and(warp::get())
+        .and(warp::query::<HistoryQuery>())
+        .and(with_hardware(hardware))
+        .and(encoding::accept())
+        .and_then(reply::history);
+
+    // errors within the version are answered here, rather than falling
+    // through to the other routes
+    warp::path!("api" / "v1" / ..).and(
+        devices
+            .or(device)
+            .or(telemetry)
+            .or(status)
+            .or(history)
+            .recover(reply::rejection),
+    )
+}
+
+/// A device, with its status and the signals it publishes
+#[derive(Serialize)]
+struct Device {
+    name: String,
+    kind: String,
+    commands: Vec<String>,
+    status: Status,
+    signals: Vec<Signal>,
+}
+
+impl Device {
+    fn new(device: &dyn device::Device, descriptors: &[Descriptor]) -> Self {
+        let metadata = device.metadata();
+        let signals = descriptors
+            .iter()
+            .filter(|descriptor| descriptor.device == metadata.name)
+            .map(Signal::new)
+            .collect();
+
+        Self {
+            name: metadata.name,
+            kind: metadata.kind,
+            commands: metadata.commands,
+            status: Status::new(device.health()),
+            signals,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum State {
+    Starting,
+    Connected,
+    Degraded,
+    Disconnected,
+    Loopback,
+}
+
+impl From<device::State> for State {
+    fn from(state: device::State) -> Self {
+        match state {
+            device::State::Starting => State::Starting,
+            device::State::Connected => State::Connected,
+            device::State::Degraded => State::Degraded,
+            device::State::Disconnected => State::Disconnected,
+            device::State::Loopback => State::Loopback,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    state: State,
+    last_frame_at: Option<DateTime<Utc>>,
+    frames_per_second: f32,
+
+    /// Errors since habctl started
+    error_count: u32,
+    last_error: Option<String>,
+}
+
+impl Status {
+    fn new(health: Health) -> Self {
+        Self {
+            state: health.state.into(),
+            last_frame_at: health.last_frame,
+            frames_per_second: health.frame_rate,
+            error_count: health.errors,
+            last_error: health.last_error,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Signal {
+    name: String,
+
+    /// Symbol of the unit, empty if unitless
+    unit: &'static str,
+    min: Option<f32>,
+    max: Option<f32>,
+    description: String,
+}
+
+impl Signal {
+    fn new(descriptor: &Descriptor) -> Self {
+        Self {
+            name: descriptor.signal.clone(),
+            unit: descriptor.unit.symbol(),
+            min: descriptor.min,
+            max: descriptor.max,
+            description: descriptor.description.clone(),
+        }
+    }
+}
+
+/// Latest value of a signal
+#[derive(Serialize)]
+struct Reading {
+    signal: String,
+    value: Value,
+    timestamp: DateTime<Utc>,
+    stale: bool,
+}
+
+impl Reading {
+    fn new(update: Update) -> Self {
+        Self {
+            signal: update.topic.signal,
+            value: update.value,
+            timestamp: update.timestamp,
+            stale: update.stale,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    resolution: Option<Resolution>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+}
+
+/// Summary of a signal over a minute or hour, or a single raw value
+#[derive(Serialize)]
+struct HistorySample {
+    /// Start of the minute or hour, or time of the value
+    timestamp: DateTime<Utc>,
+    min: f32,
+    max: f32,
+    mean: f32,
+    count: u32,
+}
+
+impl HistorySample {
+    fn new(summary: &Summary) -> Self {
+        Self {
+            timestamp: Utc.timestamp_millis(summary.start),
+            min: summary.min,
+            max: summary.max,
+            mean: summary.mean,
+            count: summary.count,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Failure {
+    error: ErrorBody,
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    /// Kind of error, for programs
+    code: &'static str,
+
+    /// Description of the error, for people
+    message: String,
+}
+
+mod reply {
+    use super::*;
+
+    pub async fn devices(
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<Response, Infallible> {
+        let descriptors = telemetry::bus().descriptors();
+        let devices: Vec<_> = hardware
+            .devices()
+            .iter()
+            .map(|device| Device::new(device.as_ref(), &descriptors))
+            .collect();
+
+        Ok(encoded(encoding, &devices))
+    }
+
+    pub async fn device(
+        name: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<Response, Infallible> {
+        Ok(match hardware.device(&name) {
+            Some(device) => {
+                let descriptors = telemetry::bus().descriptors();
+                encoded(encoding, &Device::new(device.as_ref(), &descriptors))
+            }
+            None => not_found(&name),
+        })
+    }
+
+    /// Latest value of each of the device's signals
+    pub async fn telemetry(
+        name: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<Response, Infallible> {
+        if hardware.device(&name).is_none() {
+            return Ok(not_found(&name));
+        }
+
+        let readings: Vec<_> = telemetry::bus()
+            .snapshot()
+            .into_iter()
+            .filter(|update| update.topic.device == name)
+            .map(Reading::new)
+            .collect();
+
+        Ok(encoded(encoding, &readings))
+    }
+
+    pub async fn status(
+        name: String,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<Response, Infallible> {
+        Ok(match hardware.device(&name) {
+            Some(device) => encoded(encoding, &Status::new(device.health())),
+            None => not_found(&name),
+        })
+    }
+
+    /// Recorded values or summaries of one of the device's signals
+    pub async fn history(
+        name: String,
+        signal: String,
+        query: HistoryQuery,
+        hardware: Arc<Hardware>,
+        encoding: Encoding,
+    ) -> Result<Response, Infallible> {
+        if hardware.device(&name).is_none() {
+            return Ok(not_found(&name));
+        }
+
+        let until = query.until.unwrap_or_else(Utc::now);
+        let since = query.since.unwrap_or_else(|| until - Duration::days(1));
+        let resolution = query.resolution.unwrap_or(Resolution::Minute);
+        if since > until {
+            return Ok(error(
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+                format!("since ({}) is after until ({})", since, until),
+            ));
+        }
+
+        let topic = Topic::new(&name, &signal);
+        let summaries = {
+            let topic = topic.clone();
+            task::spawn_blocking(move || history::history().query(&topic, resolution, since, until))
+                .await
+                .map_err(Error::from)
+                .and_then(|result| result)
+        };
+
+        Ok(match summaries {
+            Ok(summaries) => {
+                let samples: Vec<_> = summaries.iter().map(HistorySample::new).collect();
+                encoded(encoding, &samples)
+            }
+            Err(e) => {
+                log::warn!("Failed to read history of {}: {}", topic, e);
+                error(StatusCode::INTERNAL_SERVER_ERROR, "internal", e.to_string())
+            }
+        })
+    }
+
+    /// Error for a request no route accepted
+    pub async fn rejection(rejection: Rejection) -> Result<Response, Infallible> {
+        Ok(if rejection.is_not_found() {
+            error(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                "No such resource".into(),
+            )
+        } else if let Some(invalid) = rejection.find::<InvalidQuery>() {
+            error(StatusCode::BAD_REQUEST, "bad_request", invalid.to_string())
+        } else if rejection.find::<MethodNotAllowed>().is_some() {
+            error(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method_not_allowed",
+                "Method not allowed".into(),
+            )
+        } else {
+            log::warn!("Unhandled API rejection: {:?}", rejection);
+            error(
+                StatusCode::BAD_REQUEST,
+                "bad_request",
+                format!("{:?}", rejection),
+            )
+        })
+    }
+
+    fn not_found(name: &str) -> Response {
+        error(
+            StatusCode::NOT_FOUND,
+            "not_found",
+            format!("No device named {}", name),
+        )
+    }
+
+    fn error(status: StatusCode, code: &'static str, message: String) -> Response {
+        let failure = Failure {
+            error: ErrorBody { code, message },
+        };
+        with_status(json(&failure), status).into_response()
+    }
+
+    fn encoded<T: Serialize>(encoding: Encoding, value: &T) -> Response {
+        match encoding.encode(value) {
+            Ok(body) => with_header(body, CONTENT_TYPE, encoding.media_type()).into_response(),
+            Err(e) => error(
+                StatusCode::NOT_ACCEPTABLE,
+                "not_acceptable",
+                format!("Can't encode as {}: {}", encoding.name(), e),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::api;
+    use crate::config::Config;
+    use crate::hardware::Hardware;
+    use crate::history;
+    use crate::telemetry::{Topic, Update, Value};
+    use chrono::{Duration, Utc};
+    use serde_json::Value as Json;
+    use std::sync::Arc;
+    use warp::http::StatusCode;
+
+    /// Status and JSON body of a request to the API
+    async fn request(method: &str, path: &str) -> (StatusCode, Json) {
+        Config::load_test();
+        let hardware = Arc::new(Hardware::new().unwrap());
+
+        let response = warp::test::request()
+            .method(method)
+            .path(path)
+            .reply(&api(hardware))
+            .await;
+        let body = serde_json::from_slice(response.body()).unwrap();
+
+        (response.status(), body)
+    }
+
+    async fn get(path: &str) -> (StatusCode, Json) {
+        request("GET", path).await
+    }
+
+    fn assert_error(expected: (StatusCode, &str), (status, body): (StatusCode, Json)) {
+        assert_eq!(expected.0, status);
+        assert_eq!(expected.1, body["error"]["code"], "{}", body);
+        assert!(body["error"]["message"].is_string());
+    }
+
+    #[tokio::test]
+    async fn devices() {
+        let (status, devices) = get("/api/v1/devices").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("hab", devices[0]["name"]);
+        assert_eq!("imu", devices[0]["kind"]);
+        assert_eq!("big", devices[1]["name"]);
+
+        let (status, device) = get("/api/v1/devices/big").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("mppt", device["kind"]);
+        assert_eq!("loopback", device["status"]["state"]);
+
+        let (status, readings) = get("/api/v1/devices/big/telemetry").await;
+        assert_eq!(StatusCode::OK, status);
+        assert!(readings.is_array());
+
+        let (status, health) = get("/api/v1/devices/hab/status").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!("loopback", health["state"]);
+        assert_eq!(0, health["error_count"]);
+    }
+
+    #[tokio::test]
+    async fn unknown_device() {
+        let (status, body) = get("/api/v1/devices/nope").await;
+        assert_eq!(StatusCode::NOT_FOUND, status);
+        assert_eq!("No device named nope", body["error"]["message"]);
+
+        for path in [
+            "/api/v1/devices/nope/telemetry",
+            "/api/v1/devices/nope/status",
+            "/api/v1/devices/nope/history/battery_voltage",
+        ]
+        .iter()
+        {
+            assert_error((StatusCode::NOT_FOUND, "not_found"), get(path).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn history() {
+        let timestamp = Utc::now() - Duration::minutes(5);
+        history::history().record(&Update {
+            topic: Topic::new("big", "api_test"),
+            timestamp,
+            value: Value::Number(13.5),
+            stale: false,
+        });
+
+        let (status, samples) = get("/api/v1/devices/big/history/api_test?resolution=raw").await;
+        assert_eq!(StatusCode::OK, status);
+        assert_eq!(1, samples.as_array().unwrap().len());
+        assert_eq!(13.5, samples[0]["mean"]);
+
+        // before the range asked for
+        let since = (timestamp + Duration::minutes(1)).to_rfc3339();
+        let path = format!(
+            "/api/v1/devices/big/history/api_test?resolution=raw&since={}",
+            since.replace('+', "%2B")
+        );
+        let (_, samples) = get(&path).await;
+        assert_eq!(0, samples.as_array().unwrap().len());
+
+        assert_error(
+            (StatusCode::BAD_REQUEST, "bad_request"),
+            get("/api/v1/devices/big/history/api_test?resolution=weekly").await,
+        );
+        let reversed = get(
+            "/api/v1/devices/big/history/api_test?since=2021-06-02T00:00:00Z&until=2021-06-01T00:00:00Z",
+        )
+        .await;
+        assert!(reversed.1["error"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("is after"));
+        assert_error((StatusCode::BAD_REQUEST, "bad_request"), reversed);
+    }
+
+    #[tokio::test]
+    async fn rejections() {
+        assert_error(
+            (StatusCode::NOT_FOUND, "not_found"),
+            get("/api/v1/nothing/here").await,
+        );
+        assert_error(
+            (StatusCode::METHOD_NOT_ALLOWED, "method_not_allowed"),
+            request("POST", "/api/v1/devices").await,
+        );
+    }
+}
@@ -11,6 +11,36 @@ pub struct Web {
 
     /// Update interval, in milliseconds
     pub update_interval: u64,
+
+    /// Number of recent messages kept for each websocket session, so that a
+    /// reconnecting client can resume it (default 1024)
+    pub replay_capacity: Option<usize>,
+
+    /// Time a disconnected websocket session is kept for resuming, in seconds
+    /// (default 300)
+    pub session_timeout: Option<u64>,
+
+    /// Number of messages a connected websocket client may fall behind before
+    /// it overflows (default 256)
+    pub queue_capacity: Option<usize>,
+
+    /// What to do when a websocket client overflows (default coalesce)
+    #[serde(default)]
+    pub overflow: Overflow,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Skip the oldest messages, keeping the most recent
+    DropOldest,
+
+    /// Skip every queued message, and start over from the latest values
+    #[default]
+    Coalesce,
+
+    /// Close the connection, so that the client reconnects
+    Disconnect,
 }
 
 pub type ListenAddr = ([u8; 4], u16);
@@ -0,0 +1,195 @@
+//! Encodings offered to clients
+//!
+//! The websocket is encoded as chosen by subprotocol or the `encoding` query
+//! parameter, and the REST API by the `Accept` header.  Bincode is the most
+//! compact, and what the habux client uses; JSON, CBOR and MessagePack are
+//! for tools that can't build against the protocol crate.  Every encoding
+//! carries the same messages, with enums externally tagged and structs as
+//! maps of field names.
+
+use anyhow::{Error, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::convert::Infallible;
+use warp::Filter;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Bincode,
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 4] = [
+        Encoding::Bincode,
+        Encoding::Json,
+        Encoding::Cbor,
+        Encoding::MessagePack,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Bincode => "bincode",
+            Encoding::Json => "json",
+            Encoding::Cbor => "cbor",
+            Encoding::MessagePack => "msgpack",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|encoding| encoding.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Encoding::Bincode => "application/x-bincode",
+            Encoding::Json => "application/json",
+            Encoding::Cbor => "application/cbor",
+            Encoding::MessagePack => "application/msgpack",
+        }
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type.to_ascii_lowercase().as_str() {
+            "application/x-bincode" => Some(Encoding::Bincode),
+            "application/json" | "application/*" | "*/*" => Some(Encoding::Json),
+            "application/cbor" => Some(Encoding::Cbor),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Encoding::MessagePack)
+            }
+            _ => None,
+        }
+    }
+
+    /// Most preferred encoding of an `Accept` header, if any is offered
+    pub fn accepted(accept: &str) -> Option<Self> {
+        let mut offered: Vec<_> = accept
+            .split(',')
+            .filter_map(|range| {
+                let mut parameters = range.split(';').map(str::trim);
+                let encoding = Self::from_media_type(parameters.next()?)?;
+
+                let quality = parameters
+                    .find_map(|parameter| parameter.strip_prefix("q="))
+                    .and_then(|quality| quality.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+
+                Some((encoding, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+
+        // stable, so the first of equal preference wins
+        offered.sort_by(|a, b| b.1.total_cmp(&a.1));
+        offered.first().map(|(encoding, _)| *encoding)
+    }
+
+    /// Websocket subprotocol, e.g. "habctl.json"
+    pub fn subprotocol(self) -> String {
+        format!("habctl.{}", self.name())
+    }
+
+    /// First encoding of a `Sec-WebSocket-Protocol` header, if any is offered
+    pub fn from_subprotocols(protocols: &str) -> Option<Self> {
+        protocols.split(',').find_map(|protocol| {
+            let name = protocol.trim().strip_prefix("habctl.")?;
+            Self::from_name(name)
+        })
+    }
+
+    /// Whether the encoding is text, sent as text websocket messages
+    pub fn is_text(self) -> bool {
+        self == Encoding::Json
+    }
+
+    pub fn encode<T: Serialize + ?Sized>(self, value: &T) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Bincode => bincode::serialize(value)?,
+            Encoding::Json => serde_json::to_vec(value)?,
+            Encoding::Cbor => {
+                let mut encoded = Vec::new();
+                ciborium::ser::into_writer(value, &mut encoded)
+                    .map_err(|e| Error::msg(e.to_string()))?;
+                encoded
+            }
+            Encoding::MessagePack => rmp_serde::to_vec_named(value)?,
+        })
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, encoded: &[u8]) -> Result<T> {
+        Ok(match self {
+            Encoding::Bincode => bincode::deserialize(encoded)?,
+            Encoding::Json => serde_json::from_slice(encoded)?,
+            Encoding::Cbor => {
+                ciborium::de::from_reader(encoded).map_err(|e| Error::msg(e.to_string()))?
+            }
+            Encoding::MessagePack => rmp_serde::from_slice(encoded)?,
+        })
+    }
+}
+
+/// Encoding of the request's `Accept` header, JSON unless another is preferred
+pub fn accept() -> impl Filter<Extract = (Encoding,), Error = Infallible> + Clone {
+    warp::header::optional::<String>("accept")
+        .map(|accept: Option<String>| {
+            accept
+                .as_deref()
+                .and_then(Encoding::accepted)
+                .unwrap_or(Encoding::Json)
+        })
+        .or(warp::any().map(|| Encoding::Json))
+        .unify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::Encoding;
+    use crate::telemetry::{Topic, Update, Value};
+    use chrono::Utc;
+
+    #[test]
+    fn accepted() {
+        assert_eq!(Some(Encoding::Json), Encoding::accepted("*/*"));
+        assert_eq!(
+            Some(Encoding::Cbor),
+            Encoding::accepted("text/html, application/cbor")
+        );
+        assert_eq!(
+            Some(Encoding::MessagePack),
+            Encoding::accepted("application/json;q=0.5, application/x-msgpack")
+        );
+        assert_eq!(
+            Some(Encoding::Json),
+            Encoding::accepted("application/json, application/cbor;q=0")
+        );
+        assert_eq!(None, Encoding::accepted("text/html"));
+
+        assert_eq!(
+            Some(Encoding::Json),
+            Encoding::from_subprotocols("chat, habctl.json, habctl.cbor")
+        );
+        assert_eq!(None, Encoding::from_subprotocols("habctl.xml"));
+    }
+
+    #[test]
+    fn round_trip() {
+        let update = Update {
+            topic: Topic::new("big", "voltage"),
+            timestamp: Utc::now(),
+            value: Value::Number(13.2),
+            stale: false,
+        };
+
+        for &encoding in &Encoding::ALL {
+            let encoded = encoding.encode(&update).unwrap();
+            let decoded: Update = encoding.decode(&encoded).unwrap();
+            assert_eq!(update.value, decoded.value, "{}", encoding.name());
+            assert_eq!(update.timestamp, decoded.timestamp, "{}", encoding.name());
+        }
+    }
+}
@@ -1,50 +1,255 @@
-use chrono::{DateTime, Utc};
+pub mod feed;
+pub mod session;
+mod subscription;
+
+use futures::stream::{SplitSink, SplitStream};
 use futures::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
+use habctl_protocol::socket::{Data, Request};
+use std::collections::HashMap;
+use std::sync::Arc;
 use tokio::time::{self, Duration};
+use warp::http::StatusCode;
 use warp::ws::{Message, WebSocket, Ws};
 use warp::{Filter, Reply};
 
-use crate::config::Config;
+use crate::hardware::Hardware;
+use crate::web::config::Overflow;
+use crate::web::encoding::Encoding;
 
-#[derive(Serialize, Deserialize, Debug)]
-pub enum Data {
-    Empty,
-    SystemTime(DateTime<Utc>),
-}
+/// Time the client has to answer habctl's hello
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Close code for a client speaking an incompatible protocol version
+const CLOSE_INCOMPATIBLE: u16 = 4000;
+
+/// Close code for a connection whose session was resumed on another
+const CLOSE_REPLACED: u16 = 4001;
+
+/// Close code for a client too slow to receive every message of its session
+const CLOSE_LAGGED: u16 = 4002;
 
 /// UI Websocket at /socket/ui
-pub fn ui_socket() -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+///
+/// Messages are bincode, unless the client offers a "habctl.<encoding>"
+/// subprotocol or passes `?encoding=<encoding>`.
+pub fn ui_socket(
+    hardware: Arc<Hardware>,
+) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
     warp::path!("socket" / "ui")
         .and(warp::ws())
-        .map(move |ws: Ws| ws.on_upgrade(move |socket| socket_connected(socket)))
+        .and(warp::header::optional::<String>("sec-websocket-protocol"))
+        .and(warp::query::<HashMap<String, String>>())
+        .map(
+            move |ws: Ws, protocols: Option<String>, query: HashMap<String, String>| {
+                let hardware = hardware.clone();
+                let subprotocol = protocols.as_deref().and_then(Encoding::from_subprotocols);
+
+                let encoding = match (subprotocol, query.get("encoding")) {
+                    (Some(encoding), _) => encoding,
+                    (None, Some(name)) => match Encoding::from_name(name) {
+                        Some(encoding) => encoding,
+                        None => {
+                            let error = format!("Unknown encoding {}", name);
+                            return warp::reply::with_status(error, StatusCode::BAD_REQUEST)
+                                .into_response();
+                        }
+                    },
+                    (None, None) => Encoding::Bincode,
+                };
+
+                let reply =
+                    ws.on_upgrade(move |socket| socket_connected(socket, hardware, encoding));
+
+                // the chosen subprotocol must be echoed for the client to accept it
+                match subprotocol {
+                    Some(encoding) => warp::reply::with_header(
+                        reply,
+                        "sec-websocket-protocol",
+                        encoding.subprotocol(),
+                    )
+                    .into_response(),
+                    None => reply.into_response(),
+                }
+            },
+        )
 }
 
 /// Socket has connected
-async fn socket_connected(ws: WebSocket) {
+async fn socket_connected(ws: WebSocket, hardware: Arc<Hardware>, encoding: Encoding) {
     let (mut ws_send, mut ws_recv) = ws.split();
 
-    // handle messages from the web client
-    tokio::spawn(async move {
-        while let Some(msg) = ws_recv.next().await {
-            log::debug!("Received {:?}", msg);
+    // announce the protocol version, so the client can check it first
+    let hello = Data::Hello {
+        version: habctl_protocol::VERSION,
+    };
+    if let Err(e) = send(&mut ws_send, encoding, &hello).await {
+        log::debug!("Exiting send task: {:?}", e);
+        return;
+    }
+
+    // the client's hello, naming any session it is resuming
+    let resume = match time::timeout(HELLO_TIMEOUT, request(&mut ws_recv, encoding)).await {
+        Ok(Some(Request::Hello { version, .. })) if !habctl_protocol::compatible(version) => {
+            log::warn!(
+                "UI client speaks protocol {}, not {}",
+                version,
+                habctl_protocol::VERSION
+            );
+
+            let reason = format!(
+                "incompatible protocol version {}, expected {}",
+                version,
+                habctl_protocol::VERSION
+            );
+            let _ = ws_send
+                .send(Message::close_with(CLOSE_INCOMPATIBLE, reason))
+                .await;
+            return;
+        }
+        Ok(Some(Request::Hello { resume, .. })) => resume,
+        Ok(Some(request)) => {
+            log::warn!("UI client sent {:?} before hello", request);
+            let _ = ws_send
+                .send(Message::close_with(CLOSE_INCOMPATIBLE, "expected hello"))
+                .await;
+            return;
         }
-        log::debug!("Exiting receive task");
+        Ok(None) => return,
+        Err(_) => {
+            log::warn!("UI client did not say hello");
+            let _ = ws_send
+                .send(Message::close_with(CLOSE_INCOMPATIBLE, "expected hello"))
+                .await;
+            return;
+        }
+    };
+
+    // resume the client's session if possible, or start a new one
+    let sessions = session::sessions();
+    let resumed = resume.and_then(|resume| {
+        let session = sessions.resume(resume, encoding)?;
+        Some((session, resume.sequence))
     });
+    let answer = resumed.is_some();
+    let (session, mut sent) = resumed.unwrap_or_else(|| (sessions.start(hardware, encoding), 0));
+
+    let answer = Data::Session {
+        id: session.id,
+        resumed: answer,
+    };
+    if let Err(e) = send(&mut ws_send, encoding, &answer).await {
+        log::debug!("Exiting send task: {:?}", e);
+        return;
+    }
+
+    let (connection, mut replaced) = session.attach();
+    let mut latest = session.watch();
+    session.sent(sent);
+
+    // messages queued before connecting are all sent, but after that the
+    // client may only fall behind by the queue capacity
+    let resumed = session.latest();
 
-    // periodically send telemetry until disconnected
-    let mut interval = time::interval(Duration::from_millis(Config::get().web.update_interval));
+    // send the session's messages as they are added, and pass on requests,
+    // until disconnected
     loop {
-        // send telemetry, exiting handler on error
-        let msg = Data::SystemTime(Utc::now());
-        if let Err(e) = ws_send
-            .send(Message::binary(bincode::serialize(&msg).unwrap()))
-            .await
-        {
-            log::debug!("Exiting send task: {:?}", e);
-            break;
+        let behind = session.latest() - sent;
+        let overflowed = sent >= resumed && behind > sessions.queue_capacity as u64;
+
+        let messages = match session.since(sent).filter(|_| !overflowed) {
+            Some(messages) => messages,
+            None => {
+                log::warn!(
+                    "UI client of session {} fell {} messages behind",
+                    session.id,
+                    behind
+                );
+
+                match sessions.overflow {
+                    Overflow::DropOldest => {
+                        let oldest = session.latest() - sessions.queue_capacity as u64;
+                        session.overflowed(oldest - sent);
+                        sent = oldest;
+                    }
+                    Overflow::Coalesce => {
+                        let snapshot = session.resync();
+                        session.overflowed(snapshot - 1 - sent);
+                        sent = snapshot - 1;
+                    }
+                    Overflow::Disconnect => {
+                        session.overflowed(behind);
+                        let _ = ws_send
+                            .send(Message::close_with(CLOSE_LAGGED, "fell behind"))
+                            .await;
+                        break;
+                    }
+                }
+                continue;
+            }
+        };
+
+        for (sequence, message) in messages {
+            if let Err(e) = ws_send.send(self::message(encoding, message)).await {
+                log::debug!("Exiting send task: {:?}", e);
+                session.detach(connection);
+                return;
+            }
+            sent = sequence;
+            session.sent(sent);
+        }
+
+        tokio::select! {
+            _ = latest.changed() => {}
+            _ = replaced.changed() => {
+                let _ = ws_send.send(Message::close_with(CLOSE_REPLACED, "resumed elsewhere")).await;
+                log::debug!("Exiting send task: session resumed elsewhere");
+                return;
+            }
+            request = self::request(&mut ws_recv, encoding) => match request {
+                Some(request) => session.request(request).await,
+                None => break,
+            },
+        }
+    }
+
+    log::debug!("Exiting send task: client closed");
+    session.detach(connection);
+}
+
+/// Next request from the client, or none once it disconnects
+async fn request(ws_recv: &mut SplitStream<WebSocket>, encoding: Encoding) -> Option<Request> {
+    while let Some(Ok(msg)) = ws_recv.next().await {
+        if !msg.is_binary() && !msg.is_text() {
+            log::debug!("Received {:?}", msg);
+            continue;
         }
 
-        interval.tick().await;
+        match encoding.decode::<Request>(msg.as_bytes()) {
+            Ok(request) => {
+                log::debug!("Received {:?}", request);
+                return Some(request);
+            }
+            Err(e) => log::warn!("UI client sent an unknown request: {}", e),
+        }
+    }
+
+    None
+}
+
+async fn send(
+    ws_send: &mut SplitSink<WebSocket, Message>,
+    encoding: Encoding,
+    msg: &Data,
+) -> Result<(), warp::Error> {
+    let encoded = feed::encode(encoding, msg);
+    ws_send.send(message(encoding, encoded.to_vec())).await
+}
+
+/// Websocket message of an encoded message, as text if the encoding is
+fn message(encoding: Encoding, encoded: Vec<u8>) -> Message {
+    match encoding.is_text() {
+        // text encodings are UTF-8 by construction
+        true => Message::text(String::from_utf8(encoded).unwrap()),
+        false => Message::binary(encoded),
     }
 }
@@ -0,0 +1,363 @@
+//! Telemetry and events shared by every session, each serialized once
+//!
+//! A single task collects every update from the telemetry bus, every IMU event
+//! and the state sent on each tick, and broadcasts them to the sessions, which
+//! only number them.  Each is encoded by the first session to need it in its
+//! encoding, and shared by the rest.  Updates are encoded as elements of
+//! `Data::Updates`, so a session builds its updates from those it subscribed
+//! to by concatenation.
+
+use crate::config::Config;
+use crate::hardware::imu::Icm20948;
+use crate::hardware::Hardware;
+use crate::telemetry::{self, Update};
+use crate::web::encoding::Encoding;
+use chrono::Utc;
+use futures::stream::{self, Stream, StreamExt};
+use habctl_protocol::socket::Data;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::{self, Duration};
+
+/// Number of items buffered for slow sessions
+const CHANNEL_CAPACITY: usize = 1024;
+
+static FEED: OnceCell<broadcast::Sender<Item>> = OnceCell::new();
+
+/// Serialized value
+pub type Encoded = Arc<[u8]>;
+
+/// Value encoded on demand, at most once in each encoding
+pub struct Shared<T> {
+    pub value: T,
+    encoded: [OnceCell<Encoded>; Encoding::ALL.len()],
+}
+
+impl<T: Serialize> Shared<T> {
+    pub fn new(value: T) -> Arc<Self> {
+        Arc::new(Self {
+            value,
+            encoded: Default::default(),
+        })
+    }
+
+    pub fn encoded(&self, encoding: Encoding) -> Encoded {
+        let index = Encoding::ALL.iter().position(|e| *e == encoding).unwrap();
+        self.encoded[index]
+            .get_or_init(|| encode(encoding, &self.value))
+            .clone()
+    }
+}
+
+#[derive(Clone)]
+pub enum Item {
+    /// Update from the bus
+    Update(Arc<Shared<Update>>),
+
+    /// Some updates from the bus were missed
+    Lagged,
+
+    /// `Data::ImuEvent`
+    Event(Arc<Shared<Data>>),
+
+    /// `Data::SystemTime`, then the `Data` of the state of each IMU by name
+    ///
+    /// State that has not changed since the last tick is shared with it, so
+    /// that sessions can tell it apart without comparing.
+    Tick(Arc<Shared<Data>>, Arc<BTreeMap<String, Arc<Shared<Data>>>>),
+}
+
+/// Subscribe to the feed, starting it if need be
+pub fn subscribe(hardware: &Arc<Hardware>) -> broadcast::Receiver<Item> {
+    FEED.get_or_init(|| {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        tokio::spawn(run(sender.clone(), hardware.clone()));
+        sender
+    })
+    .subscribe()
+}
+
+/// Encode a message, which can't fail for any encoding
+pub fn encode(encoding: Encoding, value: &impl Serialize) -> Encoded {
+    encoding.encode(value).unwrap().into()
+}
+
+/// `Data::Updates` of encoded updates
+pub fn updates<'a>(
+    encoding: Encoding,
+    updates: impl ExactSizeIterator<Item = &'a Encoded>,
+) -> Encoded {
+    let count = updates.len();
+    let mut data = match encoding {
+        Encoding::Bincode => {
+            // a vector is encoded as its length then its elements, and the
+            // length of the empty vector is last
+            let mut data = bincode::serialize(&Data::Updates(Vec::new())).unwrap();
+            data.truncate(data.len() - 8);
+            data.extend((count as u64).to_le_bytes());
+            data
+        }
+        Encoding::Json => br#"{"Updates":["#.to_vec(),
+        Encoding::Cbor => {
+            let mut data = vec![0xa1];
+            cbor::text(&mut data, "Updates");
+            cbor::header(&mut data, cbor::ARRAY, count as u64);
+            data
+        }
+        Encoding::MessagePack => {
+            let mut data = vec![0x81];
+            msgpack::str(&mut data, "Updates");
+            msgpack::array(&mut data, count);
+            data
+        }
+    };
+
+    for (i, update) in updates.enumerate() {
+        if encoding == Encoding::Json && i > 0 {
+            data.push(b',');
+        }
+        data.extend_from_slice(update);
+    }
+
+    if encoding == Encoding::Json {
+        data.extend(b"]}");
+    }
+
+    data.into()
+}
+
+/// `Sequenced` of encoded `Data`
+pub fn sequenced(encoding: Encoding, sequence: u64, data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(data.len() + 24);
+
+    match encoding {
+        Encoding::Bincode => message.extend(sequence.to_le_bytes()),
+        Encoding::Json => message.extend(format!(r#"{{"sequence":{},"data":"#, sequence).bytes()),
+        Encoding::Cbor => {
+            message.push(0xa2);
+            cbor::text(&mut message, "sequence");
+            cbor::header(&mut message, cbor::UNSIGNED, sequence);
+            cbor::text(&mut message, "data");
+        }
+        Encoding::MessagePack => {
+            message.push(0x82);
+            msgpack::str(&mut message, "sequence");
+            msgpack::uint(&mut message, sequence);
+            msgpack::str(&mut message, "data");
+        }
+    }
+
+    message.extend_from_slice(data);
+    if encoding == Encoding::Json {
+        message.push(b'}');
+    }
+
+    message
+}
+
+/// Heads of CBOR items, in the shortest form as RFC 8949 prefers
+mod cbor {
+    pub const UNSIGNED: u8 = 0;
+    pub const TEXT: u8 = 3;
+    pub const ARRAY: u8 = 4;
+
+    pub fn header(out: &mut Vec<u8>, major: u8, argument: u64) {
+        let major = major << 5;
+        match argument {
+            0..=23 => out.push(major | argument as u8),
+            24..=0xff => out.extend([major | 24, argument as u8]),
+            0x100..=0xffff => {
+                out.push(major | 25);
+                out.extend((argument as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                out.push(major | 26);
+                out.extend((argument as u32).to_be_bytes());
+            }
+            _ => {
+                out.push(major | 27);
+                out.extend(argument.to_be_bytes());
+            }
+        }
+    }
+
+    pub fn text(out: &mut Vec<u8>, text: &str) {
+        header(out, TEXT, text.len() as u64);
+        out.extend(text.bytes());
+    }
+}
+
+/// Heads of MessagePack values, in the shortest form as rmp writes them
+mod msgpack {
+    pub fn uint(out: &mut Vec<u8>, value: u64) {
+        match value {
+            0..=0x7f => out.push(value as u8),
+            0x80..=0xff => out.extend([0xcc, value as u8]),
+            0x100..=0xffff => {
+                out.push(0xcd);
+                out.extend((value as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                out.push(0xce);
+                out.extend((value as u32).to_be_bytes());
+            }
+            _ => {
+                out.push(0xcf);
+                out.extend(value.to_be_bytes());
+            }
+        }
+    }
+
+    /// String of fewer than 32 bytes
+    pub fn str(out: &mut Vec<u8>, text: &str) {
+        out.push(0xa0 | text.len() as u8);
+        out.extend(text.bytes());
+    }
+
+    pub fn array(out: &mut Vec<u8>, len: usize) {
+        match len {
+            0..=15 => out.push(0x90 | len as u8),
+            16..=0xffff => {
+                out.push(0xdc);
+                out.extend((len as u16).to_be_bytes());
+            }
+            _ => {
+                out.push(0xdd);
+                out.extend((len as u32).to_be_bytes());
+            }
+        }
+    }
+}
+
+async fn run(feed: broadcast::Sender<Item>, hardware: Arc<Hardware>) {
+    let mut updates = telemetry::bus().subscribe();
+
+    let mut events = stream::select_all(hardware.all::<Icm20948>().map(|imu| {
+        let name = imu.name().to_owned();
+        Box::pin(received(imu.subscribe_events()).map(move |e| Data::ImuEvent(name.clone(), e)))
+    }));
+
+    let mut state: BTreeMap<String, Arc<Shared<Data>>> = BTreeMap::new();
+
+    let update_interval = Duration::from_millis(Config::get().web.update_interval);
+    let mut interval = time::interval(update_interval);
+    loop {
+        let item = tokio::select! {
+            _ = interval.tick() => {
+                let mut latest = BTreeMap::new();
+                for imu in hardware.all::<Icm20948>() {
+                    if let Some(orientation) = imu.orientation() {
+                        let data = Data::Orientation(imu.name().to_owned(), orientation);
+                        latest.insert(format!("{}/orientation", imu.name()), Shared::new(data));
+                    }
+
+                    if let Some(level) = imu.level() {
+                        let data = Data::Level(imu.name().to_owned(), level);
+                        latest.insert(format!("{}/level", imu.name()), Shared::new(data));
+                    }
+                }
+
+                // share the previous state where unchanged
+                for (key, data) in latest.iter_mut() {
+                    if let Some(previous) = state.get(key) {
+                        if unchanged(previous, data) {
+                            *data = Arc::clone(previous);
+                        }
+                    }
+                }
+                state = latest;
+
+                Item::Tick(Shared::new(Data::SystemTime(Utc::now())), Arc::new(state.clone()))
+            }
+            update = updates.recv() => match update {
+                Ok(update) => Item::Update(Shared::new(update)),
+                Err(RecvError::Lagged(_)) => Item::Lagged,
+                Err(RecvError::Closed) => return,
+            },
+            Some(event) = events.next() => Item::Event(Shared::new(event)),
+        };
+
+        // no sessions is not an error
+        let _ = feed.send(item);
+    }
+}
+
+fn unchanged(previous: &Shared<Data>, latest: &Shared<Data>) -> bool {
+    match (&previous.value, &latest.value) {
+        (Data::Orientation(_, previous), Data::Orientation(_, latest)) => previous == latest,
+        (Data::Level(_, previous), Data::Level(_, latest)) => previous == latest,
+        _ => false,
+    }
+}
+
+/// Stream of values from a broadcast channel, skipping any missed by lagging
+fn received<T: Clone + Send>(receiver: broadcast::Receiver<T>) -> impl Stream<Item = T> {
+    stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(value) => return Some((value, receiver)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, sequenced, updates};
+    use crate::telemetry::{Topic, Update, Value};
+    use crate::web::encoding::Encoding;
+    use chrono::Utc;
+    use habctl_protocol::socket::{Data, Sequenced};
+
+    fn updates_of(count: usize) -> Vec<Update> {
+        (0..count)
+            .map(|i| Update {
+                topic: Topic::new("big", &format!("signal{}", i)),
+                timestamp: Utc::now(),
+                value: Value::Number(13.2),
+                stale: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn concatenated_updates() {
+        // either side of each change in the size of the length
+        for &encoding in &Encoding::ALL {
+            for &count in &[0, 1, 2, 15, 16, 23, 24, 300] {
+                let list = updates_of(count);
+                let encoded: Vec<_> = list.iter().map(|u| encode(encoding, u)).collect();
+
+                let expected = encoding.encode(&Data::Updates(list)).unwrap();
+                let actual = updates(encoding, encoded.iter());
+                assert_eq!(expected, &actual[..], "{} {}", encoding.name(), count);
+            }
+        }
+    }
+
+    #[test]
+    fn sequenced_data() {
+        for &encoding in &Encoding::ALL {
+            for &sequence in &[0, 23, 24, 127, 128, 255, 256, 70_000, 5_000_000_000] {
+                let data = encoding.encode(&Data::Empty).unwrap();
+                let expected = encoding
+                    .encode(&Sequenced {
+                        sequence,
+                        data: Data::Empty,
+                    })
+                    .unwrap();
+
+                let actual = sequenced(encoding, sequence, &data);
+                assert_eq!(expected, actual, "{} {}", encoding.name(), sequence);
+
+                let decoded: Sequenced = encoding.decode(&actual).unwrap();
+                assert_eq!(sequence, decoded.sequence);
+            }
+        }
+    }
+}
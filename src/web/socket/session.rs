@@ -0,0 +1,568 @@
+//! Websocket sessions, which outlive their connections
+//!
+//! Each session runs its own task, which answers the client's requests and
+//! queues the telemetry and events it subscribed to from the feed, numbering
+//! every message.  The most recent messages are kept in a bounded replay
+//! buffer, which is also the client's send queue, so a client that drops off
+//! the network and reconnects resumes from the last message it received,
+//! rather than starting over and missing what happened meanwhile.  A session
+//! with no connection is dropped after the session timeout.  A session keeps
+//! the encoding its client chose when it started, so it can only be resumed
+//! in the same encoding.
+
+use super::feed::{self, Encoded, Item, Shared};
+use super::subscription::{Subscription, Subscriptions};
+use crate::config::Config;
+use crate::hardware::Hardware;
+use crate::telemetry;
+use crate::web::config::Overflow;
+use crate::web::encoding::Encoding;
+use anyhow::{Error, Result};
+use habctl_protocol::socket::{Data, Request, Resume};
+use once_cell::sync::Lazy;
+use rand::Rng;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, watch};
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
+
+/// Default number of messages kept for resuming each session
+const DEFAULT_REPLAY_CAPACITY: usize = 1024;
+
+/// Default time a disconnected session is kept, in seconds
+const DEFAULT_SESSION_TIMEOUT: u64 = 300;
+
+/// Default number of messages a connected client may fall behind
+const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+/// Largest session ID, as JSON clients in browsers read numbers as doubles,
+/// which only hold integers exactly up to 53 bits
+const MAX_SESSION_ID: u64 = (1 << 53) - 1;
+
+/// Number of requests buffered while the previous ones are answered
+const REQUEST_CAPACITY: usize = 16;
+
+static SESSIONS: Lazy<Sessions> = Lazy::new(|| {
+    let config = &Config::get().web;
+    let replay_capacity = config.replay_capacity.unwrap_or(DEFAULT_REPLAY_CAPACITY);
+
+    Sessions {
+        sessions: Mutex::default(),
+        replay_capacity,
+        timeout: Duration::from_secs(config.session_timeout.unwrap_or(DEFAULT_SESSION_TIMEOUT)),
+        // a client can't be further behind than the messages kept
+        queue_capacity: config
+            .queue_capacity
+            .unwrap_or(DEFAULT_QUEUE_CAPACITY)
+            .min(replay_capacity),
+        overflow: config.overflow,
+    }
+});
+
+/// Every session, connected or awaiting its client
+pub fn sessions() -> &'static Sessions {
+    &SESSIONS
+}
+
+pub struct Sessions {
+    /// Sessions by their random IDs, which can't be guessed to take over
+    /// another client's session
+    sessions: Mutex<HashMap<u64, Arc<Session>>>,
+
+    replay_capacity: usize,
+    timeout: Duration,
+
+    /// Number of messages a connected client may fall behind
+    pub queue_capacity: usize,
+
+    /// What to do when a client falls further behind
+    pub overflow: Overflow,
+}
+
+impl Sessions {
+    /// Start a new session, beginning with every signal's description and latest value
+    pub fn start(&'static self, hardware: Arc<Hardware>, encoding: Encoding) -> Arc<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let id = loop {
+            let id = rand::thread_rng().gen::<u64>() & MAX_SESSION_ID;
+            if !sessions.contains_key(&id) {
+                break id;
+            }
+        };
+
+        let (requests, requests_rx) = mpsc::channel(REQUEST_CAPACITY);
+        let update_interval = Duration::from_millis(Config::get().web.update_interval);
+
+        let session = Arc::new(Session {
+            id,
+            encoding,
+            requests,
+            subscriptions: Mutex::new(Subscriptions::new(update_interval)),
+            replay: Mutex::new(Replay::new(self.replay_capacity, encoding)),
+            latest: watch::channel(0).0,
+            connection: watch::channel(0).0,
+            detached: Mutex::new(Some(Instant::now())),
+            lag: Mutex::default(),
+        });
+
+        // subscribe before the snapshot, so that no later change is missed
+        let feed = feed::subscribe(&hardware);
+        session.push_data(&Data::Signals(telemetry::bus().descriptors()));
+        session.resync();
+
+        sessions.insert(id, session.clone());
+        drop(sessions);
+        tokio::spawn(self.run(session.clone(), requests_rx, feed, hardware));
+
+        session
+    }
+
+    /// Session to resume, if it is still kept in the same encoding and has
+    /// every message after the last one the client received
+    pub fn resume(&self, resume: Resume, encoding: Encoding) -> Option<Arc<Session>> {
+        let session = self.sessions.lock().unwrap().get(&resume.session)?.clone();
+        if session.encoding != encoding {
+            return None;
+        }
+        session.since(resume.sequence)?;
+        Some(session)
+    }
+
+    /// Statistics of every session, ordered by ID
+    pub fn stats(&self) -> Vec<Stats> {
+        let sessions = self.sessions.lock().unwrap();
+        let mut stats: Vec<_> = sessions.values().map(|session| session.stats()).collect();
+        stats.sort_by_key(|stats| stats.id);
+        stats
+    }
+
+    /// Queue telemetry and events and answer requests, until the session expires
+    async fn run(
+        &self,
+        session: Arc<Session>,
+        mut requests: mpsc::Receiver<Request>,
+        mut feed: broadcast::Receiver<Item>,
+        hardware: Arc<Hardware>,
+    ) {
+        // state last sent, so only changes are sent
+        let mut state: BTreeMap<String, Arc<Shared<Data>>> = BTreeMap::new();
+
+        loop {
+            let due = session.subscriptions.lock().unwrap().due();
+
+            tokio::select! {
+                _ = time::sleep_until(due.unwrap_or_else(Instant::now)), if due.is_some() => {
+                    let updates = session.subscriptions.lock().unwrap().flush(Instant::now());
+                    if !updates.is_empty() {
+                        let encoded: Vec<_> = updates.iter().map(|u| u.encoded(session.encoding)).collect();
+                        session.push(feed::updates(session.encoding, encoded.iter()));
+                    }
+                }
+                item = feed.recv() => match item {
+                    Ok(Item::Update(update)) => {
+                        session.subscriptions.lock().unwrap().changed(&update);
+                    }
+                    // some changes were missed, so start over from the latest values
+                    Ok(Item::Lagged) | Err(RecvError::Lagged(_)) => {
+                        let snapshot: Vec<_> = telemetry::bus().snapshot().into_iter().map(Shared::new).collect();
+                        session.subscriptions.lock().unwrap().reset(&snapshot);
+                    }
+                    Ok(Item::Event(event)) => {
+                        session.push(event.encoded(session.encoding));
+                    }
+                    Ok(Item::Tick(time, latest)) => {
+                        if session.expired(self.timeout) {
+                            log::debug!("Session {} expired", session.id);
+                            self.sessions.lock().unwrap().remove(&session.id);
+                            return;
+                        }
+
+                        // current state is only worth sending while connected, as
+                        // it is sent again on the next tick after resuming
+                        if !session.connected() {
+                            continue;
+                        }
+
+                        session.push(time.encoded(session.encoding));
+
+                        for (key, data) in latest.iter() {
+                            if !state.get(key).is_some_and(|sent| Arc::ptr_eq(sent, data)) {
+                                state.insert(key.clone(), data.clone());
+                                session.push(data.encoded(session.encoding));
+                            }
+                        }
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                Some(request) = requests.recv() => {
+                    for data in handle(request, &hardware, &session.subscriptions).await {
+                        session.push_data(&data);
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct Session {
+    pub id: u64,
+
+    /// Encoding of every message, as the client chose
+    pub encoding: Encoding,
+
+    requests: mpsc::Sender<Request>,
+
+    subscriptions: Mutex<Subscriptions>,
+
+    replay: Mutex<Replay>,
+
+    /// Sequence of the latest message
+    latest: watch::Sender<u64>,
+
+    /// Number of the latest connection, so that a connection can tell when
+    /// the session has been resumed on another
+    connection: watch::Sender<u64>,
+
+    /// Time the last connection ended, or none while connected
+    detached: Mutex<Option<Instant>>,
+
+    lag: Mutex<Lag>,
+}
+
+/// How far behind a session's client has fallen
+#[derive(Default)]
+struct Lag {
+    /// Sequence of the last message sent
+    sent: u64,
+
+    max_pending: u64,
+    overflows: u64,
+    dropped: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    pub id: u64,
+    pub connected: bool,
+
+    /// Number of messages queued but not yet sent
+    pub pending: u64,
+
+    /// Largest number of messages queued since the session started
+    pub max_pending: u64,
+
+    /// Number of times the client fell behind by more than the queue capacity
+    pub overflows: u64,
+
+    /// Number of messages skipped because the client fell behind
+    pub dropped: u64,
+}
+
+impl Session {
+    /// Queue an encoded `Data`, returning its sequence
+    fn push(&self, data: Encoded) -> u64 {
+        let sequence = self.replay.lock().unwrap().push(data);
+        self.latest.send_replace(sequence);
+        sequence
+    }
+
+    /// Encode and queue a `Data`, returning its sequence
+    fn push_data(&self, data: &Data) -> u64 {
+        self.push(feed::encode(self.encoding, data))
+    }
+
+    /// Queue the latest value of every subscribed signal, replacing any
+    /// changes not yet sent, returning its sequence
+    pub fn resync(&self) -> u64 {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let snapshot: Vec<_> = telemetry::bus()
+            .snapshot()
+            .into_iter()
+            .filter(|update| subscriptions.matches(&update.topic))
+            .collect();
+
+        subscriptions.reset(&[]);
+        self.push_data(&Data::Snapshot(snapshot))
+    }
+
+    /// Messages after `sequence`, or none if some were discarded
+    pub fn since(&self, sequence: u64) -> Option<Vec<(u64, Vec<u8>)>> {
+        self.replay.lock().unwrap().since(sequence)
+    }
+
+    /// Sequence of the latest message
+    pub fn latest(&self) -> u64 {
+        *self.latest.borrow()
+    }
+
+    /// Watch the sequence of the latest message
+    pub fn watch(&self) -> watch::Receiver<u64> {
+        self.latest.subscribe()
+    }
+
+    /// Record the last message sent to the client
+    pub fn sent(&self, sequence: u64) {
+        let pending = self.latest() - sequence;
+
+        let mut lag = self.lag.lock().unwrap();
+        lag.sent = sequence;
+        lag.max_pending = lag.max_pending.max(pending);
+    }
+
+    /// Record that the client fell behind, and `dropped` messages were skipped
+    pub fn overflowed(&self, dropped: u64) {
+        let mut lag = self.lag.lock().unwrap();
+        lag.overflows += 1;
+        lag.dropped += dropped;
+    }
+
+    fn stats(&self) -> Stats {
+        let lag = self.lag.lock().unwrap();
+
+        Stats {
+            id: self.id,
+            connected: self.connected(),
+            pending: self.latest() - lag.sent,
+            max_pending: lag.max_pending,
+            overflows: lag.overflows,
+            dropped: lag.dropped,
+        }
+    }
+
+    /// Pass a request from the client to the session's task
+    pub async fn request(&self, request: Request) {
+        // the task only ends once the session is removed
+        let _ = self.requests.send(request).await;
+    }
+
+    /// Begin a connection, replacing any other, returning its number and a
+    /// watch that changes once it is replaced
+    pub fn attach(&self) -> (u64, watch::Receiver<u64>) {
+        let mut number = 0;
+        self.connection.send_modify(|connection| {
+            *connection += 1;
+            number = *connection;
+        });
+        *self.detached.lock().unwrap() = None;
+
+        let mut replaced = self.connection.subscribe();
+        replaced.borrow_and_update();
+        (number, replaced)
+    }
+
+    /// End a connection, unless it has already been replaced
+    pub fn detach(&self, connection: u64) {
+        if *self.connection.borrow() == connection {
+            *self.detached.lock().unwrap() = Some(Instant::now());
+        }
+    }
+
+    fn connected(&self) -> bool {
+        self.detached.lock().unwrap().is_none()
+    }
+
+    fn expired(&self, timeout: Duration) -> bool {
+        let detached = self.detached.lock().unwrap();
+        detached.is_some_and(|detached| detached.elapsed() > timeout)
+    }
+}
+
+/// Most recent messages of a session, as encoded `Data`
+struct Replay {
+    messages: VecDeque<(u64, Encoded)>,
+    capacity: usize,
+    encoding: Encoding,
+
+    /// Sequence of the next message
+    next: u64,
+}
+
+impl Replay {
+    fn new(capacity: usize, encoding: Encoding) -> Self {
+        Self {
+            messages: VecDeque::with_capacity(capacity),
+            capacity,
+            encoding,
+            next: 1,
+        }
+    }
+
+    /// Add a message, discarding the oldest if full, returning its sequence
+    fn push(&mut self, data: Encoded) -> u64 {
+        let sequence = self.next;
+        self.next += 1;
+
+        if self.messages.len() >= self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back((sequence, data));
+
+        sequence
+    }
+
+    /// Messages after `sequence`, encoded as `Sequenced`
+    fn since(&self, sequence: u64) -> Option<Vec<(u64, Vec<u8>)>> {
+        let first = self.messages.front().map_or(self.next, |(first, _)| *first);
+        let after = sequence.checked_add(1)?;
+        if after < first || sequence >= self.next {
+            return None;
+        }
+
+        let skip = (after - first) as usize;
+        let messages = self.messages.iter().skip(skip).map(|(sequence, data)| {
+            let message = feed::sequenced(self.encoding, *sequence, data);
+            (*sequence, message)
+        });
+
+        Some(messages.collect())
+    }
+}
+
+/// Answer a request from the client
+async fn handle(
+    request: Request,
+    hardware: &Arc<Hardware>,
+    subscriptions: &Mutex<Subscriptions>,
+) -> Vec<Data> {
+    match request {
+        Request::Hello { .. } => Vec::new(),
+        Request::Subscribe {
+            id,
+            device,
+            signal,
+            interval,
+        } => {
+            let bus = telemetry::bus();
+            let known = bus.descriptors().iter().any(|descriptor| {
+                descriptor.device == device
+                    && signal.iter().all(|signal| *signal == descriptor.signal)
+            });
+            if !known {
+                let error = match signal {
+                    Some(signal) => format!("No signal {} on {}", signal, device),
+                    None => format!("No signals on {}", device),
+                };
+                return vec![Data::Error { id, error }];
+            }
+
+            let interval = Duration::from_millis(interval.into());
+            let subscription = Subscription::new(Some(device), signal, interval);
+
+            // start from the latest values, then changes at the subscribed rate
+            let latest: Vec<_> = bus
+                .snapshot()
+                .into_iter()
+                .filter(|update| subscription.matches(&update.topic))
+                .collect();
+            subscriptions.lock().unwrap().subscribe(id, subscription);
+
+            let mut messages = vec![Data::Ack { id }];
+            if !latest.is_empty() {
+                messages.push(Data::Updates(latest));
+            }
+            messages
+        }
+        Request::Unsubscribe { id } => match subscriptions.lock().unwrap().unsubscribe(id) {
+            true => vec![Data::Ack { id }],
+            false => vec![Data::Error {
+                id,
+                error: format!("No subscription {}", id),
+            }],
+        },
+        Request::Command {
+            id,
+            device,
+            command,
+            arguments,
+        } => {
+            // commands may read or write state files
+            let hardware = hardware.clone();
+            let result = task::spawn_blocking(move || {
+                self::command(&hardware, &device, &command, &arguments)
+            })
+            .await
+            .unwrap_or_else(|e| Err(e.into()));
+
+            vec![match result {
+                Ok(result) => Data::Reply {
+                    id,
+                    result: result.to_string(),
+                },
+                Err(e) => Data::Error {
+                    id,
+                    error: e.to_string(),
+                },
+            }]
+        }
+    }
+}
+
+/// Perform a command on a device, with arguments as JSON text
+fn command(hardware: &Hardware, device: &str, command: &str, arguments: &str) -> Result<Value> {
+    let device = hardware
+        .device(device)
+        .ok_or_else(|| Error::msg(format!("No device {}", device)))?;
+
+    let arguments = match arguments {
+        "" => Value::Null,
+        arguments => serde_json::from_str(arguments)?,
+    };
+
+    device.command(command, arguments)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Replay, MAX_SESSION_ID};
+    use crate::web::encoding::Encoding;
+    use crate::web::socket::feed::encode;
+    use habctl_protocol::socket::{Data, Resume, Sequenced};
+    use serde_json::Value;
+
+    #[test]
+    fn replay() {
+        let mut replay = Replay::new(3, Encoding::Bincode);
+        assert_eq!(0, replay.since(0).unwrap().len());
+        assert!(replay.since(1).is_none());
+
+        for _ in 0..5 {
+            replay.push(encode(Encoding::Bincode, &Data::Empty));
+        }
+
+        // 3, 4 and 5 are kept
+        assert!(replay.since(1).is_none());
+        let messages = replay.since(2).unwrap();
+        assert_eq!(3, messages.len());
+        assert_eq!(3, messages[0].0);
+
+        let message: Sequenced = bincode::deserialize(&messages[2].1).unwrap();
+        assert_eq!(5, message.sequence);
+        assert!(matches!(message.data, Data::Empty));
+
+        assert_eq!(0, replay.since(5).unwrap().len());
+        assert!(replay.since(6).is_none());
+        assert!(replay.since(u64::MAX).is_none());
+    }
+
+    #[test]
+    fn session_id_survives_javascript() {
+        let data = Encoding::Json
+            .encode(&Data::Session {
+                id: MAX_SESSION_ID,
+                resumed: false,
+            })
+            .unwrap();
+
+        // read as a browser would, with every number a double
+        let data: Value = serde_json::from_slice(&data).unwrap();
+        let id = data["Session"]["id"].as_f64().unwrap();
+
+        let resume = format!(r#"{{"session": {}, "sequence": 1}}"#, id);
+        let resume: Resume = serde_json::from_str(&resume).unwrap();
+        assert_eq!(MAX_SESSION_ID, resume.session);
+    }
+}
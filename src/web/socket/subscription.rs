@@ -0,0 +1,267 @@
+//! Signals each websocket client has subscribed to
+//!
+//! Every subscription collects the latest value of each matching topic that
+//! changed, and flushes them no more often than its own interval.  Values equal
+//! to the one the subscription last flushed are not sent again.  A client
+//! that has not subscribed to anything receives every signal at the default
+//! interval.  Updates are kept as shared by the feed.
+
+use super::feed::Shared;
+use crate::telemetry::{Topic, Update, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+pub struct Subscription {
+    /// Device to match, or every device if none
+    device: Option<String>,
+
+    /// Signal of the device to match, or every signal if none
+    signal: Option<String>,
+
+    /// Least time between updates
+    interval: Duration,
+
+    /// Latest update of each matching topic changed since the last flush
+    changed: BTreeMap<Topic, Arc<Shared<Update>>>,
+
+    /// Value of each matching topic last flushed
+    sent: BTreeMap<Topic, Value>,
+
+    /// Time of the last flush
+    flushed: Instant,
+}
+
+impl Subscription {
+    /// Subscribe to every signal of every device
+    pub fn all(interval: Duration) -> Self {
+        Self::new(None, None, interval)
+    }
+
+    pub fn new(device: Option<String>, signal: Option<String>, interval: Duration) -> Self {
+        Self {
+            device,
+            signal,
+            interval,
+            changed: BTreeMap::new(),
+            sent: BTreeMap::new(),
+            flushed: Instant::now(),
+        }
+    }
+
+    pub fn matches(&self, topic: &Topic) -> bool {
+        self.device.iter().all(|device| *device == topic.device)
+            && self.signal.iter().all(|signal| *signal == topic.signal)
+    }
+
+    fn changed(&mut self, update: &Arc<Shared<Update>>) {
+        let topic = &update.value.topic;
+        if !self.matches(topic) {
+            return;
+        }
+
+        if self.sent.get(topic) == Some(&update.value.value) {
+            // back to the value last sent, so there is nothing to send
+            self.changed.remove(topic);
+        } else {
+            self.changed.insert(topic.clone(), update.clone());
+        }
+    }
+
+    /// Time the changes can next be flushed, if there are any
+    fn due(&self) -> Option<Instant> {
+        (!self.changed.is_empty()).then(|| self.flushed + self.interval)
+    }
+
+    fn flush(&mut self, now: Instant) -> Option<BTreeMap<Topic, Arc<Shared<Update>>>> {
+        if self.due()? > now {
+            return None;
+        }
+
+        self.flushed = now;
+        let changed = std::mem::take(&mut self.changed);
+        for (topic, update) in &changed {
+            self.sent.insert(topic.clone(), update.value.value.clone());
+        }
+
+        Some(changed)
+    }
+}
+
+pub struct Subscriptions {
+    /// Subscription to everything, until the client first subscribes
+    default: Option<Subscription>,
+
+    subscriptions: BTreeMap<u32, Subscription>,
+}
+
+impl Subscriptions {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            default: Some(Subscription::all(interval)),
+            subscriptions: BTreeMap::new(),
+        }
+    }
+
+    /// Add or replace the subscription with `id`
+    pub fn subscribe(&mut self, id: u32, subscription: Subscription) {
+        self.default = None;
+        self.subscriptions.insert(id, subscription);
+    }
+
+    /// Remove the subscription with `id`, returning whether there was one
+    pub fn unsubscribe(&mut self, id: u32) -> bool {
+        self.subscriptions.remove(&id).is_some()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &Subscription> {
+        self.default.iter().chain(self.subscriptions.values())
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Subscription> {
+        self.default
+            .iter_mut()
+            .chain(self.subscriptions.values_mut())
+    }
+
+    /// Whether any subscription matches a topic
+    pub fn matches(&self, topic: &Topic) -> bool {
+        self.iter().any(|subscription| subscription.matches(topic))
+    }
+
+    /// Record a published update in every subscription matching it
+    pub fn changed(&mut self, update: &Arc<Shared<Update>>) {
+        for subscription in self.iter_mut() {
+            subscription.changed(update);
+        }
+    }
+
+    /// Start over from the latest value of every topic, after missing updates
+    pub fn reset(&mut self, snapshot: &[Arc<Shared<Update>>]) {
+        for subscription in self.iter_mut() {
+            subscription.changed.clear();
+            subscription.sent.clear();
+            for update in snapshot {
+                subscription.changed(update);
+            }
+        }
+    }
+
+    /// Earliest time any subscription can be flushed, if any has changes
+    pub fn due(&mut self) -> Option<Instant> {
+        self.iter_mut().filter_map(|s| s.due()).min()
+    }
+
+    /// Changes of every subscription that is due, ordered by topic
+    pub fn flush(&mut self, now: Instant) -> Vec<Arc<Shared<Update>>> {
+        let mut updates = BTreeMap::new();
+        for subscription in self.iter_mut() {
+            updates.extend(subscription.flush(now).into_iter().flatten());
+        }
+
+        updates.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Shared, Subscription, Subscriptions};
+    use crate::telemetry::{Topic, Update, Value};
+    use chrono::Utc;
+    use tokio::time::{Duration, Instant};
+
+    struct Test(Subscriptions);
+
+    impl Test {
+        fn changed(&mut self, device: &str, signal: &str) {
+            self.changed_to(device, signal, 1.0);
+        }
+
+        fn changed_to(&mut self, device: &str, signal: &str, value: f32) {
+            self.0.changed(&Shared::new(Update {
+                topic: Topic::new(device, signal),
+                timestamp: Utc::now(),
+                value: Value::Number(value),
+                stale: false,
+            }));
+        }
+
+        fn flush(&mut self, now: Instant) -> Vec<String> {
+            let updates = self.0.flush(now);
+            updates
+                .iter()
+                .map(|update| update.value.topic.to_string())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn rate_limited() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let mut subscriptions = Test(Subscriptions::new(Duration::from_millis(100)));
+        subscriptions.changed("mppt", "voltage");
+        assert_eq!(0, subscriptions.flush(start).len());
+
+        // default subscription covers everything
+        assert!(subscriptions.0.due().unwrap() < at(150));
+        assert_eq!(vec!["mppt/voltage"], subscriptions.flush(at(150)));
+        assert_eq!(None, subscriptions.0.due());
+
+        // replaced by the first subscription
+        subscriptions.0.subscribe(
+            1,
+            Subscription::new(Some("imu".into()), None, Duration::from_secs(1)),
+        );
+        subscriptions.0.subscribe(
+            2,
+            Subscription::new(
+                Some("mppt".into()),
+                Some("voltage".into()),
+                Duration::from_millis(10),
+            ),
+        );
+
+        subscriptions.changed("imu", "temperature");
+        subscriptions.changed("mppt", "voltage");
+        subscriptions.changed("mppt", "current");
+        assert!(!subscriptions.0.matches(&Topic::new("mppt", "current")));
+
+        assert_eq!(vec!["mppt/voltage"], subscriptions.flush(at(100)));
+        assert_eq!(vec!["imu/temperature"], subscriptions.flush(at(1100)));
+
+        assert!(subscriptions.0.unsubscribe(2));
+        assert!(!subscriptions.0.unsubscribe(2));
+        subscriptions.changed("mppt", "voltage");
+        assert_eq!(None, subscriptions.0.due());
+    }
+
+    #[test]
+    fn unchanged() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        let mut subscriptions = Test(Subscriptions::new(Duration::from_millis(100)));
+        subscriptions.changed_to("mppt", "voltage", 13.0);
+        assert_eq!(vec!["mppt/voltage"], subscriptions.flush(at(150)));
+
+        // the same value again is not sent
+        subscriptions.changed_to("mppt", "voltage", 13.0);
+        assert_eq!(None, subscriptions.0.due());
+        assert_eq!(0, subscriptions.flush(at(300)).len());
+
+        // nor is a change that is undone before the next flush
+        subscriptions.changed_to("mppt", "voltage", 13.5);
+        subscriptions.changed_to("mppt", "voltage", 13.0);
+        assert_eq!(0, subscriptions.flush(at(450)).len());
+
+        subscriptions.changed_to("mppt", "voltage", 13.5);
+        assert_eq!(vec!["mppt/voltage"], subscriptions.flush(at(600)));
+
+        // after a reset every value is sent again
+        subscriptions.0.reset(&[]);
+        subscriptions.changed_to("mppt", "voltage", 13.5);
+        assert_eq!(vec!["mppt/voltage"], subscriptions.flush(at(750)));
+    }
+}
@@ -0,0 +1,278 @@
+//! Server-sent events of telemetry, for simple consumers
+//!
+//! Streams the same feed as the UI websocket, as JSON that `curl -N` or a
+//! browser's `EventSource` can read without the protocol crate.  The stream
+//! starts with the latest value of every selected signal, then each `update`
+//! as it is published and each IMU `event`.  If the stream falls behind, it
+//! starts over from the latest values.  Selecting a device or signal that
+//! doesn't exist is a 404, with an error like that of /api/v1.
+
+use crate::hardware::Hardware;
+use crate::telemetry::{self, Topic, Update};
+use crate::web::encoding::Encoding;
+use crate::web::socket::feed::{self, Item};
+use futures::stream::{self, Stream, StreamExt};
+use habctl_protocol::imu::ImuEvent;
+use habctl_protocol::socket::Data;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use warp::http::StatusCode;
+use warp::reply::{self, Response};
+use warp::sse::Event;
+use warp::{Filter, Reply};
+
+/// Signals to stream, from the query string
+#[derive(Clone, Deserialize)]
+struct Selection {
+    /// Device to stream, or every device if none
+    device: Option<String>,
+
+    /// Signal to stream, or every signal if none, in which case events are
+    /// streamed too
+    signal: Option<String>,
+}
+
+impl Selection {
+    fn matches(&self, topic: &Topic) -> bool {
+        self.device.iter().all(|device| *device == topic.device)
+            && self.signal.iter().all(|signal| *signal == topic.signal)
+    }
+
+    fn matches_event(&self, device: &str) -> bool {
+        self.signal.is_none() && self.device.iter().all(|selected| selected == device)
+    }
+
+    /// Why nothing can match, if the device or signal doesn't exist
+    fn unknown(&self, hardware: &Hardware) -> Option<String> {
+        if let Some(device) = &self.device {
+            if hardware.device(device).is_none() {
+                return Some(format!("No device named {}", device));
+            }
+        }
+
+        let signal = self.signal.as_ref()?;
+        let known = telemetry::bus()
+            .descriptors()
+            .iter()
+            .any(|descriptor| self.matches(&Topic::new(&descriptor.device, &descriptor.signal)));
+
+        (!known).then(|| format!("No signal named {}", signal))
+    }
+
+    /// Latest value of every selected signal
+    fn snapshot(&self) -> Vec<Event> {
+        telemetry::bus()
+            .snapshot()
+            .iter()
+            .filter(|update| self.matches(&update.topic))
+            .map(|update| update_event(&serde_json::to_string(update).unwrap()))
+            .collect()
+    }
+}
+
+/// IMU event, with the device that detected it
+#[derive(Serialize)]
+struct DeviceEvent<'a> {
+    device: &'a str,
+    event: &'a ImuEvent,
+}
+
+/// Telemetry stream at /sse/telemetry, optionally `?device=<name>&signal=<name>`
+pub fn telemetry(
+    hardware: Arc<Hardware>,
+) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    warp::path!("sse" / "telemetry")
+        .and(warp::get())
+        .and(warp::query::<Selection>())
+        .map(move |selection: Selection| -> Response {
+            if let Some(message) = selection.unknown(&hardware) {
+                let failure = json!({ "error": { "code": "not_found", "message": message } });
+                return reply::with_status(reply::json(&failure), StatusCode::NOT_FOUND)
+                    .into_response();
+            }
+
+            let events = events(&hardware, selection).map(Ok::<_, Infallible>);
+            warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+        })
+}
+
+/// Latest value of every selected signal, then updates and events as they occur
+fn events(hardware: &Arc<Hardware>, selection: Selection) -> impl Stream<Item = Event> {
+    // subscribe before the snapshot, so that no later change is missed
+    let feed = feed::subscribe(hardware);
+    let snapshot = selection.snapshot();
+
+    let events = stream::unfold((feed, selection), |(mut feed, selection)| async move {
+        loop {
+            let events = match feed.recv().await {
+                Ok(Item::Update(update)) if selection.matches(&update.value.topic) => {
+                    vec![shared_update_event(&update)]
+                }
+                Ok(Item::Event(event)) => match &event.value {
+                    Data::ImuEvent(device, event) if selection.matches_event(device) => {
+                        let event = DeviceEvent { device, event };
+                        let json = serde_json::to_string(&event).unwrap();
+                        vec![Event::default().event("event").data(json)]
+                    }
+                    _ => continue,
+                },
+                Ok(Item::Lagged) | Err(RecvError::Lagged(_)) => selection.snapshot(),
+                Ok(_) => continue,
+                Err(RecvError::Closed) => return None,
+            };
+
+            return Some((stream::iter(events), (feed, selection)));
+        }
+    });
+
+    stream::iter(snapshot).chain(events.flatten())
+}
+
+fn update_event(json: &str) -> Event {
+    Event::default().event("update").data(json)
+}
+
+/// Update as JSON, encoded once for every client of the feed
+fn shared_update_event(update: &feed::Shared<Update>) -> Event {
+    let encoded = update.encoded(Encoding::Json);
+    update_event(std::str::from_utf8(&encoded).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{events, telemetry, Selection};
+    use crate::config::Config;
+    use crate::hardware::Hardware;
+    use crate::telemetry::unit::{Amperes, Volts};
+    use crate::telemetry::{Signals, Spec, Topic, Value};
+    use chrono::Utc;
+    use futures::StreamExt;
+    use serde_json::Value as Json;
+    use std::sync::Arc;
+    use tokio::time::{sleep, timeout, Duration};
+    use warp::http::StatusCode;
+
+    struct Frame {
+        voltage: Volts,
+        current: Amperes,
+    }
+
+    impl Signals for Frame {
+        const SPECS: &'static [Spec] = &[
+            Spec::new("sse_voltage", Volts::UNIT, "Battery voltage"),
+            Spec::new("sse_current", Amperes::UNIT, "Battery current"),
+        ];
+
+        fn signals(&self) -> Vec<(String, Value)> {
+            vec![
+                ("sse_voltage".to_owned(), self.voltage.into()),
+                ("sse_current".to_owned(), self.current.into()),
+            ]
+        }
+    }
+
+    fn hardware() -> Arc<Hardware> {
+        Config::load_test();
+        telemetry::bus().describe::<Frame>("big");
+        Arc::new(Hardware::new().unwrap())
+    }
+
+    fn select(device: Option<&str>, signal: Option<&str>) -> Selection {
+        Selection {
+            device: device.map(str::to_owned),
+            signal: signal.map(str::to_owned),
+        }
+    }
+
+    /// Name and JSON data of an event
+    fn parse(event: warp::sse::Event) -> (String, Json) {
+        let text = event.to_string();
+        let field = |name: &str| {
+            text.lines()
+                .find_map(|line| line.strip_prefix(name))
+                .unwrap()
+                .to_owned()
+        };
+        (
+            field("event:"),
+            serde_json::from_str(&field("data:")).unwrap(),
+        )
+    }
+
+    #[test]
+    fn selects() {
+        let voltage = Topic::new("big", "sse_voltage");
+        assert!(select(None, None).matches(&voltage));
+        assert!(select(Some("big"), Some("sse_voltage")).matches(&voltage));
+        assert!(!select(Some("lil"), None).matches(&voltage));
+        assert!(!select(None, Some("sse_current")).matches(&voltage));
+
+        // events only when every signal of the device is selected
+        assert!(select(Some("hab"), None).matches_event("hab"));
+        assert!(!select(Some("big"), None).matches_event("hab"));
+        assert!(!select(Some("hab"), Some("temperature")).matches_event("hab"));
+    }
+
+    #[test]
+    fn unknown() {
+        let hardware = hardware();
+        assert_eq!(
+            None,
+            select(Some("big"), Some("sse_voltage")).unknown(&hardware)
+        );
+        assert_eq!(None, select(None, Some("sse_current")).unknown(&hardware));
+        assert_eq!(
+            Some("No device named nope".to_owned()),
+            select(Some("nope"), None).unknown(&hardware)
+        );
+        assert!(select(Some("hab"), Some("sse_voltage"))
+            .unknown(&hardware)
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn not_found() {
+        let filter = super::telemetry(hardware());
+
+        for query in ["device=nope", "device=big&signal=nope"].iter() {
+            let response = warp::test::request()
+                .path(&format!("/sse/telemetry?{}", query))
+                .reply(&filter)
+                .await;
+            assert_eq!(StatusCode::NOT_FOUND, response.status());
+
+            let body: Json = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!("not_found", body["error"]["code"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshot_then_updates() {
+        let hardware = hardware();
+        let bus = telemetry::bus();
+        let frame = |voltage, current| Frame {
+            voltage: Volts(voltage),
+            current: Amperes(current),
+        };
+
+        bus.publish("big", Utc::now(), &frame(13.0, 2.0));
+        let mut events = Box::pin(events(&hardware, select(Some("big"), Some("sse_voltage"))));
+
+        // let the feed start before publishing
+        sleep(Duration::from_millis(50)).await;
+        bus.publish("big", Utc::now(), &frame(14.0, 3.0));
+        bus.publish("big", Utc::now(), &frame(14.5, 3.0));
+
+        // the latest value first, then only the selected signal as it changes
+        for expected in [13.0, 14.0, 14.5].iter() {
+            let event = timeout(Duration::from_secs(1), events.next()).await;
+            let (name, update) = parse(event.unwrap().unwrap());
+            assert_eq!("update", name);
+            assert_eq!("sse_voltage", update["topic"]["signal"]);
+            assert_eq!(*expected, update["value"]);
+        }
+    }
+}